@@ -1,7 +1,7 @@
 #!/bin/bash
 #
 # Script used by account-dao unit test to start-up DynamoDB-local,
-# create Accounts table, populate it with test data, signal setup complete
+# create the tables, populate it with test data, signal setup complete
 # and wait for signal that test is finished.
 #
 
@@ -117,6 +117,46 @@ aws dynamodb create-table \
 
 wait_until_dynamodb_table_exists $ENDPOINT Accounts
 
+aws dynamodb create-table \
+    --table-name Transactions \
+    --attribute-definitions AttributeName=accountId,AttributeType=S AttributeName=sequenceNo,AttributeType=N \
+    --key-schema AttributeName=accountId,KeyType=HASH AttributeName=sequenceNo,KeyType=RANGE \
+    --provisioned-throughput ReadCapacityUnits=1,WriteCapacityUnits=1 \
+    --endpoint-url $ENDPOINT \
+    --no-cli-pager >> $LOG 2>&1
+
+wait_until_dynamodb_table_exists $ENDPOINT Transactions
+
+aws dynamodb create-table \
+    --table-name IdempotencyKeys \
+    --attribute-definitions AttributeName=idempotencyKey,AttributeType=S \
+    --key-schema AttributeName=idempotencyKey,KeyType=HASH \
+    --provisioned-throughput ReadCapacityUnits=1,WriteCapacityUnits=1 \
+    --endpoint-url $ENDPOINT \
+    --no-cli-pager >> $LOG 2>&1
+
+wait_until_dynamodb_table_exists $ENDPOINT IdempotencyKeys
+
+aws dynamodb create-table \
+    --table-name Holds \
+    --attribute-definitions AttributeName=accountId,AttributeType=S AttributeName=holdId,AttributeType=S \
+    --key-schema AttributeName=accountId,KeyType=HASH AttributeName=holdId,KeyType=RANGE \
+    --provisioned-throughput ReadCapacityUnits=1,WriteCapacityUnits=1 \
+    --endpoint-url $ENDPOINT \
+    --no-cli-pager >> $LOG 2>&1
+
+wait_until_dynamodb_table_exists $ENDPOINT Holds
+
+aws dynamodb create-table \
+    --table-name BalanceSnapshots \
+    --attribute-definitions AttributeName=accountId,AttributeType=S AttributeName=takenAt,AttributeType=S \
+    --key-schema AttributeName=accountId,KeyType=HASH AttributeName=takenAt,KeyType=RANGE \
+    --provisioned-throughput ReadCapacityUnits=1,WriteCapacityUnits=1 \
+    --endpoint-url $ENDPOINT \
+    --no-cli-pager >> $LOG 2>&1
+
+wait_until_dynamodb_table_exists $ENDPOINT BalanceSnapshots
+
 # Signal to parent that database is ready.
 echo "==> Ready marker and endpoint ($ENDPOINT) written to output" >> $LOG
 echo READY $ENDPOINT
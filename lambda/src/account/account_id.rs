@@ -0,0 +1,136 @@
+use chrono::Utc;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::error::AppError;
+
+/// Crockford's base 32, which leaves out I, L, O and U so that an id read aloud or
+/// copied by hand is less likely to be mistaken.
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+/// The characters of a ULID, 128 bits at 5 bits each.
+const ULID_LENGTH: usize = 26;
+
+/// Makes up the ids of accounts created without one.
+///
+/// An id is the prefix, a ULID and a check character. The ULID is the time in milliseconds
+/// followed by 80 random bits, so ids made by any number of instances do not collide and
+/// sort in the order they were made. The check character, from Luhn mod 32, catches any
+/// one character being mistyped and most neighbours being swapped.
+pub struct AccountIds {
+    prefix: String,
+    random: SystemRandom,
+}
+
+impl AccountIds {
+    /// The prefix should be letters, digits, '-' or '_', so that every id is a valid account id.
+    pub fn new(prefix: &str) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            random: SystemRandom::new(),
+        }
+    }
+
+    pub fn generate(&self) -> Result<String, AppError> {
+        let mut random = [0u8; 16];
+        self.random
+            .fill(&mut random[6..])
+            .map_err(|_err| AppError::internal("no source of randomness"))?;
+        let millis = Utc::now().timestamp_millis().to_be_bytes();
+        random[..6].copy_from_slice(&millis[2..]);
+        let ulid = encode(u128::from_be_bytes(random));
+        let check = check_character(&ulid);
+        Ok(format!("{}{}{}", self.prefix, ulid, check))
+    }
+
+    /// True for an id which could have been generated, which a client is not allowed to choose
+    /// as it could later be generated for another account.
+    pub fn is_generated(&self, account_id: &str) -> bool {
+        let body = match account_id.strip_prefix(&self.prefix) {
+            Some(body) if body.len() == ULID_LENGTH + 1 => body,
+            _ => return false,
+        };
+        if !body.bytes().all(|c| ALPHABET.contains(&c)) {
+            return false;
+        }
+        let (ulid, check) = body.split_at(ULID_LENGTH);
+        check.starts_with(check_character(ulid))
+    }
+}
+
+fn encode(value: u128) -> String {
+    (0..ULID_LENGTH)
+        .rev()
+        .map(|place| ALPHABET[(value >> (place * 5)) as usize & 31] as char)
+        .collect()
+}
+
+/// Luhn mod N with N as 32, over characters which are all in the alphabet.
+fn check_character(ulid: &str) -> char {
+    let n = ALPHABET.len();
+    let sum: usize = ulid
+        .bytes()
+        .rev()
+        .enumerate()
+        .map(|(index, c)| {
+            let code_point = ALPHABET.iter().position(|a| *a == c).unwrap_or(0);
+            let addend = if index % 2 == 0 { code_point * 2 } else { code_point };
+            addend / n + addend % n
+        })
+        .sum();
+    ALPHABET[(n - sum % n) % n] as char
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_character, AccountIds, ALPHABET};
+    use crate::account::validation;
+
+    #[test]
+    fn should_generate_valid_account_ids_with_prefix() {
+        let ids = AccountIds::new("acc_");
+        let first = ids.generate().expect("could not generate account id");
+        let second = ids.generate().expect("could not generate account id");
+
+        assert!(first.starts_with("acc_"));
+        assert_eq!(first.len(), 4 + 27);
+        assert!(validation::account_id(&first).is_ok());
+        assert!(ids.is_generated(&first));
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn should_sort_account_ids_by_when_they_were_generated() {
+        let ids = AccountIds::new("");
+        let first = ids.generate().expect("could not generate account id");
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        assert!(ids.generate().expect("could not generate account id") > first);
+    }
+
+    #[test]
+    fn should_catch_any_single_mistyped_character() {
+        let ids = AccountIds::new("acc_");
+        let account_id = ids.generate().expect("could not generate account id");
+        for index in 4..account_id.len() {
+            for c in ALPHABET.iter().map(|c| *c as char) {
+                let mut mistyped: Vec<char> = account_id.chars().collect();
+                if mistyped[index] == c {
+                    continue;
+                }
+                mistyped[index] = c;
+                let mistyped: String = mistyped.into_iter().collect();
+                assert!(!ids.is_generated(&mistyped), "{} accepted", mistyped);
+            }
+        }
+    }
+
+    #[test]
+    fn should_not_take_chosen_ids_for_generated_ones() {
+        let ids = AccountIds::new("acc_");
+        let ulid = "01HV7Z3K5N8Q2R4T6W9XYZABCD";
+        let account_id = format!("acc_{}{}", ulid, check_character(ulid));
+
+        assert!(ids.is_generated(&account_id));
+        assert!(!ids.is_generated(&account_id[4..]));
+        assert!(!ids.is_generated(&account_id.to_lowercase()));
+        assert!(!ids.is_generated("acc_fred"));
+    }
+}
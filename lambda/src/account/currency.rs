@@ -0,0 +1,155 @@
+use bigdecimal::BigDecimal;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::convert::TryFrom;
+
+use crate::error::{AppError, ErrorCode};
+
+/// An ISO 4217 currency, which determines how many decimal places an amount may have.
+///
+/// Deserialising checks the code is known, so an unknown currency is reported
+/// as an invalid payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Currency {
+    code: &'static str,
+    minor_units: u32,
+}
+
+impl Currency {
+    /// What accounts created before they had a currency are held in.
+    pub const LEGACY: Currency = Currency { code: "GBP", minor_units: 2 };
+
+    pub fn code(&self) -> &'static str {
+        self.code
+    }
+
+    /// Checks an amount is in whole minor units, e.g. pence for GBP or yen for JPY.
+    pub fn check_scale(&self, amount: &BigDecimal) -> Result<(), AppError> {
+        self.check_decimal_places(amount)
+            .map_err(|message| AppError::business_s(ErrorCode::InvalidAmount, message))
+    }
+
+    /// As [`check_scale`](Currency::check_scale), for use in validation.
+    pub fn check_decimal_places(&self, amount: &BigDecimal) -> Result<(), String> {
+        let (_digits, scale) = amount.normalized().as_bigint_and_exponent();
+        if scale > i64::from(self.minor_units) {
+            return Err(format!(
+                "{} amounts can not have more than {} decimal places",
+                self.code, self.minor_units
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks an amount in this currency can be applied to an account held in the given one.
+    pub fn check_account_currency(&self, account_currency: &str) -> Result<(), AppError> {
+        if account_currency != self.code {
+            return Err(AppError::business_s(
+                ErrorCode::CurrencyMismatch,
+                format!(
+                "account currency is {} not {}",
+                account_currency, self.code
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<String> for Currency {
+    type Error = String;
+
+    fn try_from(code: String) -> Result<Self, Self::Error> {
+        CURRENCIES
+            .iter()
+            .find(|(known, _)| *known == code)
+            .map(|(code, minor_units)| Currency {
+                code,
+                minor_units: *minor_units,
+            })
+            .ok_or_else(|| format!("unknown currency code {}", code))
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let code = String::deserialize(deserializer)?;
+        Currency::try_from(code).map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.code)
+    }
+}
+
+/// Active ISO 4217 codes and the number of digits after the decimal point.
+const CURRENCIES: &[(&str, u32)] = &[
+    ("AED", 2), ("AFN", 2), ("ALL", 2), ("AMD", 2), ("ANG", 2), ("AOA", 2), ("ARS", 2),
+    ("AUD", 2), ("AWG", 2), ("AZN", 2), ("BAM", 2), ("BBD", 2), ("BDT", 2), ("BGN", 2),
+    ("BHD", 3), ("BIF", 0), ("BMD", 2), ("BND", 2), ("BOB", 2), ("BRL", 2), ("BSD", 2),
+    ("BTN", 2), ("BWP", 2), ("BYN", 2), ("BZD", 2), ("CAD", 2), ("CDF", 2), ("CHF", 2),
+    ("CLF", 4), ("CLP", 0), ("CNY", 2), ("COP", 2), ("CRC", 2), ("CUP", 2), ("CVE", 2),
+    ("CZK", 2), ("DJF", 0), ("DKK", 2), ("DOP", 2), ("DZD", 2), ("EGP", 2), ("ERN", 2),
+    ("ETB", 2), ("EUR", 2), ("FJD", 2), ("FKP", 2), ("GBP", 2), ("GEL", 2), ("GHS", 2),
+    ("GIP", 2), ("GMD", 2), ("GNF", 0), ("GTQ", 2), ("GYD", 2), ("HKD", 2), ("HNL", 2),
+    ("HTG", 2), ("HUF", 2), ("IDR", 2), ("ILS", 2), ("INR", 2), ("IQD", 3), ("IRR", 2),
+    ("ISK", 0), ("JMD", 2), ("JOD", 3), ("JPY", 0), ("KES", 2), ("KGS", 2), ("KHR", 2),
+    ("KMF", 0), ("KPW", 2), ("KRW", 0), ("KWD", 3), ("KYD", 2), ("KZT", 2), ("LAK", 2),
+    ("LBP", 2), ("LKR", 2), ("LRD", 2), ("LSL", 2), ("LYD", 3), ("MAD", 2), ("MDL", 2),
+    ("MGA", 2), ("MKD", 2), ("MMK", 2), ("MNT", 2), ("MOP", 2), ("MRU", 2), ("MUR", 2),
+    ("MVR", 2), ("MWK", 2), ("MXN", 2), ("MYR", 2), ("MZN", 2), ("NAD", 2), ("NGN", 2),
+    ("NIO", 2), ("NOK", 2), ("NPR", 2), ("NZD", 2), ("OMR", 3), ("PAB", 2), ("PEN", 2),
+    ("PGK", 2), ("PHP", 2), ("PKR", 2), ("PLN", 2), ("PYG", 0), ("QAR", 2), ("RON", 2),
+    ("RSD", 2), ("RUB", 2), ("RWF", 0), ("SAR", 2), ("SBD", 2), ("SCR", 2), ("SDG", 2),
+    ("SEK", 2), ("SGD", 2), ("SHP", 2), ("SLE", 2), ("SOS", 2), ("SRD", 2), ("SSP", 2),
+    ("STN", 2), ("SVC", 2), ("SYP", 2), ("SZL", 2), ("THB", 2), ("TJS", 2), ("TMT", 2),
+    ("TND", 3), ("TOP", 2), ("TRY", 2), ("TTD", 2), ("TWD", 2), ("TZS", 2), ("UAH", 2),
+    ("UGX", 0), ("USD", 2), ("UYI", 0), ("UYU", 2), ("UYW", 4), ("UZS", 2), ("VES", 2),
+    ("VND", 0), ("VUV", 0), ("WST", 2), ("XAF", 0), ("XCD", 2), ("XOF", 0), ("XPF", 0),
+    ("YER", 2), ("ZAR", 2), ("ZMW", 2), ("ZWL", 2),
+];
+
+#[cfg(test)]
+mod test {
+    use super::Currency;
+    use bigdecimal::BigDecimal;
+    use std::{convert::TryFrom, str::FromStr};
+
+    #[test]
+    fn should_allow_amounts_in_whole_minor_units() {
+        assert!(currency("GBP").check_scale(&decimal("10.25")).is_ok());
+        assert!(currency("GBP").check_scale(&decimal("10.50")).is_ok());
+        assert!(currency("JPY").check_scale(&decimal("1000")).is_ok());
+        assert!(currency("JPY").check_scale(&decimal("1000.0")).is_ok());
+        assert!(currency("KWD").check_scale(&decimal("1.125")).is_ok());
+    }
+
+    #[test]
+    fn should_reject_amounts_with_fractions_of_minor_units() {
+        assert!(currency("GBP").check_scale(&decimal("10.255")).is_err());
+        assert!(currency("JPY").check_scale(&decimal("1000.5")).is_err());
+        assert!(currency("KWD").check_scale(&decimal("1.1255")).is_err());
+    }
+
+    #[test]
+    fn should_reject_unknown_currency() {
+        assert!(Currency::try_from("XYZ".to_string()).is_err());
+        assert!(Currency::try_from("gbp".to_string()).is_err());
+    }
+
+    #[test]
+    fn should_serialise_as_code() {
+        let json = serde_json::to_string(&currency("GBP")).expect("could not serialise");
+        assert_eq!(json, "\"GBP\"");
+        let parsed: Currency = serde_json::from_str("\"JPY\"").expect("could not deserialise");
+        assert_eq!(parsed, currency("JPY"));
+    }
+
+    fn currency(code: &str) -> Currency {
+        Currency::try_from(code.to_string()).expect("currency not known")
+    }
+
+    fn decimal(number: &str) -> BigDecimal {
+        BigDecimal::from_str(number).expect("failed to parse number")
+    }
+}
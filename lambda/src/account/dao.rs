@@ -1,125 +1,1228 @@
-use crate::error::AppError;
+use crate::config::TableNames;
+use crate::dynamodb::{is_retryable, measured, send_with_retries};
+use crate::error::{AppError, ErrorCode};
+use async_trait::async_trait;
 use aws_sdk_dynamodb::{
-    client::fluent_builders::UpdateItem,
-    error::UpdateItemError,
-    model::{AttributeValue, ReturnValue},
+    error::{PutItemError, TransactWriteItemsError, TransactWriteItemsErrorKind},
+    model::{
+        delete, update, AttributeValue, CancellationReason, Delete, Put,
+        ReturnConsumedCapacity, ReturnValue, ReturnValuesOnConditionCheckFailure, TransactWriteItem, Update,
+    },
+    client::fluent_builders,
     Client,
     SdkError::{self, ServiceError},
 };
 use bigdecimal::{num_bigint::Sign, BigDecimal};
-use std::{collections::HashMap, ops::Neg, str::FromStr};
+use chrono::{SecondsFormat, TimeZone, Utc};
+use std::{
+    collections::{BTreeSet, HashMap},
+    ops::Neg,
+    str::FromStr,
+};
+use uuid::Uuid;
+
+use super::{
+    Account, AccountRepository, AccountStatus, BalanceSnapshot, Currency, Hold, HolderDetails, HolderPatch, IdempotentRequest,
+    StoredResponse, Transaction,
+};
+
+// Every update to an account also moves it on to its next version.
+const CHANGE_BALANCE: &str = "SET balance = balance + :amount, ledgerSequence = :seq ADD version :one";
+const CAPTURE_HOLD: &str =
+    "SET balance = balance + :amount, heldAmount = heldAmount + :amount, ledgerSequence = :seq ADD version :one";
+const MIN_BALANCE_CONDITION: &str = "balance >= :min_bal";
+const ACCOUNT_EXISTS_CONDITION: &str = "attribute_exists(accountId)";
+const LEDGER_POSITION_CONDITION: &str =
+    "(attribute_not_exists(ledgerSequence) OR ledgerSequence = :prev_seq)";
+const STATUS_ACTIVE_CONDITION: &str = "(attribute_not_exists(#status) OR #status = :active)";
+const OVERDRAFT_LIMIT_CONDITION: &str =
+    "(attribute_not_exists(overdraftLimit) OR overdraftLimit = :limit)";
+const HELD_AMOUNT_CONDITION: &str = "(attribute_not_exists(heldAmount) OR heldAmount = :held)";
+const HOLD_EXISTS_CONDITION: &str = "attribute_exists(holdId)";
+const VERSION_CONDITION: &str = "(attribute_not_exists(version) OR version = :version)";
 
-use super::Account;
+/// How many times a change is attempted when other changes keep getting in first.
+const MAX_ATTEMPTS: u32 = 5;
+/// Reasons a transaction is cancelled that have nothing to do with its conditions,
+/// so it may succeed if sent again unchanged.
+const RETRYABLE_CANCELLATION_CODES: &[&str] = &["TransactionConflict", "ThrottlingError", "ProvisionedThroughputExceeded"];
 
 pub struct AccountDao {
     ddb_client: Client,
+    tables: TableNames,
+}
+
+/// What is needed from an account to work out a change to its balance,
+/// including the sequence number of its latest ledger entry.
+struct LedgerPosition {
+    status: AccountStatus,
+    currency: String,
+    balance: BigDecimal,
+    overdraft_limit: BigDecimal,
+    held_amount: BigDecimal,
+    sequence: u64,
+    version: u64,
+}
+
+impl LedgerPosition {
+    /// The currency of an account never changes, so it is enough to check it when read.
+    fn check_currency(&self, currency: Currency) -> Result<(), AppError> {
+        currency.check_account_currency(&self.currency)
+    }
+
+    fn check_version(&self, expected_version: Option<u64>) -> Result<(), AppError> {
+        match expected_version {
+            Some(version) if version != self.version => Err(AppError::precondition_failed()),
+            _ => Ok(()),
+        }
+    }
 }
 
 // For Client API see https://docs.rs/aws-sdk-dynamodb/latest/aws_sdk_dynamodb/client/index.html
 // https://docs.aws.amazon.com/amazondynamodb/latest/developerguide/SQLtoNoSQL.UpdateData.html
 // https://docs.aws.amazon.com/amazondynamodb/latest/developerguide/Expressions.UpdateExpressions.html
+// https://docs.aws.amazon.com/amazondynamodb/latest/developerguide/transaction-apis.html
+
+// Every change to a balance writes a ledger entry in the same transaction.
+// The entry records the resulting balance, which a transaction can not return,
+// so the ledger position is read first and the transaction is conditional on it
+// not having moved. If it has moved, the change is attempted again.
 
 impl AccountDao {
-    pub fn new(ddb_client: Client) -> Self {
-        Self { ddb_client }
+    pub fn new(ddb_client: Client, tables: TableNames) -> Self {
+        Self { ddb_client, tables }
+    }
+
+    /// Reads consistently, as the position is about to be used as the condition of a change.
+    async fn read_ledger_position(
+        &self,
+        account_id: &str,
+    ) -> Result<Option<LedgerPosition>, AppError> {
+        let get = || {
+            self.ddb_client
+                .get_item()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .table_name(&self.tables.accounts)
+                .key("accountId", AttributeValue::S(account_id.to_string()))
+                .consistent_read(true)
+        };
+
+        match measured("GetItem", send_with_retries(is_retryable, || get().send())).await?.item {
+            Some(attrs) => Ok(Some(unpack_ledger_position(&attrs)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Updates which do not otherwise read the account first only do so when a version is expected.
+    /// The update is then conditional on the version read.
+    async fn check_version(&self, account_id: &str, expected_version: Option<u64>) -> Result<(), AppError> {
+        if expected_version.is_some() {
+            self.read_ledger_position(account_id)
+                .await?
+                .ok_or_else(AppError::account_not_found)?
+                .check_version(expected_version)?;
+        }
+        Ok(())
+    }
+
+    /// Throttled and conflicting transactions are sent again, all with the same token so that
+    /// DynamoDB will not apply one twice when it succeeded but the response did not arrive.
+    async fn transact_write(&self, items: Vec<TransactWriteItem>) -> Result<(), SdkError<TransactWriteItemsError>> {
+        let token = Uuid::new_v4().to_string();
+        let send = send_with_retries(is_transaction_retryable, || {
+            self.ddb_client
+                .transact_write_items()
+                .set_transact_items(Some(items.clone()))
+                .client_request_token(&token)
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .send()
+        });
+        measured("TransactWriteItems", send).await?;
+        Ok(())
     }
+}
 
-    pub async fn adjust_account(
+#[async_trait]
+impl AccountRepository for AccountDao {
+    async fn adjust_account(
         &self,
         account_id: String,
         amount: BigDecimal,
+        currency: Currency,
+        description: Option<String>,
+        idempotent_request: Option<&IdempotentRequest>,
+        expected_version: Option<u64>,
     ) -> Result<BigDecimal, AppError> {
-        let update = if amount.sign() != Sign::Minus {
-            self.update_to_change_balance(account_id, amount)
-        } else {
-            let min_balance = amount.to_owned().neg();
-            self.update_with_min_balance_condition(account_id, amount, min_balance)
+        for _attempt in 0..MAX_ATTEMPTS {
+            let position = self
+                .read_ledger_position(&account_id)
+                .await?
+                .ok_or_else(AppError::account_not_found)?;
+            position.check_version(expected_version)?;
+            position.status.check_active()?;
+            position.check_currency(currency)?;
+            let entry = new_ledger_entry(new_transaction_id(), &position, &amount, &description);
+
+            // The account was there when read, but the update must not recreate it if it has gone since.
+            let update = if amount.sign() != Sign::Minus {
+                update_to_change_balance(&self.tables, &account_id, &entry, &position, &[ACCOUNT_EXISTS_CONDITION])
+            } else {
+                update_with_min_balance_condition(
+                    &self.tables,
+                    &account_id,
+                    &entry,
+                    &position,
+                    &[ACCOUNT_EXISTS_CONDITION],
+                )
+            };
+
+            let mut items = vec![
+                TransactWriteItem::builder().update(update.build()).build(),
+                put_ledger_entry(&self.tables, &account_id, &entry),
+            ];
+            if let Some(request) = idempotent_request {
+                let response = (request.respond)(&entry.balance)?;
+                items.push(put_idempotency_record(&self.tables, request, response));
+            }
+            items.extend(put_balance_snapshot(&self.tables, &account_id, &entry));
+
+            let result = self.transact_write(items).await;
+
+            match result {
+                Ok(_) => return Ok(entry.balance),
+                Err(err) if has_moved_on(&err, 0, &position) => continue,
+                Err(err) if is_idempotency_key_taken(&err) => {
+                    return Err(AppError::business(
+                        ErrorCode::IdempotencyKeyInProgress,
+                        "a request with the same idempotency key is in progress",
+                    ))
+                }
+                Err(err) => return Err(map_condition_failure_to(err, ErrorCode::InsufficientFunds, "insufficient funds")),
+            }
+        }
+        Err(too_many_attempts())
+    }
+
+    /// Both legs, and their ledger entries, are written in a single transaction.
+    async fn transfer(
+        &self,
+        from_account_id: String,
+        to_account_id: String,
+        amount: BigDecimal,
+        currency: Currency,
+        description: Option<String>,
+    ) -> Result<(), AppError> {
+        for _attempt in 0..MAX_ATTEMPTS {
+            let from = self
+                .read_ledger_position(&from_account_id)
+                .await?
+                .ok_or_else(unknown_account)?;
+            let to = self
+                .read_ledger_position(&to_account_id)
+                .await?
+                .ok_or_else(unknown_account)?;
+            from.status.check_active()?;
+            to.status.check_active()?;
+            from.check_currency(currency)?;
+            to.check_currency(currency)?;
+
+            let transaction_id = new_transaction_id();
+            let debit_entry =
+                new_ledger_entry(transaction_id.clone(), &from, &amount.to_owned().neg(), &description);
+            let credit_entry = new_ledger_entry(transaction_id, &to, &amount, &description);
+
+            let debit = update_with_min_balance_condition(
+                &self.tables,
+                &from_account_id,
+                &debit_entry,
+                &from,
+                &[ACCOUNT_EXISTS_CONDITION],
+            );
+            let credit =
+                update_to_change_balance(&self.tables, &to_account_id, &credit_entry, &to, &[ACCOUNT_EXISTS_CONDITION]);
+
+            let mut items = vec![
+                TransactWriteItem::builder().update(debit.build()).build(),
+                put_ledger_entry(&self.tables, &from_account_id, &debit_entry),
+                TransactWriteItem::builder().update(credit.build()).build(),
+                put_ledger_entry(&self.tables, &to_account_id, &credit_entry),
+            ];
+            items.extend(put_balance_snapshot(&self.tables, &from_account_id, &debit_entry));
+            items.extend(put_balance_snapshot(&self.tables, &to_account_id, &credit_entry));
+
+            let result = self.transact_write(items).await;
+
+            match result {
+                Ok(_) => return Ok(()),
+                Err(err) if has_moved_on(&err, 0, &from) || has_moved_on(&err, 2, &to) => continue,
+                Err(err) => return Err(map_transfer_failure(err)),
+            }
+        }
+        Err(too_many_attempts())
+    }
+
+    async fn create_account(&self, account: Account) -> Result<(), AppError> {
+        let opened_at = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        let put = || {
+            let put = self
+                .ddb_client
+                .put_item()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .table_name(&self.tables.accounts)
+                .item("accountId", AttributeValue::S(account.account_id.clone()))
+                .item("currency", AttributeValue::S(account.currency.code().to_string()))
+                .item("balance", AttributeValue::N(account.balance.to_string()))
+                .item("overdraftLimit", AttributeValue::N(account.overdraft_limit.to_string()))
+                .item("status", AttributeValue::S(account.status.code().to_string()))
+                .item("ledgerSequence", AttributeValue::N("0".to_string()))
+                .item("version", number(1))
+                .item("openedAt", AttributeValue::S(opened_at.clone()))
+                .condition_expression("attribute_not_exists(accountId)");
+            put_holder_details(put, &account.holder)
         };
 
-        let output = update
-            .send()
+        measured("PutItem", send_with_retries(is_retryable, || put().send()))
             .await
-            .map_err(|err| map_condition_failure_to(err, "insufficient funds"))?;
-        // TODO - trap error when account unknown.
+            .map_err(|err| map_put_condition_failure_to(err, ErrorCode::AccountExists, "account already exists"))?;
+        Ok(())
+    }
+
+    async fn read_account(&self, account_id: String) -> Result<Account, AppError> {
+        let get = || {
+            self.ddb_client
+                .get_item()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .table_name(&self.tables.accounts)
+                .key("accountId", AttributeValue::S(account_id.clone()))
+                .consistent_read(false)
+        };
 
-        let attrs = output.attributes.ok_or_else(AppError::not_found)?;
+        let attrs = measured("GetItem", send_with_retries(is_retryable, || get().send())).await?
+            .item.ok_or_else(AppError::account_not_found)?;
 
-        let balance = unpack_balance(attrs)?;
-        Ok(balance)
+        let account = unpack_account(attrs)?;
+        Ok(account)
     }
 
-    fn update_to_change_balance(&self, account_id: String, amount: BigDecimal) -> UpdateItem {
-        self.ddb_client
-            .update_item()
-            .table_name("Accounts")
-            .key("accountId", AttributeValue::S(account_id))
-            .update_expression("SET balance = balance + :amount")
-            .expression_attribute_values(":amount", AttributeValue::N(amount.to_string()))
-            .return_values(ReturnValue::UpdatedNew)
+    /// Scans, so the accounts come in the order DynamoDB keeps them.
+    async fn list_accounts(
+        &self,
+        limit: i32,
+        start_after: Option<String>,
+    ) -> Result<(Vec<Account>, Option<String>), AppError> {
+        let scan = || {
+            let scan = self
+                .ddb_client
+                .scan()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .table_name(&self.tables.accounts)
+                .limit(limit);
+            match &start_after {
+                Some(account_id) => scan.exclusive_start_key("accountId", AttributeValue::S(account_id.clone())),
+                None => scan,
+            }
+        };
+
+        let output = measured("Scan", send_with_retries(is_retryable, || scan().send())).await?;
+
+        let accounts = output
+            .items
+            .unwrap_or_default()
+            .into_iter()
+            .map(unpack_account)
+            .collect::<Result<Vec<Account>, AppError>>()?;
+        let next = match output.last_evaluated_key {
+            Some(key) => Some(str_attr(&key, "accountId")?),
+            None => None,
+        };
+        Ok((accounts, next))
     }
 
-    fn update_with_min_balance_condition(
+    /// The available funds, which take off what is on hold, can not be left below zero.
+    async fn update_overdraft_limit(
         &self,
         account_id: String,
-        amount: BigDecimal,
-        min_balance: BigDecimal,
-    ) -> UpdateItem {
-        self.update_to_change_balance(account_id, amount)
-            .condition_expression("balance >= :min_bal")
-            .expression_attribute_values(":min_bal", AttributeValue::N(min_balance.to_string()))
-    }
-
-    pub async fn create_account(&self, account: Account) -> Result<(), AppError> {
-        let put = self
-            .ddb_client
-            .put_item()
-            .table_name("Accounts")
-            .item("accountId", AttributeValue::S(account.account_id))
-            .item("balance", AttributeValue::N(account.balance.to_string()));
-
-        put.send().await?;
+        overdraft_limit: BigDecimal,
+        expected_version: Option<u64>,
+    ) -> Result<(), AppError> {
+        let mut conditions = vec![ACCOUNT_EXISTS_CONDITION, MIN_BALANCE_CONDITION, HELD_AMOUNT_CONDITION];
+        if expected_version.is_some() {
+            conditions.push(VERSION_CONDITION);
+        }
+
+        for _attempt in 0..MAX_ATTEMPTS {
+            let position = self
+                .read_ledger_position(&account_id)
+                .await?
+                .ok_or_else(AppError::account_not_found)?;
+            position.check_version(expected_version)?;
+            let min_balance = &position.held_amount - &overdraft_limit;
+
+            let update = || {
+                let update = self
+                    .ddb_client
+                    .update_item()
+                    .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                    .table_name(&self.tables.accounts)
+                    .key("accountId", AttributeValue::S(account_id.clone()))
+                    .update_expression("SET overdraftLimit = :limit ADD version :one")
+                    .condition_expression(conditions.join(" AND "))
+                    .expression_attribute_values(":limit", AttributeValue::N(overdraft_limit.to_string()))
+                    .expression_attribute_values(":min_bal", AttributeValue::N(min_balance.to_string()))
+                    .expression_attribute_values(":held", AttributeValue::N(position.held_amount.to_string()))
+                    .expression_attribute_values(":one", number(1));
+                match expected_version {
+                    Some(version) => update.expression_attribute_values(":version", number(version)),
+                    None => update,
+                }
+            };
+
+            match measured("UpdateItem", send_with_retries(is_retryable, || update().send())).await {
+                Ok(_) => return Ok(()),
+                Err(ServiceError { err, raw: _ }) if err.is_conditional_check_failed_exception() => {
+                    // A single update does not say which part of the condition failed.
+                    let current = self
+                        .read_ledger_position(&account_id)
+                        .await?
+                        .ok_or_else(AppError::account_not_found)?;
+                    current.check_version(expected_version)?;
+                    if current.held_amount != position.held_amount {
+                        continue;
+                    }
+                    return Err(overdraft_limit_below_balance());
+                }
+                Err(err) => return Err(AppError::from(err)),
+            }
+        }
+        Err(too_many_attempts())
+    }
+
+    async fn update_status(
+        &self,
+        account_id: String,
+        from: AccountStatus,
+        to: AccountStatus,
+        expected_version: Option<u64>,
+    ) -> Result<(), AppError> {
+        self.check_version(&account_id, expected_version).await?;
+        let mut conditions = vec![ACCOUNT_EXISTS_CONDITION];
+        conditions.push(if from == AccountStatus::Active {
+            "(attribute_not_exists(#status) OR #status = :from)"
+        } else {
+            "#status = :from"
+        });
+        if to == AccountStatus::Closed {
+            conditions.push("balance = :zero");
+            conditions.push("(attribute_not_exists(heldAmount) OR heldAmount = :zero)");
+        }
+        if expected_version.is_some() {
+            conditions.push(VERSION_CONDITION);
+        }
+
+        let update = || {
+            let update = self
+                .ddb_client
+                .update_item()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .table_name(&self.tables.accounts)
+                .key("accountId", AttributeValue::S(account_id.clone()))
+                .update_expression("SET #status = :to ADD version :one")
+                .condition_expression(conditions.join(" AND "))
+                .expression_attribute_names("#status", "status")
+                .expression_attribute_values(":from", AttributeValue::S(from.code().to_string()))
+                .expression_attribute_values(":to", AttributeValue::S(to.code().to_string()))
+                .expression_attribute_values(":one", number(1));
+            let update = if to == AccountStatus::Closed {
+                update.expression_attribute_values(":zero", AttributeValue::N("0".to_string()))
+            } else {
+                update
+            };
+            match expected_version {
+                Some(version) => update.expression_attribute_values(":version", number(version)),
+                None => update,
+            }
+        };
+
+        match measured("UpdateItem", send_with_retries(is_retryable, || update().send())).await {
+            Ok(_) => Ok(()),
+            Err(ServiceError { err, raw: _ }) if err.is_conditional_check_failed_exception() => {
+                self.check_version(&account_id, expected_version).await?;
+                Err(AppError::business(ErrorCode::ConcurrentUpdate, "account changed while updating its status"))
+            }
+            Err(err) => Err(AppError::from(err)),
+        }
+    }
+
+    /// Fields set by the patch are written and those which are null removed, in a single update
+    /// which returns the account as it then is.
+    async fn update_holder(
+        &self,
+        account_id: String,
+        patch: &HolderPatch,
+        expected_version: Option<u64>,
+    ) -> Result<Account, AppError> {
+        self.check_version(&account_id, expected_version).await?;
+        let mut set = Vec::new();
+        let mut remove = Vec::new();
+        let mut values = HashMap::from([(":one".to_string(), number(1))]);
+        for (name, value) in [
+            ("ownerName", &patch.owner_name),
+            ("contactEmail", &patch.contact_email),
+            ("externalReference", &patch.external_reference),
+        ] {
+            match value {
+                Some(Some(text)) => {
+                    set.push(format!("{} = :{}", name, name));
+                    values.insert(format!(":{}", name), AttributeValue::S(text.clone()));
+                }
+                Some(None) => remove.push(name),
+                None => {}
+            }
+        }
+        // DynamoDB can not store an empty set, so having no tags is having no attribute.
+        match &patch.tags {
+            Some(Some(tags)) if !tags.is_empty() => {
+                set.push("tags = :tags".to_string());
+                values.insert(":tags".to_string(), AttributeValue::Ss(tags.iter().cloned().collect()));
+            }
+            Some(_) => remove.push("tags"),
+            None => {}
+        }
+        let mut conditions = vec![ACCOUNT_EXISTS_CONDITION];
+        if let Some(version) = expected_version {
+            conditions.push(VERSION_CONDITION);
+            values.insert(":version".to_string(), number(version));
+        }
+
+        let mut expression = Vec::new();
+        if !set.is_empty() {
+            expression.push(format!("SET {}", set.join(", ")));
+        }
+        if !remove.is_empty() {
+            expression.push(format!("REMOVE {}", remove.join(", ")));
+        }
+        expression.push("ADD version :one".to_string());
+
+        let update = || {
+            self.ddb_client
+                .update_item()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .table_name(&self.tables.accounts)
+                .key("accountId", AttributeValue::S(account_id.clone()))
+                .update_expression(expression.join(" "))
+                .condition_expression(conditions.join(" AND "))
+                .set_expression_attribute_values(Some(values.clone()))
+                .return_values(ReturnValue::AllNew)
+        };
+
+        match measured("UpdateItem", send_with_retries(is_retryable, || update().send())).await {
+            Ok(output) => unpack_account(output.attributes.ok_or_else(|| app_err("account not returned by dynamodb".to_string()))?),
+            Err(ServiceError { err, raw: _ }) if err.is_conditional_check_failed_exception() => {
+                self.check_version(&account_id, expected_version).await?;
+                Err(AppError::account_not_found())
+            }
+            Err(err) => Err(AppError::from(err)),
+        }
+    }
+
+    /// The hold is recorded in the same transaction as the account's held amount goes up.
+    async fn create_hold(
+        &self,
+        account_id: String,
+        hold: &Hold,
+        expected_version: Option<u64>,
+    ) -> Result<(), AppError> {
+        for _attempt in 0..MAX_ATTEMPTS {
+            let position = self
+                .read_ledger_position(&account_id)
+                .await?
+                .ok_or_else(AppError::account_not_found)?;
+            position.check_version(expected_version)?;
+            position.status.check_active()?;
+            position.check_currency(hold.currency)?;
+
+            let min_balance = min_balance_for(&hold.amount, &position);
+            let conditions = [
+                ACCOUNT_EXISTS_CONDITION,
+                STATUS_ACTIVE_CONDITION,
+                MIN_BALANCE_CONDITION,
+                OVERDRAFT_LIMIT_CONDITION,
+                HELD_AMOUNT_CONDITION,
+                VERSION_CONDITION,
+            ];
+            let update = Update::builder()
+                .table_name(&self.tables.accounts)
+                .key("accountId", AttributeValue::S(account_id.clone()))
+                .update_expression("SET heldAmount = if_not_exists(heldAmount, :zero) + :amount ADD version :one")
+                .condition_expression(conditions.join(" AND "))
+                .expression_attribute_names("#status", "status")
+                .expression_attribute_values(":active", AttributeValue::S(AccountStatus::Active.code().to_string()))
+                .expression_attribute_values(":amount", AttributeValue::N(hold.amount.to_string()))
+                .expression_attribute_values(":zero", AttributeValue::N("0".to_string()))
+                .expression_attribute_values(":min_bal", AttributeValue::N(min_balance.to_string()))
+                .expression_attribute_values(":version", number(position.version))
+                .expression_attribute_values(":one", number(1))
+                .return_values_on_condition_check_failure(ReturnValuesOnConditionCheckFailure::AllOld);
+            let update = with_available_funds_values(update, &position);
+
+            let result = self
+                .transact_write(vec![
+                    TransactWriteItem::builder().update(update.build()).build(),
+                    put_hold(&self.tables, &account_id, hold),
+                ])
+                .await;
+
+            match result {
+                Ok(_) => return Ok(()),
+                Err(err) if has_moved_on(&err, 0, &position) => continue,
+                Err(err) => return Err(map_condition_failure_to(err, ErrorCode::InsufficientFunds, "insufficient funds")),
+            }
+        }
+        Err(too_many_attempts())
+    }
+
+    /// The available funds do not change, as the money was already set aside.
+    /// The hold is removed in the same transaction, on condition it has not expired.
+    async fn capture_hold(
+        &self,
+        account_id: String,
+        hold: &Hold,
+        now: i64,
+        expected_version: Option<u64>,
+    ) -> Result<BigDecimal, AppError> {
+        let amount = hold.amount.to_owned().neg();
+        for _attempt in 0..MAX_ATTEMPTS {
+            let position = self
+                .read_ledger_position(&account_id)
+                .await?
+                .ok_or_else(AppError::account_not_found)?;
+            position.check_version(expected_version)?;
+            position.status.check_active()?;
+            let entry = new_ledger_entry(new_transaction_id(), &position, &amount, &hold.description);
+
+            let update =
+                update_to_change_balance(&self.tables, &account_id, &entry, &position, &[ACCOUNT_EXISTS_CONDITION])
+                    .update_expression(CAPTURE_HOLD);
+            let delete = delete_hold(&self.tables, &account_id, &hold.hold_id)
+                .condition_expression(format!("{} AND expiresAt > :now", HOLD_EXISTS_CONDITION))
+                .expression_attribute_values(":now", AttributeValue::N(now.to_string()));
+
+            let mut items = vec![
+                TransactWriteItem::builder().update(update.build()).build(),
+                put_ledger_entry(&self.tables, &account_id, &entry),
+                TransactWriteItem::builder().delete(delete.build()).build(),
+            ];
+            items.extend(put_balance_snapshot(&self.tables, &account_id, &entry));
+
+            let result = self.transact_write(items).await;
+
+            match result {
+                Ok(_) => return Ok(entry.balance),
+                Err(err) if has_moved_on(&err, 0, &position) => continue,
+                Err(err) if is_hold_gone(&err, 2) => return Err(AppError::hold_not_found()),
+                Err(err) => return Err(map_condition_failure_to(err, ErrorCode::ConcurrentUpdate, "can not capture hold")),
+            }
+        }
+        Err(too_many_attempts())
+    }
+
+    async fn release_hold(
+        &self,
+        account_id: String,
+        hold: &Hold,
+        expected_version: Option<u64>,
+    ) -> Result<(), AppError> {
+        self.check_version(&account_id, expected_version).await?;
+        let update = Update::builder()
+            .table_name(&self.tables.accounts)
+            .key("accountId", AttributeValue::S(account_id.clone()))
+            .update_expression("SET heldAmount = heldAmount - :amount ADD version :one")
+            .expression_attribute_values(":amount", AttributeValue::N(hold.amount.to_string()))
+            .expression_attribute_values(":one", number(1));
+        let update = match expected_version {
+            Some(version) => update
+                .condition_expression(format!("{} AND {}", ACCOUNT_EXISTS_CONDITION, VERSION_CONDITION))
+                .expression_attribute_values(":version", number(version)),
+            None => update.condition_expression(ACCOUNT_EXISTS_CONDITION),
+        };
+        let delete =
+            delete_hold(&self.tables, &account_id, &hold.hold_id).condition_expression(HOLD_EXISTS_CONDITION);
+
+        let result = self
+            .transact_write(vec![
+                TransactWriteItem::builder().update(update.build()).build(),
+                TransactWriteItem::builder().delete(delete.build()).build(),
+            ])
+            .await;
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(err) if is_hold_gone(&err, 1) => Err(AppError::hold_not_found()),
+            Err(err) => {
+                self.check_version(&account_id, expected_version).await?;
+                Err(map_condition_failure_to(err, ErrorCode::ConcurrentUpdate, "can not release hold"))
+            }
+        }
+    }
+
+    async fn read_hold(&self, account_id: &str, hold_id: &str) -> Result<Option<Hold>, AppError> {
+        let get = || {
+            self.ddb_client
+                .get_item()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .table_name(&self.tables.holds)
+                .key("accountId", AttributeValue::S(account_id.to_string()))
+                .key("holdId", AttributeValue::S(hold_id.to_string()))
+                .consistent_read(true)
+        };
+
+        match measured("GetItem", send_with_retries(is_retryable, || get().send())).await?.item {
+            Some(attrs) => Ok(Some(unpack_hold(attrs)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn list_expired_holds(&self, account_id: &str, now: i64) -> Result<Vec<Hold>, AppError> {
+        let mut holds = Vec::new();
+        let mut start_key = None;
+        loop {
+            let query = || {
+                self.ddb_client
+                    .query()
+                    .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                    .table_name(&self.tables.holds)
+                    .key_condition_expression("accountId = :account_id")
+                    .filter_expression("expiresAt <= :now")
+                    .expression_attribute_values(":account_id", AttributeValue::S(account_id.to_string()))
+                    .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+                    .set_exclusive_start_key(start_key.clone())
+                    .consistent_read(true)
+            };
+            let output = measured("Query", send_with_retries(is_retryable, || query().send())).await?;
+            for attrs in output.items.unwrap_or_default() {
+                holds.push(unpack_hold(attrs)?);
+            }
+            start_key = output.last_evaluated_key;
+            if start_key.is_none() {
+                return Ok(holds);
+            }
+        }
+    }
+
+    async fn list_transactions(
+        &self,
+        account_id: String,
+        limit: i32,
+        start_after: Option<u64>,
+    ) -> Result<(Vec<Transaction>, Option<u64>), AppError> {
+        let query = || {
+            let query = self
+                .ddb_client
+                .query()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .table_name(&self.tables.transactions)
+                .key_condition_expression("accountId = :account_id")
+                .expression_attribute_values(":account_id", AttributeValue::S(account_id.clone()))
+                .scan_index_forward(false)
+                .limit(limit);
+            match start_after {
+                Some(sequence) => query
+                    .exclusive_start_key("accountId", AttributeValue::S(account_id.clone()))
+                    .exclusive_start_key("sequenceNo", number(sequence)),
+                None => query,
+            }
+        };
+
+        let output = measured("Query", send_with_retries(is_retryable, || query().send())).await?;
+
+        let transactions = output
+            .items
+            .unwrap_or_default()
+            .into_iter()
+            .map(unpack_transaction)
+            .collect::<Result<Vec<Transaction>, AppError>>()?;
+        let next = match output.last_evaluated_key {
+            Some(key) => Some(u64_attr(&key, "sequenceNo")?),
+            None => None,
+        };
+        Ok((transactions, next))
+    }
+
+    async fn list_transactions_after(
+        &self,
+        account_id: &str,
+        sequence: u64,
+        limit: i32,
+    ) -> Result<Vec<Transaction>, AppError> {
+        let query = || {
+            self.ddb_client
+                .query()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .table_name(&self.tables.transactions)
+                .key_condition_expression("accountId = :account_id AND sequenceNo > :seq")
+                .expression_attribute_values(":account_id", AttributeValue::S(account_id.to_string()))
+                .expression_attribute_values(":seq", number(sequence))
+                .scan_index_forward(true)
+                .limit(limit)
+        };
+
+        measured("Query", send_with_retries(is_retryable, || query().send()))
+            .await?
+            .items
+            .unwrap_or_default()
+            .into_iter()
+            .map(unpack_transaction)
+            .collect()
+    }
+
+    /// Snapshots are kept by when they were taken, so the newest by then is the first found going back from it.
+    async fn read_balance_snapshot(
+        &self,
+        account_id: &str,
+        at_or_before: &str,
+    ) -> Result<Option<BalanceSnapshot>, AppError> {
+        let query = || {
+            self.ddb_client
+                .query()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .table_name(&self.tables.balance_snapshots)
+                .key_condition_expression("accountId = :account_id AND takenAt <= :at_or_before")
+                .expression_attribute_values(":account_id", AttributeValue::S(account_id.to_string()))
+                .expression_attribute_values(":at_or_before", AttributeValue::S(at_or_before.to_string()))
+                .scan_index_forward(false)
+                .limit(1)
+        };
+
+        let output = measured("Query", send_with_retries(is_retryable, || query().send())).await?;
+        match output.items.unwrap_or_default().into_iter().next() {
+            Some(attrs) => Ok(Some(BalanceSnapshot {
+                sequence: u64_attr(&attrs, "sequenceNo")?,
+                balance: decimal_attr(&attrs, "balance")?.normalized(),
+                timestamp: str_attr(&attrs, "takenAt")?,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    /// Expired records are ignored here, as DynamoDB may take a while to remove them.
+    async fn read_idempotency_record(
+        &self,
+        key: &str,
+        now: i64,
+    ) -> Result<Option<(String, StoredResponse)>, AppError> {
+        let get = || {
+            self.ddb_client
+                .get_item()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .table_name(&self.tables.idempotency_keys)
+                .key("idempotencyKey", AttributeValue::S(key.to_string()))
+                .consistent_read(true)
+        };
+
+        match measured("GetItem", send_with_retries(is_retryable, || get().send())).await?.item {
+            Some(attrs) if i64_attr(&attrs, "expiresAt")? > now => {
+                let fingerprint = str_attr(&attrs, "fingerprint")?;
+                let response = StoredResponse {
+                    status: u16_attr(&attrs, "status")?,
+                    body: str_attr(&attrs, "body")?,
+                };
+                Ok(Some((fingerprint, response)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn remember_response(&self, request: &IdempotentRequest, response: StoredResponse) -> Result<(), AppError> {
+        let put = || {
+            self.ddb_client
+                .put_item()
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .table_name(&self.tables.idempotency_keys)
+                .item("idempotencyKey", AttributeValue::S(request.key.clone()))
+                .item("fingerprint", AttributeValue::S(request.fingerprint.clone()))
+                .item("status", AttributeValue::N(response.status.to_string()))
+                .item("body", AttributeValue::S(response.body.clone()))
+                .item("expiresAt", AttributeValue::N(request.expires_at.to_string()))
+                .condition_expression("attribute_not_exists(idempotencyKey) OR expiresAt < :now")
+                .expression_attribute_values(":now", AttributeValue::N(request.now.to_string()))
+        };
+
+        measured("PutItem", send_with_retries(is_retryable, || put().send()))
+            .await
+            .map_err(|err| {
+                map_put_condition_failure_to(
+                    err,
+                    ErrorCode::IdempotencyKeyInProgress,
+                    "a request with the same idempotency key is in progress",
+                )
+            })?;
         Ok(())
     }
+}
 
-    pub async fn read_account(&self, account_id: String) -> Result<Account, AppError> {
-        let get = self
-            .ddb_client
-            .get_item()
-            .table_name("Accounts")
-            .key("accountId", AttributeValue::S(account_id))
-            .consistent_read(false);
+fn update_to_change_balance(
+    tables: &TableNames,
+    account_id: &str,
+    entry: &Transaction,
+    position: &LedgerPosition,
+    conditions: &[&str],
+) -> update::Builder {
+    let mut all_conditions = vec![LEDGER_POSITION_CONDITION, STATUS_ACTIVE_CONDITION, VERSION_CONDITION];
+    all_conditions.extend_from_slice(conditions);
+    Update::builder()
+        .table_name(&tables.accounts)
+        .key("accountId", AttributeValue::S(account_id.to_string()))
+        .update_expression(CHANGE_BALANCE)
+        .condition_expression(all_conditions.join(" AND "))
+        .expression_attribute_names("#status", "status")
+        .expression_attribute_values(":active", AttributeValue::S(AccountStatus::Active.code().to_string()))
+        .expression_attribute_values(":amount", AttributeValue::N(entry.amount.to_string()))
+        .expression_attribute_values(":seq", number(entry.sequence))
+        .expression_attribute_values(":prev_seq", number(entry.sequence - 1))
+        .expression_attribute_values(":version", number(position.version))
+        .expression_attribute_values(":one", number(1))
+        .return_values_on_condition_check_failure(ReturnValuesOnConditionCheckFailure::AllOld)
+}
 
-        let attrs = get.send().await?
-            .item.ok_or_else(AppError::not_found)?;
+/// The balance may go down to minus the overdraft limit, less any funds on hold, but no further.
+/// DynamoDB conditions can not do arithmetic, so the lowest balance the debit can
+/// be taken from is worked out here, on condition that the limit and holds have not changed.
+fn update_with_min_balance_condition(
+    tables: &TableNames,
+    account_id: &str,
+    entry: &Transaction,
+    position: &LedgerPosition,
+    conditions: &[&str],
+) -> update::Builder {
+    let min_balance = min_balance_for(&entry.amount.to_owned().neg(), position);
+    let mut all_conditions = vec![MIN_BALANCE_CONDITION, OVERDRAFT_LIMIT_CONDITION, HELD_AMOUNT_CONDITION];
+    all_conditions.extend_from_slice(conditions);
+    let update = update_to_change_balance(tables, account_id, entry, position, &all_conditions)
+        .expression_attribute_values(":min_bal", AttributeValue::N(min_balance.to_string()));
+    with_available_funds_values(update, position)
+}
 
-        let account = unpack_account(attrs)?;
-        Ok(account)
+/// The lowest balance from which the amount can be taken, leaving the available funds not negative.
+fn min_balance_for(amount: &BigDecimal, position: &LedgerPosition) -> BigDecimal {
+    amount - &position.overdraft_limit + &position.held_amount
+}
+
+/// Adds the overdraft limit and funds on hold, which the minimum balance was worked out from,
+/// for the conditions that they have not changed since being read.
+fn with_available_funds_values(update: update::Builder, position: &LedgerPosition) -> update::Builder {
+    update
+        .expression_attribute_values(":limit", AttributeValue::N(position.overdraft_limit.to_string()))
+        .expression_attribute_values(":held", AttributeValue::N(position.held_amount.to_string()))
+}
+
+fn put_ledger_entry(tables: &TableNames, account_id: &str, entry: &Transaction) -> TransactWriteItem {
+    let mut put = Put::builder()
+        .table_name(&tables.transactions)
+        .item("accountId", AttributeValue::S(account_id.to_string()))
+        .item("sequenceNo", number(entry.sequence))
+        .item("transactionId", AttributeValue::S(entry.transaction_id.clone()))
+        .item("amount", AttributeValue::N(entry.amount.to_string()))
+        .item("balance", AttributeValue::N(entry.balance.to_string()))
+        .item("timestamp", AttributeValue::S(entry.timestamp.clone()))
+        .condition_expression("attribute_not_exists(sequenceNo)");
+    if let Some(description) = &entry.description {
+        put = put.item("description", AttributeValue::S(description.clone()));
+    }
+    TransactWriteItem::builder().put(put.build()).build()
+}
+
+/// The snapshot due with a ledger entry, if any. It goes after the other items of a transaction,
+/// so that it does not move the index of any whose cancellation reason is looked at.
+fn put_balance_snapshot(tables: &TableNames, account_id: &str, entry: &Transaction) -> Option<TransactWriteItem> {
+    let snapshot = BalanceSnapshot::due_with(entry)?;
+    let put = Put::builder()
+        .table_name(&tables.balance_snapshots)
+        .item("accountId", AttributeValue::S(account_id.to_string()))
+        .item("takenAt", AttributeValue::S(snapshot.timestamp))
+        .item("sequenceNo", number(snapshot.sequence))
+        .item("balance", AttributeValue::N(snapshot.balance.to_string()))
+        .build();
+    Some(TransactWriteItem::builder().put(put).build())
+}
+
+/// Remembers the response against the idempotency key, unless a live record is already there.
+fn put_idempotency_record(
+    tables: &TableNames,
+    request: &IdempotentRequest,
+    response: StoredResponse,
+) -> TransactWriteItem {
+    let put = Put::builder()
+        .table_name(&tables.idempotency_keys)
+        .item("idempotencyKey", AttributeValue::S(request.key.clone()))
+        .item("fingerprint", AttributeValue::S(request.fingerprint.clone()))
+        .item("status", AttributeValue::N(response.status.to_string()))
+        .item("body", AttributeValue::S(response.body))
+        .item("expiresAt", AttributeValue::N(request.expires_at.to_string()))
+        .condition_expression("attribute_not_exists(idempotencyKey) OR expiresAt < :now")
+        .expression_attribute_values(":now", AttributeValue::N(request.now.to_string()))
+        .build();
+    TransactWriteItem::builder().put(put).build()
+}
+
+/// Only the details given are written, as DynamoDB can not store an empty set of tags.
+fn put_holder_details(mut put: fluent_builders::PutItem, holder: &HolderDetails) -> fluent_builders::PutItem {
+    for (name, value) in [
+        ("ownerName", &holder.owner_name),
+        ("contactEmail", &holder.contact_email),
+        ("externalReference", &holder.external_reference),
+    ] {
+        if let Some(text) = value {
+            put = put.item(name, AttributeValue::S(text.clone()));
+        }
+    }
+    if !holder.tags.is_empty() {
+        put = put.item("tags", AttributeValue::Ss(holder.tags.iter().cloned().collect()));
+    }
+    put
+}
+
+fn put_hold(tables: &TableNames, account_id: &str, hold: &Hold) -> TransactWriteItem {
+    let mut put = Put::builder()
+        .table_name(&tables.holds)
+        .item("accountId", AttributeValue::S(account_id.to_string()))
+        .item("holdId", AttributeValue::S(hold.hold_id.clone()))
+        .item("amount", AttributeValue::N(hold.amount.to_string()))
+        .item("currency", AttributeValue::S(hold.currency.code().to_string()))
+        .item("expiresAt", AttributeValue::N(hold.expires_at.timestamp().to_string()))
+        .condition_expression("attribute_not_exists(holdId)");
+    if let Some(description) = &hold.description {
+        put = put.item("description", AttributeValue::S(description.clone()));
+    }
+    TransactWriteItem::builder().put(put.build()).build()
+}
+
+fn delete_hold(tables: &TableNames, account_id: &str, hold_id: &str) -> delete::Builder {
+    Delete::builder()
+        .table_name(&tables.holds)
+        .key("accountId", AttributeValue::S(account_id.to_string()))
+        .key("holdId", AttributeValue::S(hold_id.to_string()))
+}
+
+fn new_ledger_entry(
+    transaction_id: String,
+    position: &LedgerPosition,
+    amount: &BigDecimal,
+    description: &Option<String>,
+) -> Transaction {
+    Transaction {
+        transaction_id,
+        sequence: position.sequence + 1,
+        amount: amount.normalized(),
+        balance: (&position.balance + amount).normalized(),
+        timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        description: description.clone(),
+    }
+}
+
+fn new_transaction_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/// The transaction reports a reason for each of its items, in the order they were given.
+/// The old item is only returned when it exists.
+fn cancellation_reasons(txn_err: &SdkError<TransactWriteItemsError>) -> &[CancellationReason] {
+    match txn_err {
+        ServiceError { err, raw: _ } => match &err.kind {
+            TransactWriteItemsErrorKind::TransactionCanceledException(cancelled) => {
+                cancelled.cancellation_reasons().unwrap_or_default()
+            }
+            _ => &[],
+        },
+        _ => &[],
+    }
+}
+
+/// True when the account update at the given index failed because another change
+/// got in between reading the ledger position and writing.
+fn has_moved_on(
+    txn_err: &SdkError<TransactWriteItemsError>,
+    index: usize,
+    position: &LedgerPosition,
+) -> bool {
+    match cancellation_reasons(txn_err).get(index) {
+        Some(reason) if is_failed_check(reason) => match &reason.item {
+            Some(attrs) => !matches!(unpack_ledger_position(attrs),
+                Ok(current) if current.sequence == position.sequence
+                    && current.status == position.status
+                    && current.overdraft_limit == position.overdraft_limit
+                    && current.held_amount == position.held_amount
+                    && current.version == position.version),
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+/// The debit is first and the credit third, each followed by its ledger entry.
+/// A failed condition without an item means the account is unknown.
+fn map_transfer_failure(txn_err: SdkError<TransactWriteItemsError>) -> AppError {
+    match cancellation_reasons(&txn_err) {
+        [debit, _, _, _, ..] if is_failed_check(debit) => {
+            if debit.item.is_some() {
+                AppError::business(ErrorCode::InsufficientFunds, "insufficient funds")
+            } else {
+                unknown_account()
+            }
+        }
+        [_, _, credit, _, ..] if is_failed_check(credit) => unknown_account(),
+        _ => map_transaction_failure(txn_err),
+    }
+}
+
+fn is_failed_check(reason: &CancellationReason) -> bool {
+    matches!(&reason.code, Some(code) if code == "ConditionalCheckFailed")
+}
+
+/// A transaction cancelled because of a failed condition would only fail the same way again.
+fn is_transaction_retryable(txn_err: &SdkError<TransactWriteItemsError>) -> bool {
+    let reasons = cancellation_reasons(txn_err);
+    if reasons.is_empty() {
+        return is_retryable(txn_err);
+    }
+    !reasons.iter().any(is_failed_check)
+        && reasons
+            .iter()
+            .any(|reason| matches!(&reason.code, Some(code) if RETRYABLE_CANCELLATION_CODES.contains(&code.as_str())))
+}
+
+/// True when the removal of the hold at the given index failed because it was already gone, or expired.
+fn is_hold_gone(txn_err: &SdkError<TransactWriteItemsError>, index: usize) -> bool {
+    matches!(cancellation_reasons(txn_err).get(index), Some(reason) if is_failed_check(reason))
+}
+
+/// The idempotency record is written third, after the update and ledger entry.
+fn is_idempotency_key_taken(txn_err: &SdkError<TransactWriteItemsError>) -> bool {
+    matches!(cancellation_reasons(txn_err).get(2), Some(reason) if is_failed_check(reason))
+}
+
+/// A failed condition on the account update means the account is missing,
+/// when no item is returned, otherwise the given business rule was broken.
+fn map_condition_failure_to(
+    txn_err: SdkError<TransactWriteItemsError>,
+    code: ErrorCode,
+    message: &str,
+) -> AppError {
+    match cancellation_reasons(&txn_err).first() {
+        Some(reason) if is_failed_check(reason) => match reason.item {
+            Some(_) => AppError::business(code, message),
+            None => AppError::account_not_found(),
+        },
+        _ => map_transaction_failure(txn_err),
+    }
+}
+
+/// Conflicts which are still happening after retries are not an internal failure.
+fn map_transaction_failure(txn_err: SdkError<TransactWriteItemsError>) -> AppError {
+    if is_transaction_retryable(&txn_err) {
+        AppError::Unavailable(Box::new(txn_err))
+    } else {
+        AppError::from(txn_err)
     }
 }
 
-fn map_condition_failure_to(upd_err: SdkError<UpdateItemError>, message: &str) -> AppError {
-    if is_condition_failure(&upd_err) {
-        AppError::unprocessable(message)
+fn map_put_condition_failure_to(put_err: SdkError<PutItemError>, code: ErrorCode, message: &str) -> AppError {
+    if matches!(&put_err, ServiceError{err, raw: _} if err.is_conditional_check_failed_exception()) {
+        AppError::business(code, message)
     } else {
-        AppError::Internal(Box::new(upd_err))
+        AppError::from(put_err)
     }
 }
 
-fn is_condition_failure(upd_err: &SdkError<UpdateItemError>) -> bool {
-    matches!(upd_err, ServiceError{err, raw: _} if err.is_conditional_check_failed_exception())
+fn overdraft_limit_below_balance() -> AppError {
+    AppError::business(
+        ErrorCode::OverdraftLimitBelowBalance,
+        "balance less funds on hold is below the new overdraft limit",
+    )
+}
+
+fn unknown_account() -> AppError {
+    AppError::business(ErrorCode::UnknownAccount, "unknown account")
 }
 
-fn unpack_balance(attrs: HashMap<String, AttributeValue>) -> Result<BigDecimal, AppError> {
-    let balance = decimal_attr(&attrs, "balance")?;
-    Ok(balance.normalized())
+/// Other changes keep getting in first, which should not last.
+fn too_many_attempts() -> AppError {
+    AppError::unavailable("gave up after too many concurrent changes to account")
+}
+
+fn unpack_ledger_position(attrs: &HashMap<String, AttributeValue>) -> Result<LedgerPosition, AppError> {
+    let status = status_attr(attrs)?;
+    let currency = currency_attr(attrs)?.code().to_string();
+    let balance = decimal_attr(attrs, "balance")?;
+    let overdraft_limit = decimal_attr_or_zero(attrs, "overdraftLimit")?;
+    let held_amount = decimal_attr_or_zero(attrs, "heldAmount")?;
+    let sequence = u64_attr_or_zero(attrs, "ledgerSequence")?;
+    let version = u64_attr_or_zero(attrs, "version")?;
+    Ok(LedgerPosition {
+        status,
+        currency,
+        balance,
+        overdraft_limit,
+        held_amount,
+        sequence,
+        version,
+    })
 }
 
 fn unpack_account(attrs: HashMap<String, AttributeValue>) -> Result<Account, AppError> {
     let account_id = str_attr(&attrs, "accountId")?.to_string();
+    let currency = currency_attr(&attrs)?;
     let balance = decimal_attr(&attrs, "balance")?.normalized();
+    let overdraft_limit = decimal_attr_or_zero(&attrs, "overdraftLimit")?.normalized();
+    let held_amount = decimal_attr_or_zero(&attrs, "heldAmount")?.normalized();
+    let status = status_attr(&attrs)?;
+    let version = u64_attr_or_zero(&attrs, "version")?;
+    let holder = HolderDetails {
+        owner_name: str_attr_or_none(&attrs, "ownerName")?,
+        contact_email: str_attr_or_none(&attrs, "contactEmail")?,
+        external_reference: str_attr_or_none(&attrs, "externalReference")?,
+        tags: str_set_attr_or_empty(&attrs, "tags")?,
+    };
+    let opened_at = str_attr_or_none(&attrs, "openedAt")?;
     Ok(Account {
         account_id,
+        currency,
         balance,
+        overdraft_limit,
+        held_amount,
+        status,
+        available_funds: BigDecimal::default(),
+        holder,
+        version,
+        opened_at,
+    })
+}
+
+fn unpack_transaction(attrs: HashMap<String, AttributeValue>) -> Result<Transaction, AppError> {
+    let description = match attrs.get("description") {
+        Some(_) => Some(str_attr(&attrs, "description")?),
+        None => None,
+    };
+    Ok(Transaction {
+        transaction_id: str_attr(&attrs, "transactionId")?,
+        sequence: u64_attr(&attrs, "sequenceNo")?,
+        amount: decimal_attr(&attrs, "amount")?.normalized(),
+        balance: decimal_attr(&attrs, "balance")?.normalized(),
+        timestamp: str_attr(&attrs, "timestamp")?,
+        description,
+    })
+}
+
+fn unpack_hold(attrs: HashMap<String, AttributeValue>) -> Result<Hold, AppError> {
+    let description = match attrs.get("description") {
+        Some(_) => Some(str_attr(&attrs, "description")?),
+        None => None,
+    };
+    Ok(Hold {
+        hold_id: str_attr(&attrs, "holdId")?,
+        amount: decimal_attr(&attrs, "amount")?.normalized(),
+        currency: Currency::try_from(str_attr(&attrs, "currency")?).map_err(app_err)?,
+        description,
+        expires_at: Utc.timestamp(i64_attr(&attrs, "expiresAt")?, 0),
     })
 }
 
@@ -129,10 +1232,32 @@ fn str_attr(attrs: &HashMap<String, AttributeValue>, attr_name: &str) -> Result<
         .ok_or_else(|| app_err(format!("{} not returned by dynamodb", attr_name)))?;
     let val = av
         .as_s()
-        .or_else(|_av| Err(app_err(format!("{} not returned by dynamodb", attr_name))))?;
+        .map_err(|_av| app_err(format!("{} not returned by dynamodb", attr_name)))?;
     Ok(val.to_owned())
 }
 
+fn str_attr_or_none(attrs: &HashMap<String, AttributeValue>, attr_name: &str) -> Result<Option<String>, AppError> {
+    match attrs.get(attr_name) {
+        Some(_) => Ok(Some(str_attr(attrs, attr_name)?)),
+        None => Ok(None),
+    }
+}
+
+fn str_set_attr_or_empty(
+    attrs: &HashMap<String, AttributeValue>,
+    attr_name: &str,
+) -> Result<BTreeSet<String>, AppError> {
+    match attrs.get(attr_name) {
+        Some(av) => Ok(av
+            .as_ss()
+            .map_err(|_av| app_err(format!("{} not returned by dynamodb", attr_name)))?
+            .iter()
+            .cloned()
+            .collect()),
+        None => Ok(BTreeSet::new()),
+    }
+}
+
 fn decimal_attr(
     attrs: &HashMap<String, AttributeValue>,
     attr_name: &str,
@@ -142,32 +1267,102 @@ fn decimal_attr(
         .ok_or_else(|| app_err(format!("{} not returned by dynamodb", attr_name)))?;
     let val = av
         .as_n()
-        .or_else(|_av| Err(app_err(format!("{} not returned by dynamodb", attr_name))))?;
+        .map_err(|_av| app_err(format!("{} not returned by dynamodb", attr_name)))?;
     let val = BigDecimal::from_str(val)?;
     Ok(val)
 }
 
-fn app_err(message: String) -> AppError {
-    AppError::internal_s(message)
+/// Accounts created before they had a status are active.
+fn status_attr(attrs: &HashMap<String, AttributeValue>) -> Result<AccountStatus, AppError> {
+    match attrs.get("status") {
+        Some(_) => AccountStatus::from_code(&str_attr(attrs, "status")?),
+        None => Ok(AccountStatus::Active),
+    }
 }
 
-#[cfg(test)]
-mod test {
-
-    use std::{sync::Once, process::{Command, Stdio, Child, ChildStdout, ChildStdin}, str::FromStr, io::{Write, BufRead, BufReader}};
-    use aws_sdk_dynamodb::{Client, Config, Credentials, Endpoint, Region};
-    use super::{AccountDao, Account};
-    use bigdecimal::BigDecimal;
-    use http::Uri;
+/// Accounts created before they had a currency are in the legacy one.
+fn currency_attr(attrs: &HashMap<String, AttributeValue>) -> Result<Currency, AppError> {
+    match attrs.get("currency") {
+        Some(_) => Currency::try_from(str_attr(attrs, "currency")?).map_err(app_err),
+        None => Ok(Currency::LEGACY),
+    }
+}
+
+/// Accounts created before overdrafts existed have no limit.
+fn decimal_attr_or_zero(
+    attrs: &HashMap<String, AttributeValue>,
+    attr_name: &str,
+) -> Result<BigDecimal, AppError> {
+    match attrs.get(attr_name) {
+        Some(_) => decimal_attr(attrs, attr_name),
+        None => Ok(BigDecimal::default()),
+    }
+}
+
+fn u64_attr(attrs: &HashMap<String, AttributeValue>, attr_name: &str) -> Result<u64, AppError> {
+    let av = attrs
+        .get(attr_name)
+        .ok_or_else(|| app_err(format!("{} not returned by dynamodb", attr_name)))?;
+    let val = av
+        .as_n()
+        .map_err(|_av| app_err(format!("{} not returned by dynamodb", attr_name)))?;
+    val.parse()
+        .map_err(|_err| app_err(format!("{} is not a sequence number", attr_name)))
+}
+
+fn i64_attr(attrs: &HashMap<String, AttributeValue>, attr_name: &str) -> Result<i64, AppError> {
+    let av = attrs
+        .get(attr_name)
+        .ok_or_else(|| app_err(format!("{} not returned by dynamodb", attr_name)))?;
+    let val = av
+        .as_n()
+        .map_err(|_av| app_err(format!("{} not returned by dynamodb", attr_name)))?;
+    val.parse()
+        .map_err(|_err| app_err(format!("{} is not a whole number", attr_name)))
+}
+
+fn u16_attr(attrs: &HashMap<String, AttributeValue>, attr_name: &str) -> Result<u16, AppError> {
+    let val = i64_attr(attrs, attr_name)?;
+    u16::try_from(val).map_err(|_err| app_err(format!("{} is out of range", attr_name)))
+}
+
+/// Accounts created before the ledger existed have no sequence number, and before versions no version.
+fn u64_attr_or_zero(attrs: &HashMap<String, AttributeValue>, attr_name: &str) -> Result<u64, AppError> {
+    match attrs.get(attr_name) {
+        Some(_) => u64_attr(attrs, attr_name),
+        None => Ok(0),
+    }
+}
+
+fn number(value: u64) -> AttributeValue {
+    AttributeValue::N(value.to_string())
+}
+
+fn app_err(message: String) -> AppError {
+    AppError::internal_s(message)
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::{sync::OnceLock, process::{Command, Stdio, Child, ChildStdout, ChildStdin}, str::FromStr, io::{Write, BufRead, BufReader}};
+    use aws_sdk_dynamodb::{model::AttributeValue, Client, Config, Credentials, Endpoint, Region};
+    use crate::config::TableNames;
+    use super::{AccountDao, AccountRepository, Account, AccountStatus, Currency, Hold, HolderDetails, HolderPatch,
+        IdempotentRequest, StoredResponse};
+    use chrono::Utc;
+    use crate::error::{AppError, ErrorCode};
+    use bigdecimal::BigDecimal;
+    use http::Uri;
 
     #[tokio::test]
     async fn should_create_new_account() {
         // Given
         let account_id = "NEWACC001".to_string();
         let amount = BigDecimal::from_str("10.10").expect("failed to parse number");
-        let account = Account{account_id: account_id.clone(), balance: amount.clone()};
+        let account = account(&account_id, "10.10");
 
-        let dao = AccountDao::new(get_dynamodb_client());
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
 
         // When
         dao.create_account(account).await.expect("could not create account");
@@ -176,6 +1371,666 @@ mod test {
         let current_account = dao.read_account(account_id.clone()).await.expect("could not read account");
         assert_eq!(current_account.account_id, account_id);
         assert_eq!(current_account.balance, amount);
+        assert!(current_account.opened_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn should_not_overwrite_existing_account() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        create_account(&dao, "EXISTING001", "10.10").await;
+        let account = account("EXISTING001", "0");
+
+        // When
+        let result = dao.create_account(account).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::AccountExists, _))));
+        assert_eq!(read_balance(&dao, "EXISTING001").await, decimal("10.10"));
+    }
+
+    #[tokio::test]
+    async fn should_not_adjust_unknown_account() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+
+        // When
+        let result = dao.adjust_account("ADJUST_UNKNOWN".to_string(), decimal("1.00"), gbp(), None, None, None).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::AccountNotFound, _))));
+        assert!(matches!(dao.read_account("ADJUST_UNKNOWN".to_string()).await,
+            Err(AppError::Business(ErrorCode::AccountNotFound, _))));
+    }
+
+    #[tokio::test]
+    async fn should_not_adjust_below_zero() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        create_account(&dao, "ADJUST001", "1.00").await;
+
+        // When
+        let result = dao.adjust_account("ADJUST001".to_string(), decimal("-1.01"), gbp(), None, None, None).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::InsufficientFunds, message))
+            if message == "insufficient funds"));
+        assert_eq!(read_balance(&dao, "ADJUST001").await, decimal("1.00"));
+    }
+
+    #[tokio::test]
+    async fn should_allow_debit_down_to_overdraft_limit() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        create_account_with_overdraft(&dao, "OVERDRAFT001", "10.00", "5.00").await;
+
+        // When
+        let balance = dao.adjust_account("OVERDRAFT001".to_string(), decimal("-15.00"), gbp(), None, None, None)
+            .await.expect("could not debit account");
+
+        // Then
+        assert_eq!(balance, decimal("-5"));
+    }
+
+    #[tokio::test]
+    async fn should_not_debit_beyond_overdraft_limit() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        create_account_with_overdraft(&dao, "OVERDRAFT002", "10.00", "5.00").await;
+
+        // When
+        let result = dao.adjust_account("OVERDRAFT002".to_string(), decimal("-15.01"), gbp(), None, None, None).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(_, message)) if message == "insufficient funds"));
+        assert_eq!(read_balance(&dao, "OVERDRAFT002").await, decimal("10.00"));
+    }
+
+    #[tokio::test]
+    async fn should_update_overdraft_limit() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        create_account(&dao, "OVERDRAFT003", "0").await;
+
+        // When
+        dao.update_overdraft_limit("OVERDRAFT003".to_string(), decimal("20"), None)
+            .await.expect("could not update limit");
+
+        // Then
+        let balance = dao.adjust_account("OVERDRAFT003".to_string(), decimal("-20"), gbp(), None, None, None)
+            .await.expect("could not debit account");
+        assert_eq!(balance, decimal("-20"));
+    }
+
+    #[tokio::test]
+    async fn should_not_lower_overdraft_limit_below_balance() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        create_account_with_overdraft(&dao, "OVERDRAFT004", "0", "10").await;
+        dao.adjust_account("OVERDRAFT004".to_string(), decimal("-8"), gbp(), None, None, None)
+            .await.expect("could not debit account");
+
+        // When
+        let result = dao.update_overdraft_limit("OVERDRAFT004".to_string(), decimal("5"), None).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::OverdraftLimitBelowBalance, _))));
+        let unknown = dao.update_overdraft_limit("OVERDRAFT_UNKNOWN".to_string(), decimal("5"), None).await;
+        assert!(matches!(unknown, Err(AppError::Business(ErrorCode::AccountNotFound, _))));
+    }
+
+    #[tokio::test]
+    async fn should_not_lower_overdraft_limit_below_funds_on_hold() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        create_account_with_overdraft(&dao, "OVERDRAFT007", "0", "20").await;
+        dao.create_hold("OVERDRAFT007".to_string(), &hold("H1", "15.00", 60), None).await.expect("could not create hold");
+
+        // When
+        let result = dao.update_overdraft_limit("OVERDRAFT007".to_string(), decimal("10"), None).await;
+        let lowered = dao.update_overdraft_limit("OVERDRAFT007".to_string(), decimal("15"), None).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::OverdraftLimitBelowBalance, _))));
+        lowered.expect("could not lower overdraft limit to funds on hold");
+        let account = dao.read_account("OVERDRAFT007".to_string()).await.expect("could not read account");
+        assert_eq!(account.overdraft_limit, decimal("15"));
+    }
+
+    #[tokio::test]
+    async fn should_not_adjust_frozen_account() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        create_account(&dao, "FROZEN001", "10.00").await;
+        dao.update_status("FROZEN001".to_string(), AccountStatus::Active, AccountStatus::Frozen, None)
+            .await.expect("could not freeze account");
+
+        // When
+        let result = dao.adjust_account("FROZEN001".to_string(), decimal("-1.00"), gbp(), None, None, None).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::AccountFrozen, _))));
+        assert_eq!(read_balance(&dao, "FROZEN001").await, decimal("10.00"));
+    }
+
+    #[tokio::test]
+    async fn should_not_transfer_to_closed_account() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        create_account(&dao, "CLOSED001", "10.00").await;
+        create_account(&dao, "CLOSED002", "0").await;
+        dao.update_status("CLOSED002".to_string(), AccountStatus::Active, AccountStatus::Closed, None)
+            .await.expect("could not close account");
+
+        // When
+        let result = dao.transfer("CLOSED001".to_string(), "CLOSED002".to_string(), decimal("1.00"), gbp(), None).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::AccountClosed, _))));
+        assert_eq!(read_balance(&dao, "CLOSED001").await, decimal("10.00"));
+    }
+
+    #[tokio::test]
+    async fn should_only_close_account_with_zero_balance() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        create_account(&dao, "CLOSED003", "0.01").await;
+
+        // When
+        let result = dao.update_status("CLOSED003".to_string(), AccountStatus::Active, AccountStatus::Closed, None).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::ConcurrentUpdate, _))));
+        let account = dao.read_account("CLOSED003".to_string()).await.expect("could not read account");
+        assert_eq!(account.status, AccountStatus::Active);
+    }
+
+    #[tokio::test]
+    async fn should_not_adjust_in_another_currency() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        create_account(&dao, "CURRENCY001", "1.00").await;
+        let euros = Currency::try_from("EUR".to_string()).expect("EUR not known");
+
+        // When
+        let result = dao.adjust_account("CURRENCY001".to_string(), decimal("1.00"), euros, None, None, None).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::CurrencyMismatch, _))));
+        assert_eq!(read_balance(&dao, "CURRENCY001").await, decimal("1.00"));
+    }
+
+    #[tokio::test]
+    async fn should_read_and_adjust_account_created_before_currencies() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        dao.ddb_client.put_item()
+            .table_name(&dao.tables.accounts)
+            .item("accountId", AttributeValue::S("LEGACY001".to_string()))
+            .item("balance", AttributeValue::N("10".to_string()))
+            .send().await.expect("could not put legacy account");
+
+        // When
+        let balance = dao.adjust_account("LEGACY001".to_string(), decimal("1.50"), gbp(), None, None, None)
+            .await.expect("could not credit account");
+
+        // Then
+        let account = dao.read_account("LEGACY001".to_string()).await.expect("could not read account");
+        assert_eq!(balance, decimal("11.50"));
+        assert_eq!(account.currency, gbp());
+    }
+
+    #[tokio::test]
+    async fn should_transfer_between_accounts() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        create_account(&dao, "TRANSFER001", "10.00").await;
+        create_account(&dao, "TRANSFER002", "5.00").await;
+
+        // When
+        dao.transfer("TRANSFER001".to_string(), "TRANSFER002".to_string(), decimal("2.50"), gbp(), None)
+            .await.expect("could not transfer");
+
+        // Then
+        assert_eq!(read_balance(&dao, "TRANSFER001").await, decimal("7.50"));
+        assert_eq!(read_balance(&dao, "TRANSFER002").await, decimal("7.50"));
+    }
+
+    #[tokio::test]
+    async fn should_not_transfer_more_than_balance() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        create_account(&dao, "TRANSFER003", "1.00").await;
+        create_account(&dao, "TRANSFER004", "0").await;
+
+        // When
+        let result = dao.transfer("TRANSFER003".to_string(), "TRANSFER004".to_string(), decimal("1.01"), gbp(), None).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(_, message)) if message == "insufficient funds"));
+        assert_eq!(read_balance(&dao, "TRANSFER003").await, decimal("1.00"));
+        assert_eq!(read_balance(&dao, "TRANSFER004").await, decimal("0"));
+    }
+
+    #[tokio::test]
+    async fn should_not_transfer_more_than_balance_when_debit_is_snapshotted() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        create_account(&dao, "TRANSFER006", "0").await;
+        create_account(&dao, "TRANSFER007", "0").await;
+        for _ in 0..99 {
+            dao.adjust_account("TRANSFER006".to_string(), decimal("1"), gbp(), None, None, None)
+                .await.expect("could not credit account");
+        }
+
+        // When
+        let result = dao.transfer("TRANSFER006".to_string(), "TRANSFER007".to_string(), decimal("100"), gbp(), None).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(_, message)) if message == "insufficient funds"));
+        assert_eq!(read_balance(&dao, "TRANSFER006").await, decimal("99"));
+        assert_eq!(read_balance(&dao, "TRANSFER007").await, decimal("0"));
+    }
+
+    #[tokio::test]
+    async fn should_not_transfer_to_unknown_account() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        create_account(&dao, "TRANSFER005", "1.00").await;
+
+        // When
+        let result = dao.transfer("TRANSFER005".to_string(), "TRANSFER_UNKNOWN".to_string(), decimal("0.50"), gbp(), None).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(_, message)) if message == "unknown account"));
+        assert_eq!(read_balance(&dao, "TRANSFER005").await, decimal("1.00"));
+    }
+
+    #[tokio::test]
+    async fn should_record_adjustments_in_ledger() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        create_account(&dao, "LEDGER001", "10.00").await;
+
+        // When
+        dao.adjust_account("LEDGER001".to_string(), decimal("2.50"), gbp(), Some("wages".to_string()), None, None)
+            .await.expect("could not credit account");
+        let balance = dao.adjust_account("LEDGER001".to_string(), decimal("-1.25"), gbp(), None, None, None)
+            .await.expect("could not debit account");
+
+        // Then
+        assert_eq!(balance, decimal("11.25"));
+        let (transactions, next) = dao.list_transactions("LEDGER001".to_string(), 10, None)
+            .await.expect("could not list transactions");
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[0].amount, decimal("-1.25"));
+        assert_eq!(transactions[0].balance, decimal("11.25"));
+        assert_eq!(transactions[0].description, None);
+        assert_eq!(transactions[1].amount, decimal("2.50"));
+        assert_eq!(transactions[1].balance, decimal("12.50"));
+        assert_eq!(transactions[1].description, Some("wages".to_string()));
+        assert_eq!(next, None);
+    }
+
+    #[tokio::test]
+    async fn should_page_through_ledger_newest_first() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        create_account(&dao, "LEDGER002", "0").await;
+        for amount in ["1", "2", "3"] {
+            dao.adjust_account("LEDGER002".to_string(), decimal(amount), gbp(), None, None, None)
+                .await.expect("could not credit account");
+        }
+
+        // When
+        let (first_page, next) = dao.list_transactions("LEDGER002".to_string(), 2, None)
+            .await.expect("could not list transactions");
+        let (second_page, _) = dao.list_transactions("LEDGER002".to_string(), 2, next)
+            .await.expect("could not list transactions");
+
+        // Then
+        let amounts: Vec<BigDecimal> = first_page.iter().chain(second_page.iter())
+            .map(|transaction| transaction.amount.clone()).collect();
+        assert_eq!(amounts, vec![decimal("3"), decimal("2"), decimal("1")]);
+    }
+
+    #[tokio::test]
+    async fn should_snapshot_balance_every_hundred_entries() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        create_account(&dao, "SNAPSHOT001", "0").await;
+        for _ in 0..101 {
+            dao.adjust_account("SNAPSHOT001".to_string(), decimal("1"), gbp(), None, None, None)
+                .await.expect("could not credit account");
+        }
+
+        // When
+        let snapshot = dao.read_balance_snapshot("SNAPSHOT001", "9999").await.expect("could not read snapshot")
+            .expect("no snapshot");
+        let before = dao.read_balance_snapshot("SNAPSHOT001", "2000").await.expect("could not read snapshot");
+        let after = dao.list_transactions_after("SNAPSHOT001", snapshot.sequence, 10)
+            .await.expect("could not list transactions");
+
+        // Then
+        assert_eq!(snapshot.sequence, 100);
+        assert_eq!(snapshot.balance, decimal("100"));
+        assert!(before.is_none());
+        let sequences: Vec<u64> = after.iter().map(|transaction| transaction.sequence).collect();
+        assert_eq!(sequences, vec![101]);
+    }
+
+    #[tokio::test]
+    async fn should_not_record_rejected_adjustment_in_ledger() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        create_account(&dao, "LEDGER003", "1.00").await;
+
+        // When
+        let result = dao.adjust_account("LEDGER003".to_string(), decimal("-1.01"), gbp(), None, None, None).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(_, message)) if message == "insufficient funds"));
+        let (transactions, _) = dao.list_transactions("LEDGER003".to_string(), 10, None)
+            .await.expect("could not list transactions");
+        assert!(transactions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn should_remember_response_against_idempotency_key() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        create_account(&dao, "IDEMPOTENT001", "10.00").await;
+        let request = idempotent_request("KEY001");
+
+        // When
+        dao.adjust_account("IDEMPOTENT001".to_string(), decimal("1.00"), gbp(), None, Some(&request), None)
+            .await.expect("could not credit account");
+
+        // Then
+        let (fingerprint, response) = dao.read_idempotency_record("KEY001", request.now)
+            .await.expect("could not read record").expect("record not found");
+        assert_eq!(fingerprint, "fingerprint");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, "11");
+    }
+
+    #[tokio::test]
+    async fn should_not_adjust_twice_with_same_idempotency_key() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        create_account(&dao, "IDEMPOTENT002", "10.00").await;
+        let request = idempotent_request("KEY002");
+        dao.adjust_account("IDEMPOTENT002".to_string(), decimal("1.00"), gbp(), None, Some(&request), None)
+            .await.expect("could not credit account");
+
+        // When
+        let result = dao.adjust_account("IDEMPOTENT002".to_string(), decimal("1.00"), gbp(), None, Some(&request), None).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::IdempotencyKeyInProgress, _))));
+        assert_eq!(read_balance(&dao, "IDEMPOTENT002").await, decimal("11.00"));
+    }
+
+    #[tokio::test]
+    async fn should_remember_rejection_against_idempotency_key_once() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        let request = idempotent_request("KEY003");
+        dao.remember_response(&request, StoredResponse { status: 422, body: "rejected".to_string() })
+            .await.expect("could not remember response");
+
+        // When
+        let result = dao.remember_response(&request, StoredResponse { status: 404, body: "other".to_string() }).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::IdempotencyKeyInProgress, _))));
+        let (_, response) = dao.read_idempotency_record("KEY003", request.now)
+            .await.expect("could not read record").expect("record not found");
+        assert_eq!(response.status, 422);
+        assert_eq!(response.body, "rejected");
+    }
+
+    #[tokio::test]
+    async fn should_reduce_available_funds_when_holding() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        create_account(&dao, "HOLD001", "10.00").await;
+
+        // When
+        dao.create_hold("HOLD001".to_string(), &hold("H1", "4.00", 60), None).await.expect("could not create hold");
+        let result = dao.create_hold("HOLD001".to_string(), &hold("H2", "6.01", 60), None).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::InsufficientFunds, _))));
+        let account = dao.read_account("HOLD001".to_string()).await.expect("could not read account");
+        assert_eq!(account.balance, decimal("10.00"));
+        assert_eq!(account.held_amount, decimal("4.00"));
+    }
+
+    #[tokio::test]
+    async fn should_not_debit_funds_on_hold() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        create_account(&dao, "HOLD002", "10.00").await;
+        dao.create_hold("HOLD002".to_string(), &hold("H1", "4.00", 60), None).await.expect("could not create hold");
+
+        // When
+        let result = dao.adjust_account("HOLD002".to_string(), decimal("-6.01"), gbp(), None, None, None).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::InsufficientFunds, _))));
+        assert_eq!(read_balance(&dao, "HOLD002").await, decimal("10.00"));
+    }
+
+    #[tokio::test]
+    async fn should_capture_hold_into_ledger() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        create_account(&dao, "HOLD003", "10.00").await;
+        let hold = hold("H1", "4.00", 60);
+        dao.create_hold("HOLD003".to_string(), &hold, None).await.expect("could not create hold");
+
+        // When
+        let balance = dao.capture_hold("HOLD003".to_string(), &hold, Utc::now().timestamp(), None)
+            .await.expect("could not capture hold");
+
+        // Then
+        assert_eq!(balance, decimal("6.00"));
+        let account = dao.read_account("HOLD003".to_string()).await.expect("could not read account");
+        assert_eq!(account.held_amount, decimal("0"));
+        let (transactions, _) = dao.list_transactions("HOLD003".to_string(), 10, None)
+            .await.expect("could not list transactions");
+        assert_eq!(transactions[0].amount, decimal("-4.00"));
+        assert!(dao.read_hold("HOLD003", "H1").await.expect("could not read hold").is_none());
+        let again = dao.capture_hold("HOLD003".to_string(), &hold, Utc::now().timestamp(), None).await;
+        assert!(matches!(again, Err(AppError::Business(ErrorCode::HoldNotFound, _))));
+    }
+
+    #[tokio::test]
+    async fn should_release_hold_without_changing_balance() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        create_account(&dao, "HOLD004", "10.00").await;
+        let hold = hold("H1", "4.00", 60);
+        dao.create_hold("HOLD004".to_string(), &hold, None).await.expect("could not create hold");
+
+        // When
+        dao.release_hold("HOLD004".to_string(), &hold, None).await.expect("could not release hold");
+
+        // Then
+        let account = dao.read_account("HOLD004".to_string()).await.expect("could not read account");
+        assert_eq!(account.balance, decimal("10.00"));
+        assert_eq!(account.held_amount, decimal("0"));
+        let again = dao.release_hold("HOLD004".to_string(), &hold, None).await;
+        assert!(matches!(again, Err(AppError::Business(ErrorCode::HoldNotFound, _))));
+    }
+
+    #[tokio::test]
+    async fn should_not_capture_expired_hold() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        create_account(&dao, "HOLD005", "10.00").await;
+        let live = hold("H1", "1.00", 60);
+        let expired = hold("H2", "2.00", -60);
+        dao.create_hold("HOLD005".to_string(), &live, None).await.expect("could not create hold");
+        dao.create_hold("HOLD005".to_string(), &expired, None).await.expect("could not create hold");
+
+        // When
+        let result = dao.capture_hold("HOLD005".to_string(), &expired, Utc::now().timestamp(), None).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::HoldNotFound, _))));
+        assert_eq!(read_balance(&dao, "HOLD005").await, decimal("10.00"));
+        let found = dao.list_expired_holds("HOLD005", Utc::now().timestamp()).await.expect("could not list holds");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].hold_id, "H2");
+    }
+
+    #[tokio::test]
+    async fn should_move_on_version_with_every_change() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        create_account(&dao, "VERSION001", "10.00").await;
+        let hold = hold("H1", "1.00", 60);
+
+        // When
+        dao.adjust_account("VERSION001".to_string(), decimal("1.00"), gbp(), None, None, None)
+            .await.expect("could not adjust account");
+        dao.create_hold("VERSION001".to_string(), &hold, None).await.expect("could not create hold");
+        dao.release_hold("VERSION001".to_string(), &hold, None).await.expect("could not release hold");
+        dao.update_overdraft_limit("VERSION001".to_string(), decimal("5"), None)
+            .await.expect("could not update limit");
+        dao.update_status("VERSION001".to_string(), AccountStatus::Active, AccountStatus::Frozen, None)
+            .await.expect("could not freeze account");
+
+        // Then
+        let account = dao.read_account("VERSION001".to_string()).await.expect("could not read account");
+        assert_eq!(account.version, 6);
+    }
+
+    #[tokio::test]
+    async fn should_not_change_account_at_other_version() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        create_account(&dao, "VERSION002", "10.00").await;
+        dao.adjust_account("VERSION002".to_string(), decimal("-1.00"), gbp(), None, None, Some(1))
+            .await.expect("could not adjust account at expected version");
+
+        // When
+        let adjusted = dao.adjust_account("VERSION002".to_string(), decimal("-1.00"), gbp(), None, None, Some(1)).await;
+        let held = dao.create_hold("VERSION002".to_string(), &hold("H1", "1.00", 60), Some(1)).await;
+        let limited = dao.update_overdraft_limit("VERSION002".to_string(), decimal("5"), Some(1)).await;
+        let frozen = dao.update_status("VERSION002".to_string(), AccountStatus::Active, AccountStatus::Frozen, Some(1)).await;
+
+        // Then
+        for result in [adjusted.map(|_balance| ()), held, limited, frozen] {
+            assert!(matches!(result, Err(AppError::Business(ErrorCode::PreconditionFailed, _))));
+        }
+        let account = dao.read_account("VERSION002".to_string()).await.expect("could not read account");
+        assert_eq!(account.balance, decimal("9.00"));
+        assert_eq!(account.status, AccountStatus::Active);
+        assert_eq!(account.version, 2);
+    }
+
+    #[tokio::test]
+    async fn should_set_and_remove_holder_details() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        let holder = HolderDetails{owner_name: Some("Fred".to_string()), contact_email: None,
+            external_reference: Some("F1".to_string()), tags: ["gold".to_string()].into()};
+        let account = Account { holder, ..account("HOLDER001", "1.00") };
+        dao.create_account(account).await.expect("could not create account");
+        let patch: HolderPatch = serde_json::from_str(r#"{"contactEmail":"fred@bedrock.example","externalReference":null,"tags":[]}"#)
+            .expect("could not parse patch");
+
+        // When
+        let updated = dao.update_holder("HOLDER001".to_string(), &patch, Some(1)).await.expect("could not update holder");
+
+        // Then
+        let read = dao.read_account("HOLDER001".to_string()).await.expect("could not read account");
+        for account in [updated, read] {
+            assert_eq!(account.holder, HolderDetails{owner_name: Some("Fred".to_string()),
+                contact_email: Some("fred@bedrock.example".to_string()), external_reference: None, tags: Default::default()});
+            assert_eq!(account.version, 2);
+        }
+        let stale = dao.update_holder("HOLDER001".to_string(), &patch, Some(1)).await;
+        assert!(matches!(stale, Err(AppError::Business(ErrorCode::PreconditionFailed, _))));
+        let unknown = dao.update_holder("HOLDER_UNKNOWN".to_string(), &patch, None).await;
+        assert!(matches!(unknown, Err(AppError::Business(ErrorCode::AccountNotFound, _))));
+    }
+
+    #[tokio::test]
+    async fn should_page_through_all_accounts() {
+        // Given
+        let dao = AccountDao::new(get_dynamodb_client(), TableNames::default());
+        for account_id in ["LIST001", "LIST002", "LIST003"] {
+            create_account(&dao, account_id, "1.00").await;
+        }
+
+        // When
+        let mut listed = Vec::new();
+        let mut start_after = None;
+        loop {
+            let (accounts, next) = dao.list_accounts(2, start_after).await.expect("could not list accounts");
+            assert!(accounts.len() <= 2);
+            listed.extend(accounts.into_iter().map(|account| account.account_id));
+            start_after = next;
+            if start_after.is_none() {
+                break;
+            }
+        }
+
+        // Then
+        for account_id in ["LIST001", "LIST002", "LIST003"] {
+            assert_eq!(listed.iter().filter(|listed_id| *listed_id == account_id).count(), 1);
+        }
+    }
+
+    fn hold(hold_id: &str, amount: &str, expires_in_secs: i64) -> Hold {
+        Hold {
+            hold_id: hold_id.to_string(),
+            amount: decimal(amount),
+            currency: gbp(),
+            description: None,
+            expires_at: Utc::now() + chrono::Duration::seconds(expires_in_secs),
+        }
+    }
+
+    fn idempotent_request(key: &str) -> IdempotentRequest {
+        IdempotentRequest {
+            key: key.to_string(),
+            fingerprint: "fingerprint".to_string(),
+            now: 1_000_000,
+            expires_at: 2_000_000,
+            respond: |balance| Ok(StoredResponse { status: 200, body: balance.to_string() }),
+        }
+    }
+
+    async fn create_account(dao: &AccountDao, account_id: &str, balance: &str) {
+        create_account_with_overdraft(dao, account_id, balance, "0").await;
+    }
+
+    async fn create_account_with_overdraft(dao: &AccountDao, account_id: &str, balance: &str, overdraft_limit: &str) {
+        let account = Account { overdraft_limit: decimal(overdraft_limit), ..account(account_id, balance) };
+        dao.create_account(account).await.expect("could not create account");
+    }
+
+    fn account(account_id: &str, balance: &str) -> Account {
+        Account{account_id: account_id.to_string(), currency: gbp(), balance: decimal(balance),
+            overdraft_limit: decimal("0"), available_funds: decimal("0"), status: AccountStatus::Active,
+            held_amount: decimal("0"), holder: HolderDetails::default(), version: 0, opened_at: None}
+    }
+
+    async fn read_balance(dao: &AccountDao, account_id: &str) -> BigDecimal {
+        dao.read_account(account_id.to_string()).await.expect("could not read account").balance
+    }
+
+    fn gbp() -> Currency {
+        Currency::try_from("GBP".to_string()).expect("GBP not known")
+    }
+
+    fn decimal(number: &str) -> BigDecimal {
+        BigDecimal::from_str(number).expect("failed to parse number")
     }
 
     // These tests use DynamoDB in a docker container.
@@ -185,20 +2040,13 @@ mod test {
     // It's all terribly clunky, and should be moved into its own module.
 
     fn get_dynamodb_client() -> Client {
-        unsafe {
-            DB_INITIALISER.call_once(|| {
-                DB_CLIENT = Some(setup_dynamodb_client());
-            });
-            match &DB_CLIENT {
-                Some(client) => client.clone(),
-                None => panic!("db client not initialised")
-            }
-        }
+        DB_CLIENT.get_or_init(setup_dynamodb_client).clone()
     }
 
-    static mut DB_CLIENT: Option<Client> = Option::None;
-    static DB_INITIALISER: Once = Once::new();
+    static DB_CLIENT: OnceLock<Client> = OnceLock::new();
 
+    // The script process is deliberately left running, it stops when our end of its input closes.
+    #[allow(clippy::zombie_processes)]
     fn setup_dynamodb_client() -> Client {
         let mut dynamodb_process: Child = start_db_setup_script();
 
@@ -266,7 +2114,7 @@ mod test {
     }
 
     fn establish_input_stream(mut script_stdin: ChildStdin) {
-        script_stdin.write("Tests beginning\n".as_bytes()).expect("Failed to write script stdin");
+        script_stdin.write_all("Tests beginning\n".as_bytes()).expect("Failed to write script stdin");
         script_stdin.flush().expect("Failed to flush buffer");
     }
 }
@@ -0,0 +1,641 @@
+use async_trait::async_trait;
+use bigdecimal::{num_bigint::Sign, BigDecimal};
+use chrono::{SecondsFormat, Utc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Mutex, MutexGuard},
+};
+use uuid::Uuid;
+
+use super::{
+    Account, AccountRepository, AccountStatus, BalanceSnapshot, Currency, Hold, HolderPatch, IdempotentRequest, StoredResponse,
+    Transaction,
+};
+use crate::error::{AppError, ErrorCode};
+
+/// Keeps accounts in memory, for tests and for running without DynamoDB.
+///
+/// Every change takes the lock for its whole duration, so it happens entirely
+/// or not at all, like a DynamoDB transaction. Business rules are checked before
+/// anything is changed, and fail with the same errors as [`AccountDao`](super::AccountDao).
+#[derive(Default)]
+pub struct InMemoryAccountRepository {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    accounts: BTreeMap<String, Account>,
+    /// Each account's ledger, oldest entry first, so an entry's sequence number is its position plus one.
+    ledgers: HashMap<String, Vec<Transaction>>,
+    /// Each account's balance snapshots, oldest first.
+    snapshots: HashMap<String, Vec<BalanceSnapshot>>,
+    holds: BTreeMap<(String, String), Hold>,
+    idempotency_records: HashMap<String, IdempotencyRecord>,
+}
+
+struct IdempotencyRecord {
+    fingerprint: String,
+    response: StoredResponse,
+    expires_at: i64,
+}
+
+impl InMemoryAccountRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> Result<MutexGuard<'_, State>, AppError> {
+        self.state
+            .lock()
+            .map_err(|_err| AppError::internal("in-memory account store is poisoned"))
+    }
+}
+
+impl State {
+    fn account(&self, account_id: &str) -> Result<&Account, AppError> {
+        self.accounts.get(account_id).ok_or_else(AppError::account_not_found)
+    }
+
+    fn account_mut(&mut self, account_id: &str) -> Result<&mut Account, AppError> {
+        self.accounts.get_mut(account_id).ok_or_else(AppError::account_not_found)
+    }
+
+    /// Changes the balance and writes the ledger entry recording it, returning the new balance.
+    fn append_entry(
+        &mut self,
+        account_id: &str,
+        transaction_id: String,
+        amount: &BigDecimal,
+        description: &Option<String>,
+    ) -> Result<BigDecimal, AppError> {
+        let account = self.account_mut(account_id)?;
+        account.balance = (&account.balance + amount).normalized();
+        account.version += 1;
+        let balance = account.balance.clone();
+
+        let ledger = self.ledgers.entry(account_id.to_string()).or_default();
+        let entry = Transaction {
+            transaction_id,
+            sequence: ledger.len() as u64 + 1,
+            amount: amount.normalized(),
+            balance: balance.clone(),
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            description: description.clone(),
+        };
+        if let Some(snapshot) = BalanceSnapshot::due_with(&entry) {
+            self.snapshots.entry(account_id.to_string()).or_default().push(snapshot);
+        }
+        ledger.push(entry);
+        Ok(balance)
+    }
+
+    fn is_idempotency_key_taken(&self, key: &str, now: i64) -> bool {
+        matches!(self.idempotency_records.get(key), Some(record) if record.expires_at >= now)
+    }
+
+    fn remember_response(&mut self, request: &IdempotentRequest, response: StoredResponse) -> Result<(), AppError> {
+        if self.is_idempotency_key_taken(&request.key, request.now) {
+            return Err(AppError::business(
+                ErrorCode::IdempotencyKeyInProgress,
+                "a request with the same idempotency key is in progress",
+            ));
+        }
+        self.idempotency_records.insert(
+            request.key.clone(),
+            IdempotencyRecord {
+                fingerprint: request.fingerprint.clone(),
+                response,
+                expires_at: request.expires_at,
+            },
+        );
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl AccountRepository for InMemoryAccountRepository {
+    async fn adjust_account(
+        &self,
+        account_id: String,
+        amount: BigDecimal,
+        currency: Currency,
+        description: Option<String>,
+        idempotent_request: Option<&IdempotentRequest>,
+        expected_version: Option<u64>,
+    ) -> Result<BigDecimal, AppError> {
+        let mut state = self.state()?;
+        let account = state.account(&account_id)?;
+        check_version(account, expected_version)?;
+        account.status.check_active()?;
+        currency.check_account_currency(account.currency.code())?;
+        if amount.sign() == Sign::Minus {
+            check_available_funds(account, &amount)?;
+        }
+        if let Some(request) = idempotent_request {
+            let balance_after = &account.balance + &amount;
+            let response = (request.respond)(&balance_after.normalized())?;
+            state.remember_response(request, response)?;
+        }
+        state.append_entry(&account_id, new_transaction_id(), &amount, &description)
+    }
+
+    async fn transfer(
+        &self,
+        from_account_id: String,
+        to_account_id: String,
+        amount: BigDecimal,
+        currency: Currency,
+        description: Option<String>,
+    ) -> Result<(), AppError> {
+        let mut state = self.state()?;
+        let from = state.accounts.get(&from_account_id).ok_or_else(unknown_account)?;
+        let to = state.accounts.get(&to_account_id).ok_or_else(unknown_account)?;
+        from.status.check_active()?;
+        to.status.check_active()?;
+        currency.check_account_currency(from.currency.code())?;
+        currency.check_account_currency(to.currency.code())?;
+        let debit = -amount.clone();
+        check_available_funds(from, &debit)?;
+
+        let transaction_id = new_transaction_id();
+        state.append_entry(&from_account_id, transaction_id.clone(), &debit, &description)?;
+        state.append_entry(&to_account_id, transaction_id, &amount, &description)?;
+        Ok(())
+    }
+
+    async fn create_account(&self, account: Account) -> Result<(), AppError> {
+        let mut state = self.state()?;
+        if state.accounts.contains_key(&account.account_id) {
+            return Err(AppError::business(ErrorCode::AccountExists, "account already exists"));
+        }
+        let account = Account {
+            held_amount: BigDecimal::default(),
+            available_funds: BigDecimal::default(),
+            version: 1,
+            opened_at: Some(Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)),
+            ..account
+        };
+        state.accounts.insert(account.account_id.clone(), account);
+        Ok(())
+    }
+
+    async fn read_account(&self, account_id: String) -> Result<Account, AppError> {
+        let state = self.state()?;
+        Ok(normalized(state.account(&account_id)?))
+    }
+
+    async fn list_accounts(
+        &self,
+        limit: i32,
+        start_after: Option<String>,
+    ) -> Result<(Vec<Account>, Option<String>), AppError> {
+        let state = self.state()?;
+        let accounts: Vec<Account> = state
+            .accounts
+            .values()
+            .filter(|account| start_after.as_ref().is_none_or(|id| account.account_id > *id))
+            .take(page_size(limit))
+            .map(normalized)
+            .collect();
+        // Like DynamoDB, a full page says there may be more even when there is not.
+        let next = match accounts.last() {
+            Some(last) if accounts.len() == page_size(limit) => Some(last.account_id.clone()),
+            _ => None,
+        };
+        Ok((accounts, next))
+    }
+
+    async fn update_overdraft_limit(
+        &self,
+        account_id: String,
+        overdraft_limit: BigDecimal,
+        expected_version: Option<u64>,
+    ) -> Result<(), AppError> {
+        let mut state = self.state()?;
+        let account = state.account_mut(&account_id)?;
+        check_version(account, expected_version)?;
+        // The available funds, which take off what is on hold, can not be left below zero.
+        if &account.balance + &overdraft_limit - &account.held_amount < BigDecimal::default() {
+            return Err(AppError::business(
+                ErrorCode::OverdraftLimitBelowBalance,
+                "balance less funds on hold is below the new overdraft limit",
+            ));
+        }
+        account.overdraft_limit = overdraft_limit;
+        account.version += 1;
+        Ok(())
+    }
+
+    async fn update_status(
+        &self,
+        account_id: String,
+        from: AccountStatus,
+        to: AccountStatus,
+        expected_version: Option<u64>,
+    ) -> Result<(), AppError> {
+        let mut state = self.state()?;
+        if expected_version.is_some() {
+            check_version(state.account(&account_id)?, expected_version)?;
+        }
+        let account = state
+            .accounts
+            .get_mut(&account_id)
+            .filter(|account| account.status == from)
+            .filter(|account| {
+                to != AccountStatus::Closed
+                    || (account.balance == BigDecimal::default()
+                        && account.held_amount == BigDecimal::default())
+            })
+            .ok_or_else(|| AppError::business(ErrorCode::ConcurrentUpdate, "account changed while updating its status"))?;
+        account.status = to;
+        account.version += 1;
+        Ok(())
+    }
+
+    async fn update_holder(
+        &self,
+        account_id: String,
+        patch: &HolderPatch,
+        expected_version: Option<u64>,
+    ) -> Result<Account, AppError> {
+        let mut state = self.state()?;
+        let account = state.account_mut(&account_id)?;
+        check_version(account, expected_version)?;
+        account.holder.apply(patch);
+        account.version += 1;
+        Ok(normalized(account))
+    }
+
+    async fn create_hold(
+        &self,
+        account_id: String,
+        hold: &Hold,
+        expected_version: Option<u64>,
+    ) -> Result<(), AppError> {
+        let mut state = self.state()?;
+        let account = state.account(&account_id)?;
+        check_version(account, expected_version)?;
+        account.status.check_active()?;
+        hold.currency.check_account_currency(account.currency.code())?;
+        check_available_funds(account, &-hold.amount.clone())?;
+        let key = (account_id.clone(), hold.hold_id.clone());
+        if state.holds.contains_key(&key) {
+            return Err(AppError::internal("hold already exists"));
+        }
+
+        let account = state.account_mut(&account_id)?;
+        account.held_amount = &account.held_amount + &hold.amount;
+        account.version += 1;
+        state.holds.insert(key, hold.clone());
+        Ok(())
+    }
+
+    async fn capture_hold(
+        &self,
+        account_id: String,
+        hold: &Hold,
+        now: i64,
+        expected_version: Option<u64>,
+    ) -> Result<BigDecimal, AppError> {
+        let mut state = self.state()?;
+        let account = state.account(&account_id)?;
+        check_version(account, expected_version)?;
+        account.status.check_active()?;
+        let key = (account_id.clone(), hold.hold_id.clone());
+        match state.holds.get(&key) {
+            Some(held) if held.expires_at.timestamp() > now => {}
+            _ => return Err(AppError::hold_not_found()),
+        }
+
+        state.holds.remove(&key);
+        let account = state.account_mut(&account_id)?;
+        account.held_amount = &account.held_amount - &hold.amount;
+        state.append_entry(&account_id, new_transaction_id(), &-hold.amount.clone(), &hold.description)
+    }
+
+    async fn release_hold(
+        &self,
+        account_id: String,
+        hold: &Hold,
+        expected_version: Option<u64>,
+    ) -> Result<(), AppError> {
+        let mut state = self.state()?;
+        check_version(state.account(&account_id)?, expected_version)?;
+        state
+            .holds
+            .remove(&(account_id.clone(), hold.hold_id.clone()))
+            .ok_or_else(AppError::hold_not_found)?;
+        let account = state.account_mut(&account_id)?;
+        account.held_amount = &account.held_amount - &hold.amount;
+        account.version += 1;
+        Ok(())
+    }
+
+    async fn read_hold(&self, account_id: &str, hold_id: &str) -> Result<Option<Hold>, AppError> {
+        let state = self.state()?;
+        Ok(state
+            .holds
+            .get(&(account_id.to_string(), hold_id.to_string()))
+            .cloned())
+    }
+
+    async fn list_expired_holds(&self, account_id: &str, now: i64) -> Result<Vec<Hold>, AppError> {
+        let state = self.state()?;
+        Ok(state
+            .holds
+            .iter()
+            .filter(|((held_on, _), hold)| held_on == account_id && hold.expires_at.timestamp() <= now)
+            .map(|(_, hold)| hold.clone())
+            .collect())
+    }
+
+    async fn list_transactions(
+        &self,
+        account_id: String,
+        limit: i32,
+        start_after: Option<u64>,
+    ) -> Result<(Vec<Transaction>, Option<u64>), AppError> {
+        let state = self.state()?;
+        let transactions: Vec<Transaction> = state
+            .ledgers
+            .get(&account_id)
+            .map(|ledger| ledger.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .rev()
+            .filter(|entry| start_after.is_none_or(|sequence| entry.sequence < sequence))
+            .take(page_size(limit))
+            .cloned()
+            .collect();
+        let next = match transactions.last() {
+            Some(last) if transactions.len() == page_size(limit) => Some(last.sequence),
+            _ => None,
+        };
+        Ok((transactions, next))
+    }
+
+    async fn list_transactions_after(
+        &self,
+        account_id: &str,
+        sequence: u64,
+        limit: i32,
+    ) -> Result<Vec<Transaction>, AppError> {
+        let state = self.state()?;
+        Ok(state
+            .ledgers
+            .get(account_id)
+            .map(|ledger| ledger.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .skip(sequence as usize)
+            .take(page_size(limit))
+            .cloned()
+            .collect())
+    }
+
+    async fn read_balance_snapshot(
+        &self,
+        account_id: &str,
+        at_or_before: &str,
+    ) -> Result<Option<BalanceSnapshot>, AppError> {
+        let state = self.state()?;
+        Ok(state
+            .snapshots
+            .get(account_id)
+            .and_then(|snapshots| snapshots.iter().rev().find(|snapshot| snapshot.timestamp.as_str() <= at_or_before))
+            .cloned())
+    }
+
+    async fn read_idempotency_record(
+        &self,
+        key: &str,
+        now: i64,
+    ) -> Result<Option<(String, StoredResponse)>, AppError> {
+        let state = self.state()?;
+        Ok(match state.idempotency_records.get(key) {
+            Some(record) if record.expires_at > now => {
+                Some((record.fingerprint.clone(), record.response.clone()))
+            }
+            _ => None,
+        })
+    }
+
+    async fn remember_response(&self, request: &IdempotentRequest, response: StoredResponse) -> Result<(), AppError> {
+        self.state()?.remember_response(request, response)
+    }
+}
+
+/// The balance may go down to minus the overdraft limit, less any funds on hold, but no further.
+fn check_available_funds(account: &Account, amount: &BigDecimal) -> Result<(), AppError> {
+    if &account.balance + amount + &account.overdraft_limit - &account.held_amount < BigDecimal::default() {
+        return Err(AppError::business(ErrorCode::InsufficientFunds, "insufficient funds"));
+    }
+    Ok(())
+}
+
+fn check_version(account: &Account, expected_version: Option<u64>) -> Result<(), AppError> {
+    match expected_version {
+        Some(version) if version != account.version => Err(AppError::precondition_failed()),
+        _ => Ok(()),
+    }
+}
+
+/// Accounts are read back as DynamoDB would return them, without their available funds.
+fn normalized(account: &Account) -> Account {
+    Account {
+        balance: account.balance.normalized(),
+        overdraft_limit: account.overdraft_limit.normalized(),
+        held_amount: account.held_amount.normalized(),
+        available_funds: BigDecimal::default(),
+        ..account.clone()
+    }
+}
+
+fn page_size(limit: i32) -> usize {
+    usize::try_from(limit).unwrap_or_default()
+}
+
+fn new_transaction_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+fn unknown_account() -> AppError {
+    AppError::business(ErrorCode::UnknownAccount, "unknown account")
+}
+
+#[cfg(test)]
+mod test {
+    use super::InMemoryAccountRepository;
+    use crate::account::{Account, AccountRepository, AccountStatus, Currency, Hold, HolderDetails};
+    use crate::error::{AppError, ErrorCode};
+    use bigdecimal::BigDecimal;
+    use chrono::{Duration, Utc};
+    use std::{convert::TryFrom, str::FromStr};
+
+    #[tokio::test]
+    async fn should_not_overwrite_existing_account() {
+        // Given
+        let repository = InMemoryAccountRepository::new();
+        create_account(&repository, "fred", "10.10", "0").await;
+
+        // When
+        let result = repository.create_account(account("fred", "0")).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::AccountExists, _))));
+        assert_eq!(read_balance(&repository, "fred").await, decimal("10.1"));
+    }
+
+    #[tokio::test]
+    async fn should_not_adjust_unknown_account() {
+        // Given
+        let repository = InMemoryAccountRepository::new();
+
+        // When
+        let result = repository.adjust_account("nobody".to_string(), decimal("1"), gbp(), None, None, None).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::AccountNotFound, _))));
+    }
+
+    #[tokio::test]
+    async fn should_not_debit_beyond_overdraft_limit_less_holds() {
+        // Given
+        let repository = InMemoryAccountRepository::new();
+        create_account(&repository, "fred", "10.00", "5.00").await;
+        repository.create_hold("fred".to_string(), &hold("H1", "3.00", 60), None).await.expect("could not create hold");
+
+        // When
+        let too_much = repository.adjust_account("fred".to_string(), decimal("-12.01"), gbp(), None, None, None).await;
+        let balance = repository.adjust_account("fred".to_string(), decimal("-12.00"), gbp(), None, None, None).await;
+
+        // Then
+        assert!(matches!(too_much, Err(AppError::Business(ErrorCode::InsufficientFunds, _))));
+        assert_eq!(balance.expect("could not debit"), decimal("-2"));
+    }
+
+    #[tokio::test]
+    async fn should_not_lower_overdraft_limit_below_funds_on_hold() {
+        // Given
+        let repository = InMemoryAccountRepository::new();
+        create_account(&repository, "fred", "0", "20.00").await;
+        repository.create_hold("fred".to_string(), &hold("H1", "15.00", 60), None).await.expect("could not create hold");
+
+        // When
+        let result = repository.update_overdraft_limit("fred".to_string(), decimal("10"), None).await;
+        let lowered = repository.update_overdraft_limit("fred".to_string(), decimal("15"), None).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::OverdraftLimitBelowBalance, _))));
+        lowered.expect("could not lower overdraft limit to funds on hold");
+        let account = repository.read_account("fred".to_string()).await.expect("could not read account");
+        assert_eq!(account.overdraft_limit, decimal("15"));
+    }
+
+    #[tokio::test]
+    async fn should_not_transfer_from_frozen_account() {
+        // Given
+        let repository = InMemoryAccountRepository::new();
+        create_account(&repository, "fred", "10.00", "0").await;
+        create_account(&repository, "wilma", "0", "0").await;
+        repository.update_status("fred".to_string(), AccountStatus::Active, AccountStatus::Frozen, None)
+            .await.expect("could not freeze account");
+
+        // When
+        let result = repository.transfer("fred".to_string(), "wilma".to_string(), decimal("1"), gbp(), None).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::AccountFrozen, _))));
+        assert_eq!(read_balance(&repository, "wilma").await, decimal("0"));
+    }
+
+    #[tokio::test]
+    async fn should_page_through_ledger_newest_first() {
+        // Given
+        let repository = InMemoryAccountRepository::new();
+        create_account(&repository, "fred", "0", "0").await;
+        for amount in ["1", "2", "3"] {
+            repository.adjust_account("fred".to_string(), decimal(amount), gbp(), None, None, None)
+                .await.expect("could not adjust account");
+        }
+
+        // When
+        let (first, next) = repository.list_transactions("fred".to_string(), 2, None)
+            .await.expect("could not list transactions");
+        let (second, last) = repository.list_transactions("fred".to_string(), 2, next)
+            .await.expect("could not list transactions");
+
+        // Then
+        let amounts: Vec<String> = first.iter().chain(second.iter()).map(|entry| entry.amount.to_string()).collect();
+        assert_eq!(amounts, ["3", "2", "1"]);
+        assert_eq!(last, None);
+    }
+
+    #[tokio::test]
+    async fn should_not_capture_expired_hold() {
+        // Given
+        let repository = InMemoryAccountRepository::new();
+        create_account(&repository, "fred", "10.00", "0").await;
+        let expired = hold("H1", "2.00", -60);
+        repository.create_hold("fred".to_string(), &expired, None).await.expect("could not create hold");
+
+        // When
+        let result = repository.capture_hold("fred".to_string(), &expired, Utc::now().timestamp(), None).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::HoldNotFound, _))));
+        assert_eq!(read_balance(&repository, "fred").await, decimal("10"));
+    }
+
+    #[tokio::test]
+    async fn should_not_change_account_at_other_version() {
+        // Given
+        let repository = InMemoryAccountRepository::new();
+        create_account(&repository, "fred", "10.00", "0").await;
+        repository.adjust_account("fred".to_string(), decimal("1"), gbp(), None, None, Some(1))
+            .await.expect("could not adjust account at expected version");
+
+        // When
+        let result = repository.update_overdraft_limit("fred".to_string(), decimal("5"), Some(1)).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::PreconditionFailed, _))));
+        let account = repository.read_account("fred".to_string()).await.expect("could not read account");
+        assert_eq!(account.overdraft_limit, decimal("0"));
+        assert_eq!(account.version, 2);
+    }
+
+    async fn create_account(repository: &InMemoryAccountRepository, account_id: &str, balance: &str, overdraft_limit: &str) {
+        let account = Account { overdraft_limit: decimal(overdraft_limit), ..account(account_id, balance) };
+        repository.create_account(account).await.expect("could not create account");
+    }
+
+    async fn read_balance(repository: &InMemoryAccountRepository, account_id: &str) -> BigDecimal {
+        repository.read_account(account_id.to_string()).await.expect("could not read account").balance
+    }
+
+    fn account(account_id: &str, balance: &str) -> Account {
+        Account{account_id: account_id.to_string(), currency: gbp(), balance: decimal(balance),
+            overdraft_limit: decimal("0"), available_funds: decimal("0"), status: AccountStatus::Active,
+            held_amount: decimal("0"), holder: HolderDetails::default(), version: 0, opened_at: None}
+    }
+
+    fn hold(hold_id: &str, amount: &str, expires_in_secs: i64) -> Hold {
+        Hold {
+            hold_id: hold_id.to_string(),
+            amount: decimal(amount),
+            currency: gbp(),
+            description: None,
+            expires_at: Utc::now() + Duration::seconds(expires_in_secs),
+        }
+    }
+
+    fn gbp() -> Currency {
+        Currency::try_from("GBP".to_string()).expect("GBP not known")
+    }
+
+    fn decimal(number: &str) -> BigDecimal {
+        BigDecimal::from_str(number).expect("failed to parse number")
+    }
+}
@@ -1,5 +1,28 @@
+mod account_id;
+pub use account_id::AccountIds;
+
+mod currency;
+pub use currency::Currency;
+
+mod repository;
+pub use repository::AccountRepository;
+
 mod dao;
 pub use dao::AccountDao;
 
+mod memory;
+pub use memory::InMemoryAccountRepository;
+
+mod page_token;
+pub use page_token::{PageKind, PageTokens};
+
+mod status;
+pub use status::AccountStatus;
+
+mod validation;
+
 mod service;
-pub use service::{AccountService, Account, Adjustment, Balance};
+pub use service::{
+    AccountService, Account, Adjustment, BalanceSnapshot, Hold, HolderDetails, HolderPatch, IdempotentRequest, NewHold,
+    OverdraftLimit, StoredResponse, Transaction, Transfer,
+};
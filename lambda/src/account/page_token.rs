@@ -0,0 +1,114 @@
+use ring::hmac;
+
+use crate::error::AppError;
+
+/// What is being paged through. It is signed along with the position, so that a token
+/// from one listing is not taken as a position in another.
+#[derive(Debug, Clone, Copy)]
+pub enum PageKind<'a> {
+    Accounts,
+    /// The transactions of one account, as a sequence number means nothing in another's.
+    Transactions(&'a str),
+}
+
+impl PageKind<'_> {
+    fn label(&self) -> String {
+        match self {
+            PageKind::Accounts => "accounts".to_string(),
+            PageKind::Transactions(account_id) => format!("transactions:{}", account_id),
+        }
+    }
+}
+
+/// Turns the position a page ended at into an opaque token, and back again.
+///
+/// The position is signed, so a client can hand the token back for the next page
+/// but can not alter it to start somewhere it was not given.
+pub struct PageTokens {
+    key: hmac::Key,
+}
+
+impl PageTokens {
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+        }
+    }
+
+    pub fn encode(&self, kind: PageKind, position: &str) -> String {
+        let tag = hmac::sign(&self.key, &signed_input(kind, position.as_bytes()));
+        format!(
+            "{}.{}",
+            base64::encode_config(position, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(tag.as_ref(), base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    pub fn decode(&self, kind: PageKind, token: &str) -> Result<String, AppError> {
+        let (position, tag) = token.split_once('.').ok_or_else(invalid_token)?;
+        let position = base64::decode_config(position, base64::URL_SAFE_NO_PAD)
+            .map_err(|_err| invalid_token())?;
+        let tag = base64::decode_config(tag, base64::URL_SAFE_NO_PAD).map_err(|_err| invalid_token())?;
+        hmac::verify(&self.key, &signed_input(kind, &position), &tag).map_err(|_err| invalid_token())?;
+        String::from_utf8(position).map_err(|_err| invalid_token())
+    }
+}
+
+/// Account ids can not have a colon in them, so the kind and position can not run into each other.
+fn signed_input(kind: PageKind, position: &[u8]) -> Vec<u8> {
+    [kind.label().as_bytes(), b":", position].concat()
+}
+
+fn invalid_token() -> AppError {
+    AppError::invalid_parameter("invalid next token")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PageKind, PageTokens};
+
+    #[test]
+    fn should_decode_token_it_encoded() {
+        let tokens = PageTokens::new(b"secret");
+        let token = tokens.encode(PageKind::Accounts, "fred");
+        assert_eq!(tokens.decode(PageKind::Accounts, &token).expect("could not decode"), "fred");
+    }
+
+    #[test]
+    fn should_reject_altered_token() {
+        let tokens = PageTokens::new(b"secret");
+        let token = tokens.encode(PageKind::Accounts, "fred");
+        let (_, tag) = token.split_once('.').expect("no tag");
+        let altered = format!("{}.{}", base64::encode_config("wilma", base64::URL_SAFE_NO_PAD), tag);
+        assert!(tokens.decode(PageKind::Accounts, &altered).is_err());
+    }
+
+    #[test]
+    fn should_reject_token_signed_with_another_secret() {
+        let token = PageTokens::new(b"other").encode(PageKind::Accounts, "fred");
+        assert!(PageTokens::new(b"secret").decode(PageKind::Accounts, &token).is_err());
+    }
+
+    #[test]
+    fn should_reject_token_for_another_listing() {
+        let tokens = PageTokens::new(b"secret");
+        let token = tokens.encode(PageKind::Transactions("fred"), "100");
+        assert!(tokens.decode(PageKind::Accounts, &token).is_err());
+        assert_eq!(tokens.decode(PageKind::Transactions("fred"), &token).expect("could not decode"), "100");
+    }
+
+    #[test]
+    fn should_reject_token_for_transactions_of_another_account() {
+        let tokens = PageTokens::new(b"secret");
+        let token = tokens.encode(PageKind::Transactions("fred"), "100");
+        assert!(tokens.decode(PageKind::Transactions("wilma"), &token).is_err());
+    }
+
+    #[test]
+    fn should_reject_malformed_token() {
+        let tokens = PageTokens::new(b"secret");
+        assert!(tokens.decode(PageKind::Accounts, "").is_err());
+        assert!(tokens.decode(PageKind::Accounts, "not a token").is_err());
+        assert!(tokens.decode(PageKind::Accounts, "ZnJlZA.!!!").is_err());
+    }
+}
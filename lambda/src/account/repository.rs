@@ -0,0 +1,154 @@
+use async_trait::async_trait;
+use bigdecimal::BigDecimal;
+
+use super::{
+    Account, AccountStatus, BalanceSnapshot, Currency, Hold, HolderPatch, IdempotentRequest, StoredResponse, Transaction,
+};
+use crate::error::AppError;
+
+/// Where accounts, their ledgers, holds and idempotency records are kept.
+///
+/// Implemented by [`AccountDao`](super::AccountDao) against DynamoDB, and by
+/// [`InMemoryAccountRepository`](super::InMemoryAccountRepository) for running without it.
+/// Both must fail in the same way: an unknown account is not found, and a change
+/// which would break a business rule is rejected without any part of it happening.
+///
+/// Every change to an account moves it on to its next version. Changes made on behalf of
+/// a client can be given the version it expects, and then fail with a precondition
+/// failure, without any part of them happening, when the account is at another.
+#[async_trait]
+pub trait AccountRepository: Send + Sync {
+    /// Changes the balance by the amount, writing a ledger entry, and returns the new balance.
+    /// A debit can not take the available funds below zero.
+    /// When an idempotent request is given, its response is remembered in the same change.
+    async fn adjust_account(
+        &self,
+        account_id: String,
+        amount: BigDecimal,
+        currency: Currency,
+        description: Option<String>,
+        idempotent_request: Option<&IdempotentRequest>,
+        expected_version: Option<u64>,
+    ) -> Result<BigDecimal, AppError>;
+
+    /// Moves money between two accounts, so that either both the debit and credit
+    /// happen or neither does. Both ledger entries share the same transaction id.
+    async fn transfer(
+        &self,
+        from_account_id: String,
+        to_account_id: String,
+        amount: BigDecimal,
+        currency: Currency,
+        description: Option<String>,
+    ) -> Result<(), AppError>;
+
+    /// Fails with a conflict if the account already exists. Keeps when it was opened.
+    async fn create_account(&self, account: Account) -> Result<(), AppError>;
+
+    async fn read_account(&self, account_id: String) -> Result<Account, AppError>;
+
+    /// Reads a page of accounts. Returns the accounts and, when there may be more,
+    /// the account id to start after.
+    async fn list_accounts(
+        &self,
+        limit: i32,
+        start_after: Option<String>,
+    ) -> Result<(Vec<Account>, Option<String>), AppError>;
+
+    /// Sets how far the balance may go below zero. The limit can not be lowered
+    /// below what is already overdrawn.
+    async fn update_overdraft_limit(
+        &self,
+        account_id: String,
+        overdraft_limit: BigDecimal,
+        expected_version: Option<u64>,
+    ) -> Result<(), AppError>;
+
+    /// Moves an account from one status to another, on condition it has not changed
+    /// since the caller checked the move was allowed. Closing also needs a zero balance
+    /// and nothing on hold.
+    async fn update_status(
+        &self,
+        account_id: String,
+        from: AccountStatus,
+        to: AccountStatus,
+        expected_version: Option<u64>,
+    ) -> Result<(), AppError>;
+
+    /// Applies the patch to the holder details, and returns the account as it then is.
+    async fn update_holder(
+        &self,
+        account_id: String,
+        patch: &HolderPatch,
+        expected_version: Option<u64>,
+    ) -> Result<Account, AppError>;
+
+    /// Reserves the amount of the hold, on condition the available funds cover it.
+    async fn create_hold(
+        &self,
+        account_id: String,
+        hold: &Hold,
+        expected_version: Option<u64>,
+    ) -> Result<(), AppError>;
+
+    /// Takes the money held from the balance, writing a ledger entry for it, and
+    /// returns the new balance. A hold which has gone or expired is not found.
+    async fn capture_hold(
+        &self,
+        account_id: String,
+        hold: &Hold,
+        now: i64,
+        expected_version: Option<u64>,
+    ) -> Result<BigDecimal, AppError>;
+
+    /// Removes the hold and gives its amount back to the account's available funds.
+    async fn release_hold(
+        &self,
+        account_id: String,
+        hold: &Hold,
+        expected_version: Option<u64>,
+    ) -> Result<(), AppError>;
+
+    async fn read_hold(&self, account_id: &str, hold_id: &str) -> Result<Option<Hold>, AppError>;
+
+    /// Lists the holds on an account which expired at or before the given time.
+    async fn list_expired_holds(&self, account_id: &str, now: i64) -> Result<Vec<Hold>, AppError>;
+
+    /// Reads a page of an account's ledger, newest entry first. Returns the entries
+    /// and, when there may be more, the sequence number to start after.
+    async fn list_transactions(
+        &self,
+        account_id: String,
+        limit: i32,
+        start_after: Option<u64>,
+    ) -> Result<(Vec<Transaction>, Option<u64>), AppError>;
+
+    /// Reads a page of an account's ledger, oldest entry first, starting after the given sequence number.
+    async fn list_transactions_after(
+        &self,
+        account_id: &str,
+        sequence: u64,
+        limit: i32,
+    ) -> Result<Vec<Transaction>, AppError>;
+
+    /// Reads the newest balance snapshot of an account taken at or before the given time,
+    /// which is in the same form as the timestamps of ledger entries. Every change which
+    /// writes a ledger entry also writes the snapshot due with it, in the same change.
+    async fn read_balance_snapshot(
+        &self,
+        account_id: &str,
+        at_or_before: &str,
+    ) -> Result<Option<BalanceSnapshot>, AppError>;
+
+    /// Reads the response remembered against an idempotency key, along with the
+    /// fingerprint of the request which produced it. Expired records are ignored.
+    async fn read_idempotency_record(
+        &self,
+        key: &str,
+        now: i64,
+    ) -> Result<Option<(String, StoredResponse)>, AppError>;
+
+    /// Remembers the response to a request which was rejected without changing anything,
+    /// unless a live record is already there for the key.
+    async fn remember_response(&self, request: &IdempotentRequest, response: StoredResponse) -> Result<(), AppError>;
+}
@@ -1,19 +1,206 @@
-use serde::{Deserialize, Serialize};
-use bigdecimal::BigDecimal;
-use crate::error::AppError;
-use super::AccountDao;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use bigdecimal::{num_bigint::Sign, BigDecimal, ToPrimitive};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use http::StatusCode;
+use std::collections::{BTreeMap, BTreeSet};
+use crate::config::Limits;
+use crate::error::{AppError, ErrorCode, FieldError};
+use crate::metrics::{self, Unit};
+use crate::xray;
+use uuid::Uuid;
+use super::validation::{self, Validate, Validator};
+use super::{AccountIds, AccountRepository, AccountStatus, Currency, PageKind, PageTokens};
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Account {
+    /// Generated when a new account is given without one.
+    #[serde(default)]
     pub(super) account_id: String,
+    pub(super) currency: Currency,
     pub(super) balance: BigDecimal,
+    /// How far the balance may go below zero.
+    #[serde(default)]
+    pub(super) overdraft_limit: BigDecimal,
+    /// What can be spent, including the overdraft but not what is on hold. Only ever reported.
+    #[serde(skip_deserializing)]
+    pub(super) available_funds: BigDecimal,
+    /// New accounts are always active.
+    #[serde(skip_deserializing)]
+    pub(super) status: AccountStatus,
+    /// The total of the holds on the account, which is still part of the balance.
+    #[serde(skip_deserializing)]
+    pub(super) held_amount: BigDecimal,
+    #[serde(flatten)]
+    pub(super) holder: HolderDetails,
+    /// Goes up by one with every change to the account, starting from 1 when it is created.
+    /// Given to clients as the ETag rather than in the payload.
+    #[serde(skip)]
+    pub(super) version: u64,
+    /// When it was opened, in the same form as the timestamps of ledger entries.
+    /// Not known for accounts opened before it was kept.
+    #[serde(skip)]
+    pub(super) opened_at: Option<String>,
+}
+
+impl Account {
+    pub fn account_id(&self) -> &str {
+        &self.account_id
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+}
+
+/// Who holds an account and how it is known elsewhere, kept for the people reading it
+/// rather than used by anything here. Every field is optional.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HolderDetails {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) owner_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) contact_email: Option<String>,
+    /// The id of the account in another system.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) external_reference: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub(super) tags: BTreeSet<String>,
+}
+
+/// A JSON Merge Patch (RFC 7396) of an account's holder details. A field left out is
+/// unchanged, one which is null is removed and any other value replaces it, so the
+/// tags are replaced as a whole. Nothing else about an account can be changed this way.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HolderPatch {
+    #[serde(default, deserialize_with = "present")]
+    pub(super) owner_name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub(super) contact_email: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub(super) external_reference: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub(super) tags: Option<Option<BTreeSet<String>>>,
+    /// Anything else in the patch, which is rejected rather than ignored.
+    #[serde(flatten)]
+    others: BTreeMap<String, Value>,
+}
+
+/// Tells a field set to null, which is `Some(None)`, from one left out, which is `None` by default.
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Adjustment {
     amount: BigDecimal,
+    currency: Currency,
+    description: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Transfer {
+    from_account_id: String,
+    to_account_id: String,
+    amount: BigDecimal,
+    currency: Currency,
+    description: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OverdraftLimit {
+    overdraft_limit: BigDecimal,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewHold {
+    amount: BigDecimal,
+    currency: Currency,
+    description: Option<String>,
+}
+
+impl Validate for Account {
+    fn validate(&self) -> Result<(), AppError> {
+        Validator::new()
+            .check("/accountId", validation::account_id(&self.account_id))
+            .check("/balance", validation::not_negative(&self.balance))
+            .check("/balance", validation::within_max_amount(&self.balance))
+            .check("/balance", self.currency.check_decimal_places(&self.balance))
+            .check("/overdraftLimit", validation::not_negative(&self.overdraft_limit))
+            .check("/overdraftLimit", validation::within_max_amount(&self.overdraft_limit))
+            .check("/overdraftLimit", self.currency.check_decimal_places(&self.overdraft_limit))
+            .check("/ownerName", validation::owner_name(&self.holder.owner_name))
+            .check("/contactEmail", validation::contact_email(&self.holder.contact_email))
+            .check("/externalReference", validation::external_reference(&self.holder.external_reference))
+            .check("/tags", validation::tags(&self.holder.tags))
+            .finish()
+    }
+}
+
+impl Validate for HolderPatch {
+    fn validate(&self) -> Result<(), AppError> {
+        let mut validator = Validator::new()
+            .check("/ownerName", validation::owner_name(&self.owner_name.clone().flatten()))
+            .check("/contactEmail", validation::contact_email(&self.contact_email.clone().flatten()))
+            .check("/externalReference", validation::external_reference(&self.external_reference.clone().flatten()))
+            .check("/tags", validation::tags(&self.tags.clone().flatten().unwrap_or_default()));
+        for name in self.others.keys() {
+            validator = validator.check(&validation::pointer_to(name), validation::not_patchable(name));
+        }
+        validator.finish()
+    }
+}
+
+impl HolderDetails {
+    pub(super) fn apply(&mut self, patch: &HolderPatch) {
+        if let Some(owner_name) = &patch.owner_name {
+            self.owner_name = owner_name.clone();
+        }
+        if let Some(contact_email) = &patch.contact_email {
+            self.contact_email = contact_email.clone();
+        }
+        if let Some(external_reference) = &patch.external_reference {
+            self.external_reference = external_reference.clone();
+        }
+        if let Some(tags) = &patch.tags {
+            self.tags = tags.clone().unwrap_or_default();
+        }
+    }
+}
+
+impl Validate for Adjustment {
+    fn validate(&self) -> Result<(), AppError> {
+        Validator::new()
+            .check("/amount", validation::not_zero(&self.amount))
+            .check("/amount", validation::within_max_amount(&self.amount))
+            .check("/amount", self.currency.check_decimal_places(&self.amount))
+            .check("/description", validation::description(&self.description))
+            .finish()
+    }
+}
+
+/// Money reserved on an account, which can no longer be spent but has not yet left it.
+/// Until it expires, it can be captured, to take the money, or released.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Hold {
+    pub(super) hold_id: String,
+    pub(super) amount: BigDecimal,
+    pub(super) currency: Currency,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) description: Option<String>,
+    pub(super) expires_at: DateTime<Utc>,
 }
 
 #[derive(Serialize)]
@@ -22,30 +209,1035 @@ pub struct Balance {
     balance: BigDecimal,
 }
 
+/// An immutable entry in an account's ledger, recording a change to its balance.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Transaction {
+    pub(super) transaction_id: String,
+    #[serde(skip)]
+    pub(super) sequence: u64,
+    pub(super) amount: BigDecimal,
+    /// The balance after the change.
+    pub(super) balance: BigDecimal,
+    pub(super) timestamp: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) description: Option<String>,
+}
+
+/// How many ledger entries there are from one balance snapshot to the next.
+const SNAPSHOT_INTERVAL: u64 = 100;
+
+/// The balance after a ledger entry, kept every [`SNAPSHOT_INTERVAL`] entries so that a past balance
+/// can be worked out from the entries since the snapshot before it, rather than from the whole ledger.
+#[derive(Debug, Clone)]
+pub struct BalanceSnapshot {
+    pub(super) sequence: u64,
+    pub(super) balance: BigDecimal,
+    /// That of the entry, so snapshots and entries can be compared by when they were made.
+    pub(super) timestamp: String,
+}
+
+impl BalanceSnapshot {
+    /// The snapshot to keep with the entry, written along with it, when one is due.
+    pub(super) fn due_with(entry: &Transaction) -> Option<BalanceSnapshot> {
+        entry.sequence.is_multiple_of(SNAPSHOT_INTERVAL).then(|| BalanceSnapshot {
+            sequence: entry.sequence,
+            balance: entry.balance.clone(),
+            timestamp: entry.timestamp.clone(),
+        })
+    }
+}
+
+/// What the balance of an account was at a time in the past.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BalanceAsOf {
+    account_id: String,
+    currency: Currency,
+    balance: BigDecimal,
+    as_of: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountPage {
+    accounts: Vec<Account>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_token: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransactionPage {
+    transactions: Vec<Transaction>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_token: Option<String>,
+}
+
+/// A response remembered against an idempotency key, so that a retried request gets the same answer.
+#[derive(Debug, Clone)]
+pub struct StoredResponse {
+    pub status: u16,
+    pub body: String,
+}
+
+/// A request which is only to take effect once, however many times it is sent.
+pub struct IdempotentRequest {
+    pub(super) key: String,
+    /// Identifies the content of the request, so a different request reusing the key can be rejected.
+    pub(super) fingerprint: String,
+    pub(super) now: i64,
+    pub(super) expires_at: i64,
+    /// Produces the response to remember from the resulting balance.
+    pub(super) respond: fn(&BigDecimal) -> Result<StoredResponse, AppError>,
+}
+
+/// How long a response is remembered against its idempotency key.
+const IDEMPOTENCY_KEY_LIFETIME_SECS: i64 = 24 * 60 * 60;
+
 pub struct AccountService {
-    account_dao: AccountDao,
+    account_repository: Box<dyn AccountRepository>,
+    limits: Limits,
+    page_tokens: PageTokens,
+    account_ids: AccountIds,
 }
 
-// Not a lot of business logic here - could probably have just put the DAO code here.
+// Not a lot of business logic here - could probably have just put the repository code here.
 
 impl AccountService {
 
-    pub fn new(account_dao: AccountDao) -> Self {
-        Self { account_dao }
+    pub fn new(
+        account_repository: Box<dyn AccountRepository>,
+        limits: Limits,
+        page_tokens: PageTokens,
+        account_ids: AccountIds,
+    ) -> Self {
+        Self {
+            account_repository,
+            limits,
+            page_tokens,
+            account_ids,
+        }
     }
 
-    pub async fn adjust_balance(&self, account_id: String, adjustment: Adjustment) -> Result<Balance, AppError> {
-        let balance = self.account_dao.adjust_account(account_id, adjustment.amount).await?;
-        Ok(Balance{ balance })
+    /// Changes made for a client take the version of the account it expects, from If-Match,
+    /// and fail if the account has moved on from it.
+    pub async fn adjust_balance(
+        &self,
+        account_id: String,
+        adjustment: Adjustment,
+        expected_version: Option<u64>,
+    ) -> Result<Balance, AppError> {
+        xray::subsegment("AccountService::adjust_balance", async {
+            adjustment.validate()?;
+            self.release_expired_holds(&account_id).await?;
+            let balance = self
+                .account_repository
+                .adjust_account(
+                    account_id,
+                    adjustment.amount.clone(),
+                    adjustment.currency,
+                    adjustment.description,
+                    None,
+                    expected_version,
+                )
+                .await?;
+            record_movement(&adjustment.amount, adjustment.currency);
+            Ok(Balance{ balance })
+        })
+        .await
     }
 
-    pub async fn create_account(&self, account: Account) -> Result<(), AppError> {
-        self.account_dao.create_account(account).await?;
-        Ok(())
+    /// Adjusts the balance only if no other request has used the idempotency key.
+    /// When one has, the response it got is returned again.
+    pub async fn adjust_balance_once(
+        &self,
+        account_id: String,
+        adjustment: Adjustment,
+        idempotency_key: String,
+        expected_version: Option<u64>,
+    ) -> Result<StoredResponse, AppError> {
+        xray::subsegment("AccountService::adjust_balance_once", async {
+            adjustment.validate()?;
+            let fingerprint = serde_json::to_string(&(
+                &account_id,
+                adjustment.amount.normalized().to_string(),
+                adjustment.currency.code(),
+                &adjustment.description,
+            ))?;
+            let now = Utc::now().timestamp();
+            self.release_expired_holds(&account_id).await?;
+
+            if let Some(response) = self.remembered_response(&idempotency_key, &fingerprint, now).await? {
+                return Ok(response);
+            }
+
+            let request = IdempotentRequest {
+                key: idempotency_key,
+                fingerprint,
+                now,
+                expires_at: now + IDEMPOTENCY_KEY_LIFETIME_SECS,
+                respond: balance_response,
+            };
+            let result = self
+                .account_repository
+                .adjust_account(
+                    account_id,
+                    adjustment.amount.clone(),
+                    adjustment.currency,
+                    adjustment.description,
+                    Some(&request),
+                    expected_version,
+                )
+                .await;
+            match result {
+                Ok(balance) => {
+                    record_movement(&adjustment.amount, adjustment.currency);
+                    balance_response(&balance)
+                }
+                Err(AppError::Business(ErrorCode::IdempotencyKeyInProgress, _)) => self.replay_winner(&request).await,
+                Err(AppError::Business(code, message)) if is_final(code) => {
+                    match self.account_repository.remember_response(&request, problem_response(code, &message)?).await {
+                        Ok(()) => Err(AppError::Business(code, message)),
+                        Err(AppError::Business(ErrorCode::IdempotencyKeyInProgress, _)) => {
+                            self.replay_winner(&request).await
+                        }
+                        Err(err) => Err(err),
+                    }
+                }
+                Err(err) => Err(err),
+            }
+        })
+        .await
+    }
+
+    /// The response remembered against the key, as long as it was given to the same request.
+    /// A rejection is returned as the error it was.
+    async fn remembered_response(
+        &self,
+        idempotency_key: &str,
+        fingerprint: &str,
+        now: i64,
+    ) -> Result<Option<StoredResponse>, AppError> {
+        match self.account_repository.read_idempotency_record(idempotency_key, now).await? {
+            Some((stored_fingerprint, response)) if stored_fingerprint == fingerprint => replay(response).map(Some),
+            Some(_) => Err(AppError::business(
+                ErrorCode::IdempotencyKeyReused,
+                "idempotency key has already been used for a different request",
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Another request with the same key got in first, so the response it got is the one to give.
+    async fn replay_winner(&self, request: &IdempotentRequest) -> Result<StoredResponse, AppError> {
+        self.remembered_response(&request.key, &request.fingerprint, request.now)
+            .await?
+            .ok_or_else(|| {
+                AppError::business(
+                    ErrorCode::IdempotencyKeyInProgress,
+                    "a request with the same idempotency key is in progress",
+                )
+            })
+    }
+
+    pub async fn transfer(&self, transfer: Transfer) -> Result<(), AppError> {
+        xray::subsegment("AccountService::transfer", async {
+            if transfer.amount.sign() != Sign::Plus {
+                return Err(AppError::business(ErrorCode::InvalidAmount, "transfer amount must be positive"));
+            }
+            if transfer.from_account_id == transfer.to_account_id {
+                return Err(AppError::business(ErrorCode::SameAccountTransfer, "cannot transfer to the same account"));
+            }
+            transfer.currency.check_scale(&transfer.amount)?;
+            self.release_expired_holds(&transfer.from_account_id).await?;
+            self.account_repository
+                .transfer(
+                    transfer.from_account_id,
+                    transfer.to_account_id,
+                    transfer.amount.clone(),
+                    transfer.currency,
+                    transfer.description,
+                )
+                .await?;
+            record_movement(&-transfer.amount.clone(), transfer.currency);
+            record_movement(&transfer.amount, transfer.currency);
+            Ok(())
+        })
+        .await
+    }
+
+    /// Creates an account, with a generated id when it has none, returning the account as created.
+    pub async fn create_account(&self, mut account: Account) -> Result<Account, AppError> {
+        xray::subsegment("AccountService::create_account", async {
+            if account.account_id.is_empty() {
+                account.account_id = self.account_ids.generate()?;
+            } else if self.account_ids.is_generated(&account.account_id) {
+                return Err(AppError::Validation(vec![FieldError {
+                    pointer: "/accountId".to_string(),
+                    detail: "account id has the form of a generated one, leave it out to have one generated".to_string(),
+                }]));
+            }
+            account.validate()?;
+            self.account_repository.create_account(account.clone()).await?;
+            Ok(with_available_funds(Account { version: 1, ..account }))
+        })
+        .await
+    }
+
+    /// Changes the holder details of an account, returning the account as it then is.
+    pub async fn update_holder(
+        &self,
+        account_id: String,
+        patch: HolderPatch,
+        expected_version: Option<u64>,
+    ) -> Result<Account, AppError> {
+        xray::subsegment("AccountService::update_holder", async {
+            patch.validate()?;
+            self.release_expired_holds(&account_id).await?;
+            let account = self
+                .account_repository
+                .update_holder(account_id, &patch, expected_version)
+                .await?;
+            Ok(with_available_funds(account))
+        })
+        .await
     }
 
     pub async fn read_account(&self, account_id: String) -> Result<Account, AppError> {
-        let account = self.account_dao.read_account(account_id).await?;
-        Ok(account)
+        xray::subsegment("AccountService::read_account", async {
+            self.release_expired_holds(&account_id).await?;
+            let account = self.account_repository.read_account(account_id).await?;
+            Ok(with_available_funds(account))
+        })
+        .await
+    }
+
+    /// Works out what the balance was at a time in the past, from the newest snapshot taken by
+    /// then and the ledger entries after it up to that time. Before the first entry, it is the
+    /// balance the account was opened with, and before the account was opened there is none.
+    pub async fn read_balance_as_of(&self, account_id: String, as_of: DateTime<Utc>) -> Result<BalanceAsOf, AppError> {
+        xray::subsegment("AccountService::read_balance_as_of", async {
+            if as_of > Utc::now() {
+                return Err(AppError::invalid_parameter("asOf can not be in the future"));
+            }
+            let account = self.account_repository.read_account(account_id.clone()).await?;
+            // Ledger timestamps are all written in this form, so sort in the order they were made.
+            let until = as_of.to_rfc3339_opts(SecondsFormat::Millis, true);
+            if account.opened_at.as_ref().is_some_and(|opened_at| until < *opened_at) {
+                return Err(not_opened_by_then());
+            }
+
+            let snapshot = self.account_repository.read_balance_snapshot(&account_id, &until).await?;
+            let (mut sequence, mut balance) = match snapshot {
+                Some(snapshot) => (snapshot.sequence, Some(snapshot.balance)),
+                None => (0, None),
+            };
+            let limit = self.limits.max_page_size;
+            let opening_balance = 'replay: loop {
+                let entries = self.account_repository.list_transactions_after(&account_id, sequence, limit).await?;
+                let is_last_page = entries.len() < limit as usize;
+                for entry in entries {
+                    if entry.timestamp > until {
+                        if balance.is_none() && account.opened_at.is_none() {
+                            // When it was opened is not known, so nothing before its first entry is.
+                            return Err(not_opened_by_then());
+                        }
+                        break 'replay Some(&entry.balance - &entry.amount);
+                    }
+                    balance = Some(match balance {
+                        Some(balance) => balance + &entry.amount,
+                        None => entry.balance,
+                    });
+                    sequence = entry.sequence;
+                }
+                if is_last_page {
+                    // Without any entry, the balance has not changed since the account was opened.
+                    break None;
+                }
+            };
+
+            Ok(BalanceAsOf {
+                account_id,
+                currency: account.currency,
+                balance: balance.or(opening_balance).unwrap_or(account.balance).normalized(),
+                as_of,
+            })
+        })
+        .await
+    }
+
+    /// Lists accounts a page at a time, in no particular order.
+    /// The token from one page is passed back to get the next.
+    /// Expired holds are not released here, so may still count against the available funds listed
+    /// until the account itself is read or changed.
+    pub async fn list_accounts(
+        &self,
+        limit: Option<i32>,
+        next_token: Option<String>,
+    ) -> Result<AccountPage, AppError> {
+        xray::subsegment("AccountService::list_accounts", async {
+            let limit = self.page_size(limit)?;
+            let start_after = next_token
+                .map(|token| self.page_tokens.decode(PageKind::Accounts, &token))
+                .transpose()?;
+
+            let (accounts, next) = self.account_repository.list_accounts(limit, start_after).await?;
+
+            Ok(AccountPage {
+                accounts: accounts.into_iter().map(with_available_funds).collect(),
+                next_token: next.map(|account_id| self.page_tokens.encode(PageKind::Accounts, &account_id)),
+            })
+        })
+        .await
+    }
+
+    /// Reserves money on an account, reducing its available funds but not its balance.
+    pub async fn create_hold(
+        &self,
+        account_id: String,
+        new_hold: NewHold,
+        expected_version: Option<u64>,
+    ) -> Result<Hold, AppError> {
+        xray::subsegment("AccountService::create_hold", async {
+            if new_hold.amount.sign() != Sign::Plus {
+                return Err(AppError::business(ErrorCode::InvalidAmount, "hold amount must be positive"));
+            }
+            new_hold.currency.check_scale(&new_hold.amount)?;
+            self.release_expired_holds(&account_id).await?;
+
+            let hold = Hold {
+                hold_id: Uuid::new_v4().to_string(),
+                amount: new_hold.amount.normalized(),
+                currency: new_hold.currency,
+                description: new_hold.description,
+                expires_at: Utc::now() + Duration::seconds(self.limits.hold_lifetime_secs),
+            };
+            self.account_repository.create_hold(account_id, &hold, expected_version).await?;
+            Ok(hold)
+        })
+        .await
+    }
+
+    /// Takes the money held, recording it in the ledger like any other debit.
+    pub async fn capture_hold(
+        &self,
+        account_id: String,
+        hold_id: String,
+        expected_version: Option<u64>,
+    ) -> Result<Balance, AppError> {
+        xray::subsegment("AccountService::capture_hold", async {
+            let hold = self.read_live_hold(&account_id, &hold_id).await?;
+            let balance = self
+                .account_repository
+                .capture_hold(account_id, &hold, Utc::now().timestamp(), expected_version)
+                .await?;
+            record_movement(&-hold.amount.clone(), hold.currency);
+            Ok(Balance { balance })
+        })
+        .await
+    }
+
+    /// Gives the money held back to the account's available funds.
+    pub async fn release_hold(
+        &self,
+        account_id: String,
+        hold_id: String,
+        expected_version: Option<u64>,
+    ) -> Result<(), AppError> {
+        xray::subsegment("AccountService::release_hold", async {
+            let hold = self.read_live_hold(&account_id, &hold_id).await?;
+            self.account_repository.release_hold(account_id, &hold, expected_version).await
+        })
+        .await
+    }
+
+    /// An expired hold is treated as already released, even if it has not been yet.
+    async fn read_live_hold(&self, account_id: &str, hold_id: &str) -> Result<Hold, AppError> {
+        self.release_expired_holds(account_id).await?;
+        match self.account_repository.read_hold(account_id, hold_id).await? {
+            Some(hold) if hold.expires_at > Utc::now() => Ok(hold),
+            _ => Err(AppError::hold_not_found()),
+        }
+    }
+
+    /// Holds expire by being released the next time the account is used,
+    /// so that they never count against its available funds afterwards.
+    async fn release_expired_holds(&self, account_id: &str) -> Result<(), AppError> {
+        let expired = self
+            .account_repository
+            .list_expired_holds(account_id, Utc::now().timestamp())
+            .await?;
+        for hold in expired {
+            match self.account_repository.release_hold(account_id.to_string(), &hold, None).await {
+                // Something else got to it first.
+                Err(AppError::Business(ErrorCode::HoldNotFound, _)) => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn freeze(&self, account_id: String, expected_version: Option<u64>) -> Result<(), AppError> {
+        xray::subsegment("AccountService::freeze", async {
+            self.change_status(account_id, AccountStatus::Frozen, expected_version).await
+        })
+        .await
+    }
+
+    pub async fn unfreeze(&self, account_id: String, expected_version: Option<u64>) -> Result<(), AppError> {
+        xray::subsegment("AccountService::unfreeze", async {
+            self.change_status(account_id, AccountStatus::Active, expected_version).await
+        })
+        .await
+    }
+
+    /// Closing is permanent, and only allowed once the balance is zero.
+    pub async fn close(&self, account_id: String, expected_version: Option<u64>) -> Result<(), AppError> {
+        xray::subsegment("AccountService::close", async {
+            self.change_status(account_id, AccountStatus::Closed, expected_version).await
+        })
+        .await
+    }
+
+    async fn change_status(
+        &self,
+        account_id: String,
+        to: AccountStatus,
+        expected_version: Option<u64>,
+    ) -> Result<(), AppError> {
+        let account = self.account_repository.read_account(account_id.clone()).await?;
+        account.status.check_transition(to)?;
+        if to == AccountStatus::Closed && account.balance != BigDecimal::default() {
+            return Err(AppError::business(ErrorCode::AccountNotEmpty, "account balance must be zero to close"));
+        }
+        if to == AccountStatus::Closed && account.held_amount != BigDecimal::default() {
+            return Err(AppError::business(ErrorCode::AccountNotEmpty, "account can not be closed with funds on hold"));
+        }
+        self.account_repository
+            .update_status(account_id, account.status, to, expected_version)
+            .await
+    }
+
+    pub async fn set_overdraft_limit(
+        &self,
+        account_id: String,
+        limit: OverdraftLimit,
+        expected_version: Option<u64>,
+    ) -> Result<(), AppError> {
+        xray::subsegment("AccountService::set_overdraft_limit", async {
+            let account = self.account_repository.read_account(account_id.clone()).await?;
+            check_overdraft_limit(account.currency, &limit.overdraft_limit)?;
+            self.account_repository
+                .update_overdraft_limit(account_id, limit.overdraft_limit, expected_version)
+                .await
+        })
+        .await
+    }
+
+    /// Lists an account's transactions, newest first.
+    /// The token from one page is passed back to get the next.
+    pub async fn list_transactions(
+        &self,
+        account_id: String,
+        limit: Option<i32>,
+        next_token: Option<String>,
+    ) -> Result<TransactionPage, AppError> {
+        xray::subsegment("AccountService::list_transactions", async {
+            let limit = self.page_size(limit)?;
+            let start_after = next_token
+                .map(|token| self.decode_sequence_token(&account_id, &token))
+                .transpose()?;
+
+            let (transactions, next) = self
+                .account_repository
+                .list_transactions(account_id.clone(), limit, start_after)
+                .await?;
+            if transactions.is_empty() && start_after.is_none() {
+                // Distinguish an account with no transactions from an unknown account.
+                self.account_repository.read_account(account_id.clone()).await?;
+            }
+
+            Ok(TransactionPage {
+                transactions,
+                next_token: next.map(|sequence| self.page_tokens.encode(PageKind::Transactions(&account_id), &sequence.to_string())),
+            })
+        })
+        .await
+    }
+
+    /// The number of items in a page, when the client may not have said.
+    fn page_size(&self, limit: Option<i32>) -> Result<i32, AppError> {
+        let limit = limit.unwrap_or(self.limits.default_page_size);
+        if !(1..=self.limits.max_page_size).contains(&limit) {
+            return Err(AppError::business_s(ErrorCode::InvalidParameter, format!(
+                "limit must be between 1 and {}",
+                self.limits.max_page_size
+            )));
+        }
+        Ok(limit)
+    }
+
+    fn decode_sequence_token(&self, account_id: &str, token: &str) -> Result<u64, AppError> {
+        self.page_tokens
+            .decode(PageKind::Transactions(account_id), token)?
+            .parse()
+            .map_err(|_err| AppError::invalid_parameter("invalid next token"))
+    }
+}
+
+/// What can be spent is worked out rather than stored.
+fn with_available_funds(mut account: Account) -> Account {
+    account.available_funds = &account.balance + &account.overdraft_limit - &account.held_amount;
+    account
+}
+
+fn check_overdraft_limit(currency: Currency, overdraft_limit: &BigDecimal) -> Result<(), AppError> {
+    if overdraft_limit.sign() == Sign::Minus {
+        return Err(AppError::business(ErrorCode::InvalidAmount, "overdraft limit can not be negative"));
+    }
+    currency.check_scale(overdraft_limit)
+}
+
+fn not_opened_by_then() -> AppError {
+    AppError::invalid_parameter("asOf is before the account was opened")
+}
+
+fn balance_response(balance: &BigDecimal) -> Result<StoredResponse, AppError> {
+    Ok(StoredResponse {
+        status: StatusCode::OK.as_u16(),
+        body: serde_json::to_string(&Balance { balance: balance.clone() })?,
+    })
+}
+
+/// A business error remembered against an idempotency key, kept as its code and message so
+/// that it is described to the client the same way as when it was first returned.
+#[derive(Serialize, Deserialize)]
+struct StoredProblem {
+    code: ErrorCode,
+    message: String,
+}
+
+fn problem_response(code: ErrorCode, message: &str) -> Result<StoredResponse, AppError> {
+    Ok(StoredResponse {
+        status: code.status().as_u16(),
+        body: serde_json::to_string(&StoredProblem { code, message: message.to_string() })?,
+    })
+}
+
+fn replay(response: StoredResponse) -> Result<StoredResponse, AppError> {
+    if StatusCode::from_u16(response.status).is_ok_and(|status| status.is_success()) {
+        return Ok(response);
+    }
+    let problem: StoredProblem = serde_json::from_str(&response.body)?;
+    Err(AppError::Business(problem.code, problem.message))
+}
+
+/// A request turned down by a business rule has had its answer, which a retry with the same key gets
+/// again, even if the account has changed since. Conflicts with other requests can still be retried,
+/// as can a failed If-Match, which is not part of the fingerprint.
+fn is_final(code: ErrorCode) -> bool {
+    !matches!(
+        code,
+        ErrorCode::IdempotencyKeyInProgress | ErrorCode::ConcurrentUpdate | ErrorCode::PreconditionFailed
+    )
+}
+
+/// Records the money moved into or out of an account, by currency, as amounts in different
+/// currencies can not be added up.
+fn record_movement(amount: &BigDecimal, currency: Currency) {
+    let name = if amount.sign() == Sign::Minus { "Debited" } else { "Credited" };
+    let amount = amount.abs().to_f64().unwrap_or_default();
+    metrics::put_with(vec![("Currency", currency.code().to_string())], name, Unit::None, amount);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AccountIds, AccountService, Adjustment, HolderDetails, HolderPatch, NewHold, OverdraftLimit};
+    use crate::account::{Account, AccountStatus, Currency, InMemoryAccountRepository, PageTokens};
+    use crate::config::Limits;
+    use crate::error::{AppError, ErrorCode};
+    use bigdecimal::BigDecimal;
+    use chrono::{DateTime, Duration, Utc};
+    use std::{convert::TryFrom, str::FromStr};
+
+    #[tokio::test]
+    async fn should_not_count_holds_in_available_funds() {
+        // Given
+        let service = account_service(60);
+        create_account(&service, "fred", "10.00").await;
+
+        // When
+        service.create_hold("fred".to_string(), new_hold("4.00"), None).await.expect("could not create hold");
+
+        // Then
+        let account = service.read_account("fred".to_string()).await.expect("could not read account");
+        assert_eq!(account.balance, decimal("10"));
+        assert_eq!(account.available_funds, decimal("6"));
+    }
+
+    #[tokio::test]
+    async fn should_release_expired_holds() {
+        // Given
+        let service = account_service(0);
+        create_account(&service, "fred", "10.00").await;
+        let hold = service.create_hold("fred".to_string(), new_hold("4.00"), None).await.expect("could not create hold");
+
+        // When
+        let account = service.read_account("fred".to_string()).await.expect("could not read account");
+
+        // Then
+        assert_eq!(account.available_funds, decimal("10"));
+        let result = service.capture_hold("fred".to_string(), hold.hold_id, None).await;
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::HoldNotFound, _))));
+    }
+
+    #[tokio::test]
+    async fn should_replay_response_to_repeated_idempotency_key() {
+        // Given
+        let service = account_service(60);
+        create_account(&service, "fred", "10.00").await;
+        service.adjust_balance_once("fred".to_string(), adjustment("-1.00"), "KEY1".to_string(), None)
+            .await.expect("could not adjust balance");
+
+        // When
+        let replayed = service.adjust_balance_once("fred".to_string(), adjustment("-1.00"), "KEY1".to_string(), None)
+            .await.expect("could not replay adjustment");
+        let reused = service.adjust_balance_once("fred".to_string(), adjustment("-2.00"), "KEY1".to_string(), None).await;
+
+        // Then
+        assert_eq!(replayed.body, "{\"balance\":\"9\"}");
+        assert!(matches!(reused, Err(AppError::Business(ErrorCode::IdempotencyKeyReused, _))));
+        let account = service.read_account("fred".to_string()).await.expect("could not read account");
+        assert_eq!(account.balance, decimal("9"));
+    }
+
+    #[tokio::test]
+    async fn should_replay_rejection_to_repeated_idempotency_key() {
+        // Given
+        let service = account_service(60);
+        create_account(&service, "fred", "1.00").await;
+        let rejected = service.adjust_balance_once("fred".to_string(), adjustment("-2.00"), "KEY2".to_string(), None).await;
+        service.adjust_balance("fred".to_string(), adjustment("5.00"), None).await.expect("could not adjust balance");
+
+        // When
+        let replayed = service.adjust_balance_once("fred".to_string(), adjustment("-2.00"), "KEY2".to_string(), None).await;
+
+        // Then
+        assert!(matches!(rejected, Err(AppError::Business(ErrorCode::InsufficientFunds, _))));
+        assert!(matches!(replayed, Err(AppError::Business(ErrorCode::InsufficientFunds, message)) if message == "insufficient funds"));
+        let account = service.read_account("fred".to_string()).await.expect("could not read account");
+        assert_eq!(account.balance, decimal("6"));
+    }
+
+    #[tokio::test]
+    async fn should_page_through_accounts_with_next_token() {
+        // Given
+        let service = account_service(60);
+        for account_id in ["barney", "betty", "fred"] {
+            create_account(&service, account_id, "1.00").await;
+        }
+
+        // When
+        let first = service.list_accounts(Some(2), None).await.expect("could not list accounts");
+        let second = service.list_accounts(Some(2), first.next_token.clone()).await.expect("could not list accounts");
+
+        // Then
+        let ids: Vec<&str> = first.accounts.iter().chain(second.accounts.iter())
+            .map(|account| account.account_id.as_str()).collect();
+        assert_eq!(ids, ["barney", "betty", "fred"]);
+        assert!(second.next_token.is_none());
+    }
+
+    #[tokio::test]
+    async fn should_list_accounts_with_available_funds() {
+        // Given
+        let service = account_service(60);
+        create_account(&service, "fred", "10.00").await;
+        service.create_hold("fred".to_string(), new_hold("4.00"), None).await.expect("could not create hold");
+
+        // When
+        let page = service.list_accounts(None, None).await.expect("could not list accounts");
+
+        // Then
+        assert_eq!(page.accounts[0].held_amount, decimal("4"));
+        assert_eq!(page.accounts[0].available_funds, decimal("6"));
+    }
+
+    #[tokio::test]
+    async fn should_not_page_through_transactions_with_token_for_another_account() {
+        // Given
+        let service = account_service(60);
+        for account_id in ["fred", "wilma"] {
+            create_account(&service, account_id, "1.00").await;
+            for amount in ["1.00", "2.00"] {
+                service.adjust_balance(account_id.to_string(), adjustment(amount), None).await.expect("could not adjust balance");
+            }
+        }
+        let page = service.list_transactions("fred".to_string(), Some(1), None).await.expect("could not list transactions");
+
+        // When
+        let result = service.list_transactions("wilma".to_string(), Some(1), page.next_token).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::InvalidParameter, _))));
+    }
+
+    #[tokio::test]
+    async fn should_not_page_through_accounts_with_transaction_token() {
+        // Given
+        let service = account_service(60);
+        create_account(&service, "fred", "1.00").await;
+        for amount in ["1.00", "2.00"] {
+            service.adjust_balance("fred".to_string(), adjustment(amount), None).await.expect("could not adjust balance");
+        }
+        let page = service.list_transactions("fred".to_string(), Some(1), None).await.expect("could not list transactions");
+
+        // When
+        let result = service.list_accounts(Some(1), page.next_token).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::InvalidParameter, _))));
+    }
+
+    #[tokio::test]
+    async fn should_read_balance_as_it_was() {
+        // Given
+        let service = account_service(60);
+        create_account(&service, "fred", "10.00").await;
+        let opened = moment().await;
+        service.adjust_balance("fred".to_string(), adjustment("5.00"), None).await.expect("could not credit");
+        let credited = moment().await;
+        service.adjust_balance("fred".to_string(), adjustment("-2.50"), None).await.expect("could not debit");
+
+        // When
+        let at_opening = service.read_balance_as_of("fred".to_string(), opened).await.expect("could not read balance");
+        let after_credit = service.read_balance_as_of("fred".to_string(), credited).await.expect("could not read balance");
+        let now = service.read_balance_as_of("fred".to_string(), moment().await).await.expect("could not read balance");
+
+        // Then
+        assert_eq!(at_opening.balance, decimal("10"));
+        assert_eq!(after_credit.balance, decimal("15"));
+        assert_eq!(now.balance, decimal("12.5"));
+    }
+
+    #[tokio::test]
+    async fn should_read_balance_from_snapshot_and_entries_since() {
+        // Given
+        let service = account_service(60);
+        create_account(&service, "fred", "0").await;
+        for _ in 0..150 {
+            service.adjust_balance("fred".to_string(), adjustment("1.00"), None).await.expect("could not credit");
+        }
+        let as_of = moment().await;
+        service.adjust_balance("fred".to_string(), adjustment("1.00"), None).await.expect("could not credit");
+
+        // When
+        let result = service.read_balance_as_of("fred".to_string(), as_of).await.expect("could not read balance");
+
+        // Then
+        assert_eq!(result.balance, decimal("150"));
+    }
+
+    #[tokio::test]
+    async fn should_not_read_balance_before_account_opened() {
+        // Given
+        let service = account_service(60);
+        let before = moment().await;
+        create_account(&service, "fred", "10.00").await;
+        service.adjust_balance("fred".to_string(), adjustment("5.00"), None).await.expect("could not credit");
+
+        // When
+        let result = service.read_balance_as_of("fred".to_string(), before).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::InvalidParameter, message))
+            if message == "asOf is before the account was opened"));
+    }
+
+    #[tokio::test]
+    async fn should_not_read_balance_in_future() {
+        // Given
+        let service = account_service(60);
+        create_account(&service, "fred", "10.00").await;
+
+        // When
+        let result = service.read_balance_as_of("fred".to_string(), Utc::now() + Duration::hours(1)).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::InvalidParameter, _))));
+    }
+
+    #[tokio::test]
+    async fn should_reject_page_size_over_maximum() {
+        let result = account_service(60).list_accounts(Some(101), None).await;
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::InvalidParameter, _))));
+    }
+
+    #[tokio::test]
+    async fn should_not_close_account_with_funds_on_hold() {
+        // Given
+        let service = account_service(60);
+        create_account(&service, "fred", "0").await;
+        service.set_overdraft_limit("fred".to_string(), OverdraftLimit { overdraft_limit: decimal("10") }, None)
+            .await.expect("could not set overdraft limit");
+        service.create_hold("fred".to_string(), new_hold("5.00"), None).await.expect("could not create hold");
+
+        // When
+        let result = service.close("fred".to_string(), None).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::AccountNotEmpty, message))
+            if message.contains("on hold")));
+    }
+
+    #[tokio::test]
+    async fn should_report_every_invalid_value_of_new_account() {
+        // Given
+        let service = account_service(60);
+        let account = account("fred flintstone", "-1.001");
+
+        // When
+        let result = service.create_account(account).await;
+
+        // Then
+        let pointers: Vec<String> = match result {
+            Err(AppError::Validation(errors)) => errors.into_iter().map(|error| error.pointer).collect(),
+            _ => vec![],
+        };
+        assert_eq!(pointers, ["/accountId", "/balance", "/balance"]);
+        assert!(matches!(service.read_account("fred flintstone".to_string()).await,
+            Err(AppError::Business(ErrorCode::AccountNotFound, _))));
+    }
+
+    #[tokio::test]
+    async fn should_generate_account_id_when_not_given() {
+        // Given
+        let service = account_service(60);
+        let account: Account = serde_json::from_str(r#"{"currency":"GBP","balance":"10.00"}"#).expect("could not parse account");
+
+        // When
+        let created = service.create_account(account).await.expect("could not create account");
+
+        // Then
+        assert!(created.account_id.starts_with("acc_"));
+        assert_eq!(created.version, 1);
+        assert_eq!(created.available_funds, decimal("10"));
+        let account = service.read_account(created.account_id).await.expect("could not read account");
+        assert_eq!(account.balance, decimal("10"));
+    }
+
+    #[tokio::test]
+    async fn should_not_let_client_choose_generated_account_id() {
+        // Given
+        let service = account_service(60);
+        let account_id = AccountIds::new("acc_").generate().expect("could not generate account id");
+
+        // When
+        let account: Account = serde_json::from_str(&format!(r#"{{"accountId":"{}","currency":"GBP","balance":"0"}}"#, account_id))
+            .expect("could not parse account");
+        let result = service.create_account(account).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Validation(errors)) if errors[0].pointer == "/accountId"));
+    }
+
+    #[tokio::test]
+    async fn should_not_adjust_by_zero() {
+        // Given
+        let service = account_service(60);
+        create_account(&service, "fred", "10.00").await;
+
+        // When
+        let result = service.adjust_balance("fred".to_string(), adjustment("0.00"), None).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Validation(errors)) if errors[0].pointer == "/amount"));
+    }
+
+    #[tokio::test]
+    async fn should_merge_patch_into_holder_details() {
+        // Given
+        let service = account_service(60);
+        create_account(&service, "fred", "10.00").await;
+        service.update_holder("fred".to_string(), patch(r#"{"ownerName":"Fred","externalReference":"F1","tags":["gold"]}"#), None)
+            .await.expect("could not update holder");
+
+        // When
+        let account = service.update_holder("fred".to_string(), patch(r#"{"externalReference":null,"contactEmail":"fred@bedrock.example"}"#), None)
+            .await.expect("could not update holder");
+
+        // Then
+        assert_eq!(account.holder, HolderDetails {
+            owner_name: Some("Fred".to_string()),
+            contact_email: Some("fred@bedrock.example".to_string()),
+            external_reference: None,
+            tags: ["gold".to_string()].into(),
+        });
+        assert_eq!(account.balance, decimal("10"));
+        assert_eq!(account.version, 3);
+    }
+
+    #[tokio::test]
+    async fn should_not_patch_balance() {
+        // Given
+        let service = account_service(60);
+        create_account(&service, "fred", "10.00").await;
+
+        // When
+        let result = service.update_holder("fred".to_string(), patch(r#"{"ownerName":"Fred","balance":1000}"#), None).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Validation(errors)) if errors[0].pointer == "/balance"));
+        let account = service.read_account("fred".to_string()).await.expect("could not read account");
+        assert_eq!(account.balance, decimal("10"));
+        assert_eq!(account.holder, HolderDetails::default());
+    }
+
+    fn account_service(hold_lifetime_secs: i64) -> AccountService {
+        AccountService::new(
+            Box::new(InMemoryAccountRepository::new()),
+            Limits { hold_lifetime_secs, ..Limits::default() },
+            PageTokens::new(b"secret"),
+            AccountIds::new("acc_"),
+        )
+    }
+
+    async fn create_account(service: &AccountService, account_id: &str, balance: &str) {
+        service.create_account(account(account_id, balance)).await.expect("could not create account");
+    }
+
+    fn account(account_id: &str, balance: &str) -> Account {
+        Account{account_id: account_id.to_string(), currency: gbp(), balance: decimal(balance),
+            overdraft_limit: decimal("0"), available_funds: decimal("0"), status: AccountStatus::Active,
+            held_amount: decimal("0"), holder: HolderDetails::default(), version: 0, opened_at: None}
+    }
+
+    /// A time apart from the ledger entries made before and after it, which are timestamped to the millisecond.
+    async fn moment() -> DateTime<Utc> {
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        let moment = Utc::now();
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        moment
+    }
+
+    fn patch(json: &str) -> HolderPatch {
+        serde_json::from_str(json).expect("could not parse patch")
+    }
+
+    fn adjustment(amount: &str) -> Adjustment {
+        Adjustment { amount: decimal(amount), currency: gbp(), description: None }
+    }
+
+    fn new_hold(amount: &str) -> NewHold {
+        NewHold { amount: decimal(amount), currency: gbp(), description: None }
+    }
+
+    fn gbp() -> Currency {
+        Currency::try_from("GBP".to_string()).expect("GBP not known")
+    }
+
+    fn decimal(number: &str) -> BigDecimal {
+        BigDecimal::from_str(number).expect("failed to parse number")
     }
 }
@@ -0,0 +1,111 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::error::{AppError, ErrorCode};
+
+/// Where an account is in its lifecycle. Only an active account can have its balance changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum AccountStatus {
+    #[default]
+    Active,
+    /// Stopped, perhaps because it has been compromised, but can be unfrozen.
+    Frozen,
+    /// Permanently stopped.
+    Closed,
+}
+
+impl AccountStatus {
+    /// The value stored in DynamoDB.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AccountStatus::Active => "ACTIVE",
+            AccountStatus::Frozen => "FROZEN",
+            AccountStatus::Closed => "CLOSED",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Result<AccountStatus, AppError> {
+        match code {
+            "ACTIVE" => Ok(AccountStatus::Active),
+            "FROZEN" => Ok(AccountStatus::Frozen),
+            "CLOSED" => Ok(AccountStatus::Closed),
+            _ => Err(AppError::internal_s(format!("unknown account status {}", code))),
+        }
+    }
+
+    /// Checks the balance of an account with this status can be changed.
+    pub fn check_active(&self) -> Result<(), AppError> {
+        match self {
+            AccountStatus::Active => Ok(()),
+            AccountStatus::Frozen => Err(AppError::business(ErrorCode::AccountFrozen, "account is frozen")),
+            AccountStatus::Closed => Err(AppError::business(ErrorCode::AccountClosed, "account is closed")),
+        }
+    }
+
+    /// Checks an account with this status can be moved to the new one.
+    pub fn check_transition(&self, to: AccountStatus) -> Result<(), AppError> {
+        match (self, to) {
+            (AccountStatus::Active, AccountStatus::Frozen) => Ok(()),
+            (AccountStatus::Frozen, AccountStatus::Active) => Ok(()),
+            (AccountStatus::Active, AccountStatus::Closed) => Ok(()),
+            (AccountStatus::Frozen, AccountStatus::Closed) => Ok(()),
+            (AccountStatus::Closed, _) => Err(AppError::business(ErrorCode::AccountClosed, "account is closed")),
+            (from, to) if *from == to => Err(AppError::business_s(
+                ErrorCode::InvalidStatusChange,
+                format!("account is already {}", to),
+            )),
+            (from, _) => Err(AppError::business_s(
+                ErrorCode::InvalidStatusChange,
+                format!("account is {}", from),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for AccountStatus {
+    fn fmt(&self, fmttr: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmttr, "{}", self.code().to_lowercase())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AccountStatus::{self, Active, Closed, Frozen};
+    use crate::error::{AppError, ErrorCode};
+
+    #[test]
+    fn should_allow_freezing_unfreezing_and_closing() {
+        assert!(Active.check_transition(Frozen).is_ok());
+        assert!(Frozen.check_transition(Active).is_ok());
+        assert!(Active.check_transition(Closed).is_ok());
+        assert!(Frozen.check_transition(Closed).is_ok());
+    }
+
+    #[test]
+    fn should_not_reopen_closed_account() {
+        for to in [Active, Frozen, Closed] {
+            assert_conflict(Closed, to);
+        }
+    }
+
+    #[test]
+    fn should_not_repeat_transition() {
+        assert_conflict(Active, Active);
+        assert_conflict(Frozen, Frozen);
+    }
+
+    #[test]
+    fn should_only_allow_active_account_to_change_balance() {
+        assert!(Active.check_active().is_ok());
+        assert!(matches!(Frozen.check_active(), Err(AppError::Business(ErrorCode::AccountFrozen, _))));
+        assert!(matches!(Closed.check_active(), Err(AppError::Business(ErrorCode::AccountClosed, _))));
+    }
+
+    fn assert_conflict(from: AccountStatus, to: AccountStatus) {
+        assert!(matches!(
+            from.check_transition(to),
+            Err(AppError::Business(ErrorCode::AccountClosed | ErrorCode::InvalidStatusChange, _))
+        ));
+    }
+}
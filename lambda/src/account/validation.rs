@@ -0,0 +1,255 @@
+use bigdecimal::{num_bigint::Sign, BigDecimal};
+use std::collections::BTreeSet;
+
+use crate::error::{AppError, FieldError};
+
+/// The longest account id, which is also the longest key DynamoDB would be given.
+const MAX_ACCOUNT_ID_LENGTH: usize = 64;
+/// No single amount should come near this, so one which does is taken to be a mistake.
+const MAX_AMOUNT: i64 = 1_000_000_000_000;
+const MAX_DESCRIPTION_LENGTH: usize = 255;
+const MAX_OWNER_NAME_LENGTH: usize = 255;
+/// The longest address allowed by RFC 5321.
+const MAX_EMAIL_LENGTH: usize = 254;
+const MAX_REFERENCE_LENGTH: usize = 128;
+const MAX_TAGS: usize = 20;
+const MAX_TAG_LENGTH: usize = 64;
+/// What an account has besides its holder details, none of which a patch can change.
+const NOT_PATCHABLE: &[&str] = &[
+    "accountId", "currency", "balance", "overdraftLimit", "availableFunds", "status", "heldAmount",
+];
+
+/// A payload which checks its own values before it is acted on.
+pub trait Validate {
+    /// Fails with every value at fault, rather than just the first.
+    fn validate(&self) -> Result<(), AppError>;
+}
+
+/// Collects the problems with the values of a payload, each rule being checked
+/// against the value found by a JSON pointer.
+#[derive(Default)]
+pub struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn check(mut self, pointer: &str, rule: Result<(), String>) -> Self {
+        if let Err(detail) = rule {
+            self.errors.push(FieldError {
+                pointer: pointer.to_string(),
+                detail,
+            });
+        }
+        self
+    }
+
+    pub fn finish(self) -> Result<(), AppError> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(AppError::Validation(self.errors))
+        }
+    }
+}
+
+/// Letters, digits, hyphens and underscores, so that an id can be used in a path unescaped.
+pub fn account_id(account_id: &str) -> Result<(), String> {
+    if account_id.is_empty() || account_id.len() > MAX_ACCOUNT_ID_LENGTH {
+        return Err(format!(
+            "account id must be between 1 and {} characters",
+            MAX_ACCOUNT_ID_LENGTH
+        ));
+    }
+    if !account_id
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err("account id can only contain letters, digits, '-' and '_'".to_string());
+    }
+    Ok(())
+}
+
+pub fn not_zero(amount: &BigDecimal) -> Result<(), String> {
+    match amount.sign() {
+        Sign::NoSign => Err("amount can not be zero".to_string()),
+        _ => Ok(()),
+    }
+}
+
+pub fn not_negative(amount: &BigDecimal) -> Result<(), String> {
+    match amount.sign() {
+        Sign::Minus => Err("amount can not be negative".to_string()),
+        _ => Ok(()),
+    }
+}
+
+/// Either way from zero.
+pub fn within_max_amount(amount: &BigDecimal) -> Result<(), String> {
+    if amount.abs() > BigDecimal::from(MAX_AMOUNT) {
+        return Err(format!("amount can not be more than {}", MAX_AMOUNT));
+    }
+    Ok(())
+}
+
+pub fn description(description: &Option<String>) -> Result<(), String> {
+    match description {
+        Some(text) if text.chars().count() > MAX_DESCRIPTION_LENGTH => Err(format!(
+            "description can not be more than {} characters",
+            MAX_DESCRIPTION_LENGTH
+        )),
+        _ => Ok(()),
+    }
+}
+
+pub fn owner_name(owner_name: &Option<String>) -> Result<(), String> {
+    match owner_name {
+        Some(name) => bounded_text("owner name", name, MAX_OWNER_NAME_LENGTH),
+        None => Ok(()),
+    }
+}
+
+/// Only roughly checked, as the way to be sure of an address is to send to it.
+pub fn contact_email(contact_email: &Option<String>) -> Result<(), String> {
+    let email = match contact_email {
+        Some(email) => email,
+        None => return Ok(()),
+    };
+    if email.len() > MAX_EMAIL_LENGTH {
+        return Err(format!("contact email can not be more than {} characters", MAX_EMAIL_LENGTH));
+    }
+    match email.rsplit_once('@') {
+        Some((local, domain))
+            if !local.is_empty()
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !email.chars().any(char::is_whitespace) =>
+        {
+            Ok(())
+        }
+        _ => Err("contact email is not an email address".to_string()),
+    }
+}
+
+pub fn external_reference(external_reference: &Option<String>) -> Result<(), String> {
+    match external_reference {
+        Some(reference) => bounded_text("external reference", reference, MAX_REFERENCE_LENGTH),
+        None => Ok(()),
+    }
+}
+
+pub fn tags(tags: &BTreeSet<String>) -> Result<(), String> {
+    if tags.len() > MAX_TAGS {
+        return Err(format!("there can not be more than {} tags", MAX_TAGS));
+    }
+    tags.iter().try_for_each(|tag| bounded_text("tag", tag, MAX_TAG_LENGTH))
+}
+
+/// The balance in particular is only ever changed by adjusting it, so that the ledger records it.
+pub fn not_patchable(name: &str) -> Result<(), String> {
+    if NOT_PATCHABLE.contains(&name) {
+        Err(format!("{} can not be changed by a patch", name))
+    } else {
+        Err(format!("{} is not a field of an account", name))
+    }
+}
+
+/// The JSON pointer to a member of the payload, escaped as RFC 6901 asks.
+pub fn pointer_to(name: &str) -> String {
+    format!("/{}", name.replace('~', "~0").replace('/', "~1"))
+}
+
+/// Not empty or only whitespace, and no longer than the maximum number of characters.
+fn bounded_text(what: &str, text: &str, max_length: usize) -> Result<(), String> {
+    if text.trim().is_empty() {
+        return Err(format!("{} can not be blank", what));
+    }
+    if text.chars().count() > max_length {
+        return Err(format!("{} can not be more than {} characters", what, max_length));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{account_id, contact_email, not_patchable, not_zero, pointer_to, tags, within_max_amount, Validator};
+    use crate::error::{AppError, FieldError};
+    use bigdecimal::BigDecimal;
+    use std::{collections::BTreeSet, str::FromStr};
+
+    #[test]
+    fn should_collect_every_problem() {
+        let result = Validator::new()
+            .check("/accountId", account_id("fred flintstone"))
+            .check("/amount", not_zero(&decimal("0")))
+            .check("/balance", within_max_amount(&decimal("1")))
+            .finish();
+
+        let pointers: Vec<String> = match result {
+            Err(AppError::Validation(errors)) => errors.into_iter().map(|error| error.pointer).collect(),
+            _ => vec![],
+        };
+        assert_eq!(pointers, ["/accountId", "/amount"]);
+    }
+
+    #[test]
+    fn should_pass_when_no_problems() {
+        assert!(Validator::new().check("/accountId", account_id("fred-01_x")).finish().is_ok());
+    }
+
+    #[test]
+    fn should_limit_account_id() {
+        assert!(account_id("").is_err());
+        assert!(account_id(&"x".repeat(64)).is_ok());
+        assert!(account_id(&"x".repeat(65)).is_err());
+        assert!(account_id("fred/wilma").is_err());
+    }
+
+    #[test]
+    fn should_limit_amount_either_way() {
+        assert!(within_max_amount(&decimal("1000000000000")).is_ok());
+        assert!(within_max_amount(&decimal("1000000000000.01")).is_err());
+        assert!(within_max_amount(&decimal("-1000000000000.01")).is_err());
+    }
+
+    #[test]
+    fn should_describe_problem() {
+        let result = Validator::new().check("/amount", not_zero(&decimal("0.00"))).finish();
+        assert!(matches!(result, Err(AppError::Validation(errors)) if errors == [FieldError {
+            pointer: "/amount".to_string(),
+            detail: "amount can not be zero".to_string(),
+        }]));
+    }
+
+    #[test]
+    fn should_roughly_check_contact_email() {
+        assert!(contact_email(&None).is_ok());
+        assert!(contact_email(&Some("fred@bedrock.example".to_string())).is_ok());
+        assert!(contact_email(&Some("fred".to_string())).is_err());
+        assert!(contact_email(&Some("@bedrock.example".to_string())).is_err());
+        assert!(contact_email(&Some("fred@bedrock".to_string())).is_err());
+        assert!(contact_email(&Some("fred flintstone@bedrock.example".to_string())).is_err());
+    }
+
+    #[test]
+    fn should_limit_tags() {
+        let many: BTreeSet<String> = (0..21).map(|n| n.to_string()).collect();
+        assert!(tags(&many).is_err());
+        assert!(tags(&BTreeSet::from([" ".to_string()])).is_err());
+        assert!(tags(&BTreeSet::from(["gold".to_string(), "x".repeat(64)])).is_ok());
+    }
+
+    #[test]
+    fn should_not_patch_balance() {
+        assert_eq!(not_patchable("balance"), Err("balance can not be changed by a patch".to_string()));
+        assert_eq!(pointer_to("a/b~c"), "/a~1b~0c");
+    }
+
+    fn decimal(value: &str) -> BigDecimal {
+        BigDecimal::from_str(value).expect("invalid decimal")
+    }
+}
@@ -0,0 +1,366 @@
+use http::Uri;
+use std::{env, fmt, net::SocketAddr, str::FromStr};
+
+use crate::logging::LogFilter;
+
+/// Signs next page tokens when accounts are kept in memory, as they are then only in the one
+/// instance, so there is nothing for a token to be taken to elsewhere.
+const MEMORY_PAGE_TOKEN_SECRET: &str = "in-memory";
+/// Leaves room in an account id for the 27 characters generated after the prefix.
+const MAX_ACCOUNT_ID_PREFIX_LENGTH: usize = 16;
+
+/// Settings for the whole application, read from environment variables once at start up.
+///
+/// Every variable is checked before any is used, so that a misconfigured deployment
+/// reports everything that is wrong with it at once rather than one problem at a time.
+/// See template.yaml for how they are set in AWS.
+pub struct Config {
+    pub storage: Storage,
+    pub tables: TableNames,
+    /// Which levels are logged for which modules, from RUST_LOG.
+    pub log_filter: LogFilter,
+    pub limits: Limits,
+    /// Used to sign next page tokens. Every instance must have the same one, for a token issued by
+    /// one to be accepted by another.
+    pub page_token_secret: String,
+    /// Starts the ids generated for accounts created without one.
+    pub account_id_prefix: String,
+    /// Where subsegments are sent, which Lambda sets when tracing is active. When not set, nothing is traced.
+    pub xray_daemon_address: Option<SocketAddr>,
+    /// Only used by the local server.
+    pub port: u16,
+}
+
+/// Where accounts are kept, chosen by DYNAMODB_SWITCH.
+pub enum Storage {
+    /// DynamoDB in AWS, with the region and credentials found as the AWS SDK normally would.
+    Global,
+    /// A DynamoDB compatible endpoint, such as dynamodb-local or Localstack.
+    Local { endpoint: Uri, region: String },
+    /// In memory, for running without DynamoDB. Everything is lost when the process stops.
+    Memory,
+}
+
+/// The names of the DynamoDB tables, which default to those in template.yaml.
+#[derive(Debug, Clone)]
+pub struct TableNames {
+    pub accounts: String,
+    pub transactions: String,
+    pub idempotency_keys: String,
+    pub holds: String,
+    pub balance_snapshots: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// How long a hold on funds lasts before it is released.
+    pub hold_lifetime_secs: i64,
+    /// How many items are in a page when the client does not say.
+    pub default_page_size: i32,
+    /// The most items a client can ask for in a page.
+    pub max_page_size: i32,
+}
+
+/// Everything wrong with the configuration.
+pub struct ConfigError {
+    problems: Vec<String>,
+}
+
+impl Default for TableNames {
+    fn default() -> Self {
+        Self {
+            accounts: "Accounts".to_string(),
+            transactions: "Transactions".to_string(),
+            idempotency_keys: "IdempotencyKeys".to_string(),
+            holds: "Holds".to_string(),
+            balance_snapshots: "BalanceSnapshots".to_string(),
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            hold_lifetime_secs: 7 * 24 * 60 * 60,
+            default_page_size: 20,
+            max_page_size: 100,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Result<Config, ConfigError> {
+        Config::from_vars(|name| env::var(name).ok())
+    }
+
+    /// Reads the configuration using the given lookup of variable names to values.
+    fn from_vars<F>(var: F) -> Result<Config, ConfigError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut reader = Reader { var, problems: Vec::new() };
+
+        let switch = reader.required("DYNAMODB_SWITCH");
+        let storage = match switch.as_deref() {
+            Some("GLOBAL") => Storage::Global,
+            Some("LOCAL") => {
+                let endpoint = reader.required_parsed::<Uri>("LOCAL_DYNAMODB_ENDPOINT", "a URL");
+                let region = reader.required("REGION");
+                match (endpoint, region) {
+                    (Some(endpoint), Some(region)) => Storage::Local { endpoint, region },
+                    _ => Storage::Memory,
+                }
+            }
+            Some("MEMORY") => Storage::Memory,
+            Some(other) => {
+                reader.problem(format!(
+                    "DYNAMODB_SWITCH must be GLOBAL, LOCAL or MEMORY but was '{}'",
+                    other
+                ));
+                Storage::Memory
+            }
+            None => Storage::Memory,
+        };
+
+        let defaults = TableNames::default();
+        let tables = TableNames {
+            accounts: reader.optional("ACCOUNTS_TABLE").unwrap_or(defaults.accounts),
+            transactions: reader.optional("TRANSACTIONS_TABLE").unwrap_or(defaults.transactions),
+            idempotency_keys: reader
+                .optional("IDEMPOTENCY_KEYS_TABLE")
+                .unwrap_or(defaults.idempotency_keys),
+            holds: reader.optional("HOLDS_TABLE").unwrap_or(defaults.holds),
+            balance_snapshots: reader
+                .optional("BALANCE_SNAPSHOTS_TABLE")
+                .unwrap_or(defaults.balance_snapshots),
+        };
+
+        let log_filter = reader
+            .optional_parsed(
+                "RUST_LOG",
+                "levels of off, error, warn, info, debug or trace, optionally for modules, e.g. 'info,rustmonkey_api::account=debug'",
+            )
+            .unwrap_or_default();
+
+        let defaults = Limits::default();
+        let limits = Limits {
+            hold_lifetime_secs: reader
+                .optional_positive("HOLD_LIFETIME_SECS")
+                .unwrap_or(defaults.hold_lifetime_secs),
+            default_page_size: reader
+                .optional_positive("DEFAULT_PAGE_SIZE")
+                .unwrap_or(defaults.default_page_size),
+            max_page_size: reader
+                .optional_positive("MAX_PAGE_SIZE")
+                .unwrap_or(defaults.max_page_size),
+        };
+        if limits.default_page_size > limits.max_page_size {
+            reader.problem(format!(
+                "DEFAULT_PAGE_SIZE ({}) must not be more than MAX_PAGE_SIZE ({})",
+                limits.default_page_size, limits.max_page_size
+            ));
+        }
+
+        let page_token_secret = match switch.as_deref() {
+            Some("GLOBAL") | Some("LOCAL") => reader.required("PAGE_TOKEN_SECRET").unwrap_or_default(),
+            _ => reader
+                .optional("PAGE_TOKEN_SECRET")
+                .unwrap_or_else(|| MEMORY_PAGE_TOKEN_SECRET.to_string()),
+        };
+        let account_id_prefix = reader.optional("ACCOUNT_ID_PREFIX").unwrap_or_else(|| "acc_".to_string());
+        if account_id_prefix.len() > MAX_ACCOUNT_ID_PREFIX_LENGTH
+            || !account_id_prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            reader.problem(format!(
+                "ACCOUNT_ID_PREFIX must be at most {} letters, digits, '-' or '_' but was '{}'",
+                MAX_ACCOUNT_ID_PREFIX_LENGTH, account_id_prefix
+            ));
+        }
+        let xray_daemon_address = reader.optional_parsed("AWS_XRAY_DAEMON_ADDRESS", "an address such as 127.0.0.1:2000");
+        let port = reader.optional_parsed("PORT", "a port number").unwrap_or(3000);
+
+        if !reader.problems.is_empty() {
+            return Err(ConfigError { problems: reader.problems });
+        }
+        Ok(Config {
+            storage,
+            tables,
+            log_filter,
+            limits,
+            page_token_secret,
+            account_id_prefix,
+            xray_daemon_address,
+            port,
+        })
+    }
+}
+
+/// Reads variables, noting the problems rather than stopping at the first.
+struct Reader<F> {
+    var: F,
+    problems: Vec<String>,
+}
+
+impl<F> Reader<F>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    fn problem(&mut self, problem: String) {
+        self.problems.push(problem);
+    }
+
+    /// An empty variable is the same as one that is not set, as CloudFormation
+    /// can not leave a variable out.
+    fn optional(&mut self, name: &str) -> Option<String> {
+        (self.var)(name).filter(|value| !value.is_empty())
+    }
+
+    fn required(&mut self, name: &str) -> Option<String> {
+        let value = self.optional(name);
+        if value.is_none() {
+            self.problem(format!("{} must be set", name));
+        }
+        value
+    }
+
+    fn optional_parsed<T: FromStr>(&mut self, name: &str, expected: &str) -> Option<T> {
+        let value = self.optional(name)?;
+        self.parse(name, value, expected)
+    }
+
+    fn required_parsed<T: FromStr>(&mut self, name: &str, expected: &str) -> Option<T> {
+        let value = self.required(name)?;
+        self.parse(name, value, expected)
+    }
+
+    fn optional_positive<T: FromStr + PartialOrd + Default>(&mut self, name: &str) -> Option<T> {
+        let value = self.optional(name)?;
+        match value.parse::<T>() {
+            Ok(number) if number > T::default() => Some(number),
+            _ => {
+                self.problem(format!("{} must be a positive whole number but was '{}'", name, value));
+                None
+            }
+        }
+    }
+
+    fn parse<T: FromStr>(&mut self, name: &str, value: String, expected: &str) -> Option<T> {
+        match value.parse() {
+            Ok(parsed) => Some(parsed),
+            Err(_) => {
+                self.problem(format!("{} must be {} but was '{}'", name, expected, value));
+                None
+            }
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, fmttr: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmttr, "invalid configuration: {}", self.problems.join("; "))
+    }
+}
+
+// Returning an error from main prints it with Debug, so make that readable too.
+impl fmt::Debug for ConfigError {
+    fn fmt(&self, fmttr: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, fmttr)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[cfg(test)]
+mod test {
+    use super::{Config, Storage};
+    use log::LevelFilter;
+    use std::collections::HashMap;
+
+    #[test]
+    fn should_use_defaults_for_optional_settings() {
+        let config = config_from(&[("DYNAMODB_SWITCH", "GLOBAL"), ("PAGE_TOKEN_SECRET", "secret")])
+            .expect("invalid configuration");
+
+        assert!(matches!(config.storage, Storage::Global));
+        assert_eq!(config.tables.accounts, "Accounts");
+        assert_eq!(config.tables.holds, "Holds");
+        assert_eq!(config.tables.balance_snapshots, "BalanceSnapshots");
+        assert_eq!(config.log_filter.max_level(), LevelFilter::Info);
+        assert_eq!(config.limits.max_page_size, 100);
+        assert_eq!(config.page_token_secret, "secret");
+        assert_eq!(config.account_id_prefix, "acc_");
+        assert!(config.xray_daemon_address.is_none());
+    }
+
+    #[test]
+    fn should_read_local_endpoint() {
+        let config = config_from(&[
+            ("DYNAMODB_SWITCH", "LOCAL"),
+            ("LOCAL_DYNAMODB_ENDPOINT", "http://localhost:8000"),
+            ("REGION", "eu-west-2"),
+            ("PAGE_TOKEN_SECRET", "secret"),
+            ("ACCOUNTS_TABLE", "TestAccounts"),
+            ("RUST_LOG", "debug"),
+        ])
+        .expect("invalid configuration");
+
+        assert!(matches!(config.storage, Storage::Local { region, .. } if region == "eu-west-2"));
+        assert_eq!(config.tables.accounts, "TestAccounts");
+        assert_eq!(config.log_filter.max_level(), LevelFilter::Debug);
+    }
+
+    #[test]
+    fn should_report_every_problem() {
+        let result = config_from(&[
+            ("DYNAMODB_SWITCH", "LOCAL"),
+            ("HOLD_LIFETIME_SECS", "-1"),
+            ("MAX_PAGE_SIZE", "lots"),
+            ("RUST_LOG", "loud"),
+            ("ACCOUNT_ID_PREFIX", "acc/"),
+        ]);
+
+        let message = result.err().expect("configuration accepted").to_string();
+        for name in [
+            "LOCAL_DYNAMODB_ENDPOINT", "REGION", "PAGE_TOKEN_SECRET", "HOLD_LIFETIME_SECS", "MAX_PAGE_SIZE", "RUST_LOG",
+            "ACCOUNT_ID_PREFIX",
+        ] {
+            assert!(message.contains(name), "{} not in '{}'", name, message);
+        }
+    }
+
+    #[test]
+    fn should_require_storage_switch() {
+        let message = config_from(&[]).err().expect("configuration accepted").to_string();
+        assert_eq!(message, "invalid configuration: DYNAMODB_SWITCH must be set");
+    }
+
+    #[test]
+    fn should_require_page_token_secret_with_dynamodb() {
+        let message = config_from(&[("DYNAMODB_SWITCH", "GLOBAL")]).err().expect("configuration accepted").to_string();
+        assert_eq!(message, "invalid configuration: PAGE_TOKEN_SECRET must be set");
+    }
+
+    #[test]
+    fn should_not_require_page_token_secret_in_memory() {
+        let config = config_from(&[("DYNAMODB_SWITCH", "MEMORY")]).expect("invalid configuration");
+        assert!(!config.page_token_secret.is_empty());
+    }
+
+    #[test]
+    fn should_not_allow_default_page_size_over_maximum() {
+        let result = config_from(&[
+            ("DYNAMODB_SWITCH", "MEMORY"),
+            ("DEFAULT_PAGE_SIZE", "50"),
+            ("MAX_PAGE_SIZE", "10"),
+        ]);
+        assert!(result.is_err());
+    }
+
+    fn config_from(vars: &[(&str, &str)]) -> Result<Config, super::ConfigError> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        Config::from_vars(|name| vars.get(name).cloned())
+    }
+}
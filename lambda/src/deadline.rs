@@ -0,0 +1,107 @@
+use std::{
+    future::Future,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::time::error::Elapsed;
+
+/// Kept back from the invocation deadline, so there is time to respond before Lambda stops the function.
+const SAFETY_MARGIN: Duration = Duration::from_millis(500);
+
+tokio::task_local! {
+    static DEADLINE: Instant;
+}
+
+/// Runs the handling of a request knowing when it has to be finished by,
+/// so that anything it waits for can give up in time to respond.
+pub async fn scope<F: Future>(deadline: Instant, future: F) -> F::Output {
+    DEADLINE.scope(deadline, future).await
+}
+
+/// Runs the handling of a request within [`scope`], abandoning it if not finished by the deadline.
+/// Once the deadline has passed it is not started at all, as it could not finish in time.
+pub async fn run_until<F: Future>(deadline: Instant, future: F) -> Result<F::Output, Elapsed> {
+    let started = async {
+        if Instant::now() >= deadline {
+            std::future::pending::<()>().await;
+        }
+        future.await
+    };
+    scope(deadline, tokio::time::timeout_at(deadline.into(), started)).await
+}
+
+/// When a request has to be finished by, given the deadline of the invocation.
+pub fn budget(deadline_millis: u64) -> Instant {
+    let deadline = from_epoch_millis(deadline_millis);
+    deadline.checked_sub(SAFETY_MARGIN).unwrap_or(deadline)
+}
+
+/// The time left before the deadline of the request being handled, if it has one.
+pub fn remaining() -> Option<Duration> {
+    DEADLINE
+        .try_with(|deadline| deadline.saturating_duration_since(Instant::now()))
+        .ok()
+}
+
+/// The Lambda runtime gives the deadline as milliseconds since the epoch.
+fn from_epoch_millis(deadline_millis: u64) -> Instant {
+    let now_millis = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_millis() as u64)
+        .unwrap_or_default();
+    Instant::now() + Duration::from_millis(deadline_millis.saturating_sub(now_millis))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{budget, from_epoch_millis, remaining, run_until, scope, SAFETY_MARGIN};
+    use std::time::{Duration, Instant, SystemTime};
+
+    #[tokio::test]
+    async fn should_only_have_deadline_within_scope() {
+        assert!(remaining().is_none());
+        let left = scope(Instant::now() + Duration::from_secs(10), async { remaining() }).await;
+        assert!(matches!(left, Some(left) if left > Duration::from_secs(9)));
+    }
+
+    #[test]
+    fn should_treat_past_deadline_as_now() {
+        assert!(from_epoch_millis(0) <= Instant::now());
+    }
+
+    #[test]
+    fn should_keep_safety_margin_back_from_deadline() {
+        // Given
+        let in_a_minute = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap() + Duration::from_secs(60);
+
+        // When
+        let left = budget(in_a_minute.as_millis() as u64).saturating_duration_since(Instant::now());
+
+        // Then
+        assert!(left <= Duration::from_secs(60) - SAFETY_MARGIN);
+        assert!(left > Duration::from_secs(59) - SAFETY_MARGIN);
+    }
+
+    #[tokio::test]
+    async fn should_abandon_request_not_finished_by_deadline() {
+        // Given
+        let slow_request = tokio::time::sleep(Duration::from_secs(10));
+
+        // When
+        let result = run_until(Instant::now() + Duration::from_millis(10), slow_request).await;
+
+        // Then
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn should_not_start_request_after_deadline() {
+        let result = run_until(Instant::now(), async { remaining() }).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn should_finish_request_within_deadline() {
+        let result = run_until(Instant::now() + Duration::from_secs(10), async { remaining().is_some() }).await;
+        assert!(matches!(result, Ok(true)));
+    }
+}
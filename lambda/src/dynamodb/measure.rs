@@ -0,0 +1,78 @@
+use aws_sdk_dynamodb::{
+    model::ConsumedCapacity,
+    output::{GetItemOutput, PutItemOutput, QueryOutput, ScanOutput, TransactWriteItemsOutput, UpdateItemOutput},
+    SdkError,
+};
+use std::{future::Future, time::Instant};
+
+use crate::metrics::{self, Unit};
+use crate::xray;
+
+/// The output of a call which reports the capacity it consumed,
+/// when asked to with `return_consumed_capacity`.
+pub trait ConsumesCapacity {
+    fn capacity_units(&self) -> f64;
+}
+
+/// Records how long a call to DynamoDB took, including any retries, and the capacity it consumed,
+/// as metrics dimensioned by the operation. When the request is traced, the call is a subsegment.
+pub async fn measured<T, E, F>(operation: &'static str, call: F) -> Result<T, SdkError<E>>
+where
+    T: ConsumesCapacity,
+    F: Future<Output = Result<T, SdkError<E>>>,
+{
+    let started = Instant::now();
+    let result = xray::aws_subsegment("DynamoDB", operation, call).await;
+    let dimensions = || vec![("Operation", operation.to_string())];
+    metrics::put_with(
+        dimensions(),
+        "DynamoDbLatency",
+        Unit::Milliseconds,
+        started.elapsed().as_secs_f64() * 1000.0,
+    );
+    if let Ok(output) = &result {
+        metrics::put_with(dimensions(), "ConsumedCapacity", Unit::None, output.capacity_units());
+    }
+    result
+}
+
+fn units(consumed: Option<&ConsumedCapacity>) -> f64 {
+    consumed.and_then(|consumed| consumed.capacity_units).unwrap_or_default()
+}
+
+impl ConsumesCapacity for GetItemOutput {
+    fn capacity_units(&self) -> f64 {
+        units(self.consumed_capacity.as_ref())
+    }
+}
+
+impl ConsumesCapacity for PutItemOutput {
+    fn capacity_units(&self) -> f64 {
+        units(self.consumed_capacity.as_ref())
+    }
+}
+
+impl ConsumesCapacity for UpdateItemOutput {
+    fn capacity_units(&self) -> f64 {
+        units(self.consumed_capacity.as_ref())
+    }
+}
+
+impl ConsumesCapacity for QueryOutput {
+    fn capacity_units(&self) -> f64 {
+        units(self.consumed_capacity.as_ref())
+    }
+}
+
+impl ConsumesCapacity for ScanOutput {
+    fn capacity_units(&self) -> f64 {
+        units(self.consumed_capacity.as_ref())
+    }
+}
+
+/// A transaction reports the capacity it consumed from each table.
+impl ConsumesCapacity for TransactWriteItemsOutput {
+    fn capacity_units(&self) -> f64 {
+        self.consumed_capacity.iter().flatten().map(|consumed| units(Some(consumed))).sum()
+    }
+}
@@ -0,0 +1,163 @@
+use aws_sdk_dynamodb::SdkError;
+use aws_smithy_types::retry::ProvideErrorKind;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::{future::Future, time::Duration};
+
+use crate::deadline;
+
+/// Gives up after this many attempts even when there is time for more,
+/// as by then something is more wrong than a passing spike in load.
+const MAX_ATTEMPTS: u32 = 6;
+const BASE_DELAY_MILLIS: u64 = 25;
+const MAX_DELAY_MILLIS: u64 = 1_000;
+/// Kept back from the deadline, for the last attempt and for responding.
+const RESERVED_MILLIS: u64 = 1_000;
+
+/// Error codes DynamoDB gives when a request may succeed if sent again.
+const RETRYABLE_CODES: &[&str] = &[
+    "ProvisionedThroughputExceededException",
+    "ThrottlingException",
+    "RequestLimitExceeded",
+    "TransactionConflictException",
+    "TransactionInProgressException",
+    "InternalServerError",
+    "ServiceUnavailable",
+];
+
+/// True when the request failed for a reason which may pass, such as throttling,
+/// a conflicting transaction, a timeout or an error within DynamoDB itself.
+pub fn is_retryable<E: ProvideErrorKind>(err: &SdkError<E>) -> bool {
+    match err {
+        SdkError::ConstructionFailure(_) => false,
+        SdkError::DispatchFailure(err) => !err.is_user(),
+        SdkError::ResponseError { .. } => true,
+        SdkError::ServiceError { err, raw } => {
+            let status = raw.http().status();
+            err.retryable_error_kind().is_some()
+                || matches!(err.code(), Some(code) if RETRYABLE_CODES.contains(&code))
+                || status.is_server_error()
+                || status == http::StatusCode::TOO_MANY_REQUESTS
+        }
+    }
+}
+
+/// Sends a request until it succeeds, fails for good or there is no time left to try again.
+///
+/// Each retry waits a random time up to twice as long as the last could have, so that
+/// requests held up together do not all come back together. The request is built by
+/// `send` each time, as the SDK's request builders are used up by sending.
+pub async fn send_with_retries<T, E, F, Fut, R>(retryable: R, mut send: F) -> Result<T, SdkError<E>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, SdkError<E>>>,
+    E: std::error::Error,
+    R: Fn(&SdkError<E>) -> bool,
+{
+    let mut attempt = 1;
+    loop {
+        match send().await {
+            Err(err) if retryable(&err) && attempt < MAX_ATTEMPTS => {
+                let delay = backoff(attempt);
+                if !time_left_for(delay) {
+                    return Err(err);
+                }
+                log::warn!("DynamoDB request failed, attempt {} of {}: {}", attempt, MAX_ATTEMPTS, err);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn backoff(attempt: u32) -> Duration {
+    let ceiling = (BASE_DELAY_MILLIS << attempt).min(MAX_DELAY_MILLIS);
+    let mut random = [0u8; 8];
+    let jitter = match SystemRandom::new().fill(&mut random) {
+        Ok(()) => u64::from_le_bytes(random) % (ceiling + 1),
+        Err(_) => ceiling,
+    };
+    Duration::from_millis(jitter)
+}
+
+/// Without a deadline, e.g. when not handling a request, only the attempts are limited.
+fn time_left_for(delay: Duration) -> bool {
+    deadline::remaining()
+        .is_none_or(|remaining| remaining > delay + Duration::from_millis(RESERVED_MILLIS))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_retryable, send_with_retries, MAX_ATTEMPTS};
+    use crate::deadline;
+    use aws_sdk_dynamodb::{error::GetItemError, SdkError};
+    use aws_smithy_http::result::ConnectorError;
+    use std::{
+        cell::Cell,
+        time::{Duration, Instant},
+    };
+
+    #[tokio::test]
+    async fn should_retry_until_success() {
+        // Given
+        let attempts = Cell::new(0);
+
+        // When
+        let result = send_with_retries(is_retryable, || {
+            attempts.set(attempts.get() + 1);
+            let failed = attempts.get() < 3;
+            async move { if failed { Err(timeout()) } else { Ok("done") } }
+        })
+        .await;
+
+        // Then
+        assert!(matches!(result, Ok("done")));
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[tokio::test]
+    async fn should_give_up_after_max_attempts() {
+        let attempts = Cell::new(0);
+        let result: Result<(), _> = send_with_retries(is_retryable, || {
+            attempts.set(attempts.get() + 1);
+            async { Err(timeout()) }
+        })
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), MAX_ATTEMPTS);
+    }
+
+    #[tokio::test]
+    async fn should_not_retry_permanent_failure() {
+        let attempts = Cell::new(0);
+        let result: Result<(), _> = send_with_retries(is_retryable, || {
+            attempts.set(attempts.get() + 1);
+            async { Err(SdkError::<GetItemError>::ConstructionFailure("invalid".into())) }
+        })
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn should_not_retry_past_deadline() {
+        let attempts = Cell::new(0);
+        let result: Result<(), _> = deadline::scope(Instant::now() + Duration::from_millis(500), async {
+            send_with_retries(is_retryable, || {
+                attempts.set(attempts.get() + 1);
+                async { Err(timeout()) }
+            })
+            .await
+        })
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+
+    fn timeout() -> SdkError<GetItemError> {
+        SdkError::DispatchFailure(ConnectorError::timeout("timed out".into()))
+    }
+}
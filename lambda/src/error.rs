@@ -1,6 +1,7 @@
 use bigdecimal::ParseBigDecimalError;
 use http::StatusCode;
 use lambda_http::lambda_runtime::Error;
+use serde::{Deserialize, Serialize};
 use std::fmt;
 
 /// Error used throughout this application.
@@ -27,17 +28,148 @@ pub enum AppError {
     /// An example of this type of error is a connection failure when retrieving data.
     Internal(Error),
 
+    /// A service this depends on failed for a reason expected to pass, such as throttling,
+    /// and was still failing after retries. A 503 status is returned to the client,
+    /// with a Retry-After header, and the error is logged as a WARN.
+    Unavailable(Error),
+
     /// The operation was prevented by a business logic rule.
-    /// A 4XX series status is returned to the client with a payload containing a message.
-    /// The message is expected to be meaningful to a user.
-    Business(String, StatusCode),
+    /// A 4XX series status, chosen by the code, is returned to the client with a payload
+    /// containing the code and a message. The message is expected to be meaningful to a user.
+    Business(ErrorCode, String),
+
+    /// The request broke the rules for the values it may hold.
+    /// A 400 status is returned to the client listing every value at fault, not just the first.
+    Validation(Vec<FieldError>),
+}
+
+/// A value in a request which is not valid, found by a JSON pointer (RFC 6901) into the payload.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FieldError {
+    pub pointer: String,
+    pub detail: String,
+}
+
+/// Why an operation was prevented, for clients to act on without reading the message.
+/// These are part of the API, so once released must not be renamed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    /// The payload is missing or is not JSON. JSON which is not the shape expected fails validation.
+    InvalidPayload,
+    /// A header or query parameter is not valid.
+    InvalidParameter,
+    /// Values in the payload are not valid, each described by a [`FieldError`].
+    ValidationFailed,
+    RouteNotFound,
+    MethodNotAllowed,
+    AccountNotFound,
+    HoldNotFound,
+    AccountExists,
+    AccountFrozen,
+    AccountClosed,
+    /// The account can not be moved to the status asked for from the one it has.
+    InvalidStatusChange,
+    /// The account changed while it was being updated, so the request can be retried.
+    ConcurrentUpdate,
+    InsufficientFunds,
+    /// An amount is not positive, or has more decimal places than its currency.
+    InvalidAmount,
+    CurrencyMismatch,
+    /// The other account of a transfer does not exist.
+    UnknownAccount,
+    SameAccountTransfer,
+    OverdraftLimitBelowBalance,
+    /// The account still has a balance or funds on hold, so can not be closed.
+    AccountNotEmpty,
+    /// An earlier request with the same idempotency key has not finished.
+    IdempotencyKeyInProgress,
+    /// The idempotency key was used for a different request.
+    IdempotencyKeyReused,
+    /// The account is no longer at the version given by If-Match, as it has changed since the client read it.
+    PreconditionFailed,
+    /// Only used for [`AppError::Unavailable`], the request can be retried later.
+    ServiceUnavailable,
+    /// The request did not finish before the invocation deadline, so may or may not have taken effect.
+    DeadlineExceeded,
+}
+
+impl ErrorCode {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidPayload | ErrorCode::InvalidParameter | ErrorCode::ValidationFailed => {
+                StatusCode::BAD_REQUEST
+            }
+            ErrorCode::RouteNotFound | ErrorCode::AccountNotFound | ErrorCode::HoldNotFound => {
+                StatusCode::NOT_FOUND
+            }
+            ErrorCode::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorCode::AccountExists
+            | ErrorCode::AccountClosed
+            | ErrorCode::InvalidStatusChange
+            | ErrorCode::ConcurrentUpdate
+            | ErrorCode::IdempotencyKeyInProgress => StatusCode::CONFLICT,
+            ErrorCode::AccountFrozen => StatusCode::LOCKED,
+            ErrorCode::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            ErrorCode::InsufficientFunds
+            | ErrorCode::InvalidAmount
+            | ErrorCode::CurrencyMismatch
+            | ErrorCode::UnknownAccount
+            | ErrorCode::SameAccountTransfer
+            | ErrorCode::OverdraftLimitBelowBalance
+            | ErrorCode::AccountNotEmpty
+            | ErrorCode::IdempotencyKeyReused => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    /// A short summary which is the same for every occurrence.
+    pub fn title(&self) -> &'static str {
+        match self {
+            ErrorCode::InvalidPayload => "Invalid payload",
+            ErrorCode::InvalidParameter => "Invalid parameter",
+            ErrorCode::ValidationFailed => "Validation failed",
+            ErrorCode::RouteNotFound => "Not found",
+            ErrorCode::MethodNotAllowed => "Method not allowed",
+            ErrorCode::AccountNotFound => "Account not found",
+            ErrorCode::HoldNotFound => "Hold not found",
+            ErrorCode::AccountExists => "Account already exists",
+            ErrorCode::AccountFrozen => "Account is frozen",
+            ErrorCode::AccountClosed => "Account is closed",
+            ErrorCode::InvalidStatusChange => "Invalid status change",
+            ErrorCode::ConcurrentUpdate => "Concurrent update",
+            ErrorCode::InsufficientFunds => "Insufficient funds",
+            ErrorCode::InvalidAmount => "Invalid amount",
+            ErrorCode::CurrencyMismatch => "Currency mismatch",
+            ErrorCode::UnknownAccount => "Unknown account",
+            ErrorCode::SameAccountTransfer => "Transfer to same account",
+            ErrorCode::OverdraftLimitBelowBalance => "Overdraft limit below balance",
+            ErrorCode::AccountNotEmpty => "Account not empty",
+            ErrorCode::IdempotencyKeyInProgress => "Idempotency key in progress",
+            ErrorCode::IdempotencyKeyReused => "Idempotency key reused",
+            ErrorCode::PreconditionFailed => "Precondition failed",
+            ErrorCode::ServiceUnavailable => "Service unavailable",
+            ErrorCode::DeadlineExceeded => "Deadline exceeded",
+        }
+    }
+
+    /// Identifies the type of problem, as a URI which is not expected to resolve.
+    pub fn type_uri(&self) -> String {
+        let code = serde_json::to_value(self)
+            .ok()
+            .and_then(|value| value.as_str().map(|code| code.to_lowercase().replace('_', "-")))
+            .unwrap_or_default();
+        format!("urn:rustmonkey:problem:{}", code)
+    }
 }
 
 impl std::error::Error for AppError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
-            AppError::Internal(error) => Some(error.as_ref()),
-            AppError::Business(_message, _status) => None,
+            AppError::Internal(error) | AppError::Unavailable(error) => Some(error.as_ref()),
+            AppError::Business(_code, _message) => None,
+            AppError::Validation(_errors) => None,
         }
     }
 }
@@ -46,28 +178,44 @@ impl fmt::Display for AppError {
     fn fmt(&self, fmttr: &mut fmt::Formatter) -> fmt::Result {
         match self {
             AppError::Internal(error) => error.fmt(fmttr),
-            AppError::Business(status, message) => {
-                write!(fmttr, "Business: {} {}", status, message)
+            AppError::Unavailable(error) => write!(fmttr, "Unavailable: {}", error),
+            AppError::Business(code, message) => {
+                write!(fmttr, "Business: {:?} {}", code, message)
+            }
+            AppError::Validation(errors) => {
+                let details: Vec<String> = errors
+                    .iter()
+                    .map(|error| format!("{} {}", error.pointer, error.detail))
+                    .collect();
+                write!(fmttr, "Validation: {}", details.join("; "))
             }
         }
     }
 }
 
 impl AppError {
-    pub fn bad_request_str(message: &str) -> AppError {
-        AppError::bad_request(message.to_string())
+    pub fn business(code: ErrorCode, message: &str) -> AppError {
+        AppError::Business(code, message.to_string())
+    }
+
+    pub fn business_s(code: ErrorCode, message: String) -> AppError {
+        AppError::Business(code, message)
+    }
+
+    pub fn invalid_parameter(message: &str) -> AppError {
+        AppError::business(ErrorCode::InvalidParameter, message)
     }
 
-    pub fn bad_request(message: String) -> AppError {
-        AppError::Business(message, StatusCode::BAD_REQUEST)
+    pub fn account_not_found() -> AppError {
+        AppError::business(ErrorCode::AccountNotFound, "account not found")
     }
 
-    pub fn not_found() -> AppError {
-        AppError::Business("not found".to_string(), StatusCode::NOT_FOUND)
+    pub fn hold_not_found() -> AppError {
+        AppError::business(ErrorCode::HoldNotFound, "hold not found")
     }
 
-    pub fn unprocessable(message: &str) -> AppError {
-        AppError::Business(message.to_string(), StatusCode::UNPROCESSABLE_ENTITY)
+    pub fn precondition_failed() -> AppError {
+        AppError::business(ErrorCode::PreconditionFailed, "account has changed since it was read")
     }
 
     pub fn wrap_internal(error: &'static (dyn std::error::Error + Send + Sync)) -> AppError {
@@ -75,24 +223,29 @@ impl AppError {
     }
 
     pub fn internal_s(message: String) -> AppError {
-        AppError::Internal(Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            message.as_str(),
-        )))
+        AppError::Internal(Box::new(std::io::Error::other(message)))
     }
 
     pub fn internal(message: &'static str) -> AppError {
-        AppError::Internal(Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            message,
-        )))
+        AppError::Internal(Box::new(std::io::Error::other(message)))
+    }
+
+    pub fn unavailable(message: &'static str) -> AppError {
+        AppError::Unavailable(Box::new(std::io::Error::other(message)))
     }
 }
 
 use aws_sdk_dynamodb::SdkError;
-impl<T: std::error::Error + Send + Sync + 'static> From<SdkError<T>> for AppError {
+use aws_smithy_types::retry::ProvideErrorKind;
+/// Failures which could pass are [`AppError::Unavailable`], as by the time they reach here
+/// they have already been retried.
+impl<T: ProvideErrorKind + std::error::Error + Send + Sync + 'static> From<SdkError<T>> for AppError {
     fn from(err: SdkError<T>) -> AppError {
-        AppError::Internal(Box::new(err))
+        if crate::dynamodb::is_retryable(&err) {
+            AppError::Unavailable(Box::new(err))
+        } else {
+            AppError::Internal(Box::new(err))
+        }
     }
 }
 
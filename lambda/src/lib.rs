@@ -0,0 +1,54 @@
+use lambda_http::lambda_runtime::Error;
+use log::warn;
+
+// 'mod' is a bit like C's #include in that it inserts the source at this point.
+// However, it is scoped within a separate named module, so you either need to
+// refer to items with the module's :: prefix, or include a 'use' statement
+// to bring the items into the current scope.
+
+mod account;
+mod config;
+mod deadline;
+mod dynamodb;
+mod error;
+mod logging;
+mod metrics;
+mod xray;
+mod web;
+
+// Re-export for easy access at crate scope.
+pub use config::Config;
+pub use error::AppError;
+pub use logging::init as init_logging;
+pub use web::{local_server, RequestHandler};
+
+use account::{
+    AccountDao, AccountIds, AccountRepository, AccountService, InMemoryAccountRepository, PageTokens,
+};
+use config::Storage;
+
+/// Creates the components which handle a request, shared by the lambda and the local server.
+pub async fn wire_up_components(config: &Config) -> Result<RequestHandler, Error> {
+    let account_repository: Box<dyn AccountRepository> = match &config.storage {
+        Storage::Memory => {
+            warn!("DYNAMODB_SWITCH is MEMORY, accounts will be lost when this instance stops");
+            Box::new(InMemoryAccountRepository::new())
+        }
+        Storage::Local { endpoint, region } => {
+            let ddb_client = dynamodb::create_client(Some((endpoint, region))).await;
+            Box::new(AccountDao::new(ddb_client, config.tables.clone()))
+        }
+        Storage::Global => {
+            let ddb_client = dynamodb::create_client(None).await;
+            Box::new(AccountDao::new(ddb_client, config.tables.clone()))
+        }
+    };
+    let page_tokens = PageTokens::new(config.page_token_secret.as_bytes());
+    let account_ids = AccountIds::new(&config.account_id_prefix);
+    let account_service = AccountService::new(account_repository, config.limits.clone(), page_tokens, account_ids);
+    let xray_daemon = match config.xray_daemon_address {
+        Some(address) => Some(xray::Daemon::new(address)?),
+        None => None,
+    };
+    Ok(web::create_request_handler(account_service, xray_daemon))
+}
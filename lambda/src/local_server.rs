@@ -0,0 +1,17 @@
+use lambda_http::lambda_runtime::Error;
+use log::info;
+
+use rustmonkey_api::{init_logging, local_server, wire_up_components, Config};
+
+/// Serves the API over plain HTTP, for trying it out without deploying.
+/// Uses the same environment variables as the lambda, plus PORT.
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let config = Config::from_env()?;
+    init_logging(config.log_filter.clone())?;
+
+    let root = wire_up_components(&config).await?;
+
+    info!("RustMonkey-api is listening on port {}", config.port);
+    local_server::serve(root, config.port).await
+}
@@ -0,0 +1,241 @@
+use chrono::{SecondsFormat, Utc};
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use serde::Serialize;
+use std::{
+    cell::RefCell,
+    future::Future,
+    io::{self, Write},
+    str::FromStr,
+};
+
+use crate::error::ErrorCode;
+
+tokio::task_local! {
+    static REQUEST: RefCell<RequestFields>;
+}
+
+/// Writes each log line to stdout as a JSON object, so that CloudWatch Logs Insights can query
+/// it by field. Lines logged while handling a request carry the [`RequestFields`] of that request.
+struct JsonLogger {
+    filter: LogFilter,
+}
+
+/// Which levels are logged for which modules, set by RUST_LOG in the same form as env_logger,
+/// e.g. `info,rustmonkey_api::account=debug`. A module without a level logs everything,
+/// and the most specific module given decides.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogFilter {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+/// What is known about the request being handled, added to every line logged while handling it.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestFields {
+    request_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    api_request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trace_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<ErrorCode>,
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    timestamp: String,
+    level: &'static str,
+    target: &'a str,
+    message: String,
+    #[serde(flatten)]
+    request: Option<&'a RequestFields>,
+}
+
+/// Replaces any logger set before, so must be called once at start up.
+pub fn init(filter: LogFilter) -> Result<(), SetLoggerError> {
+    log::set_max_level(filter.max_level());
+    log::set_boxed_logger(Box::new(JsonLogger { filter }))
+}
+
+/// Handles a request with the given fields added to every line logged while doing so.
+pub async fn scope<F: Future>(fields: RequestFields, future: F) -> F::Output {
+    REQUEST.scope(RefCell::new(fields), future).await
+}
+
+/// Adds to the fields of the request being handled, if there is one.
+pub fn annotate<F: FnOnce(&mut RequestFields)>(annotate: F) {
+    let _ = REQUEST.try_with(|fields| annotate(&mut fields.borrow_mut()));
+}
+
+impl RequestFields {
+    /// Empty ids, as when not running in Lambda, are left out.
+    pub fn new(request_id: &str, api_request_id: Option<String>, trace_id: &str) -> Self {
+        Self {
+            request_id: request_id.to_string(),
+            api_request_id: api_request_id.filter(|id| !id.is_empty()),
+            trace_id: Some(trace_id.to_string()).filter(|id| !id.is_empty()),
+            ..Default::default()
+        }
+    }
+}
+
+impl LogFilter {
+    pub fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_module, level)| *level)
+            .fold(self.default, std::cmp::max)
+    }
+
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter(|(module, _level)| is_within(target, module))
+            .max_by_key(|(module, _level)| module.len())
+            .map(|(_module, level)| *level)
+            .unwrap_or(self.default)
+    }
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        Self {
+            default: LevelFilter::Info,
+            modules: Vec::new(),
+        }
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = log::ParseLevelError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut filter = LogFilter {
+            default: LevelFilter::Off,
+            modules: Vec::new(),
+        };
+        for directive in value.split(',').map(str::trim).filter(|directive| !directive.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, level)) => filter.modules.push((module.to_string(), level.parse()?)),
+                None => match directive.parse() {
+                    Ok(level) => filter.default = level,
+                    // env_logger reads any other word as a module, but one that does not look like
+                    // a module path is more likely a misspelt level.
+                    Err(_) if directive.contains("::") || directive.contains('_') => {
+                        filter.modules.push((directive.to_string(), LevelFilter::Trace))
+                    }
+                    Err(err) => return Err(err),
+                },
+            }
+        }
+        Ok(filter)
+    }
+}
+
+/// A target is within a module when it is the module or one of its descendants.
+fn is_within(target: &str, module: &str) -> bool {
+    target == module || target.strip_prefix(module).is_some_and(|rest| rest.starts_with("::"))
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = REQUEST
+            .try_with(|fields| format_line(record, Some(&fields.borrow())))
+            .unwrap_or_else(|_| format_line(record, None));
+        // Locked, so that lines from different threads are not mixed together.
+        let _ = writeln!(io::stdout().lock(), "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = io::stdout().flush();
+    }
+}
+
+fn format_line(record: &Record, request: Option<&RequestFields>) -> String {
+    let line = Line {
+        timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        level: record.level().as_str(),
+        target: record.target(),
+        message: record.args().to_string(),
+        request,
+    };
+    serde_json::to_string(&line).unwrap_or_else(|_| record.args().to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{format_line, LogFilter, RequestFields};
+    use crate::error::ErrorCode;
+    use log::{Level, LevelFilter, Record};
+    use serde_json::Value;
+
+    #[test]
+    fn should_filter_by_most_specific_module() {
+        // Given
+        let filter: LogFilter = "warn,rustmonkey_api=info,rustmonkey_api::account::dao=debug".parse().unwrap();
+
+        // Then
+        assert_eq!(filter.level_for("hyper::proto"), LevelFilter::Warn);
+        assert_eq!(filter.level_for("rustmonkey_api::web"), LevelFilter::Info);
+        assert_eq!(filter.level_for("rustmonkey_api::account::dao"), LevelFilter::Debug);
+        assert_eq!(filter.level_for("rustmonkey_api_other"), LevelFilter::Warn);
+        assert_eq!(filter.max_level(), LevelFilter::Debug);
+    }
+
+    #[test]
+    fn should_log_everything_for_module_without_level() {
+        let filter: LogFilter = "rustmonkey_api::account".parse().unwrap();
+        assert_eq!(filter.level_for("rustmonkey_api::account::service"), LevelFilter::Trace);
+        assert_eq!(filter.level_for("rustmonkey_api::web"), LevelFilter::Off);
+    }
+
+    #[test]
+    fn should_not_accept_unknown_level() {
+        assert!("loud".parse::<LogFilter>().is_err());
+        assert!("rustmonkey_api=loud".parse::<LogFilter>().is_err());
+    }
+
+    #[test]
+    fn should_write_request_fields_into_line() {
+        // Given
+        let mut fields = RequestFields::new("request-1", Some("api-1".to_string()), "");
+        fields.status = Some(422);
+        fields.error_code = Some(ErrorCode::InsufficientFunds);
+
+        // When
+        let line = format_line(
+            &Record::builder()
+                .level(Level::Info)
+                .target("rustmonkey_api::web")
+                .args(format_args!("request end"))
+                .build(),
+            Some(&fields),
+        );
+
+        // Then
+        let line: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["message"], "request end");
+        assert_eq!(line["requestId"], "request-1");
+        assert_eq!(line["apiRequestId"], "api-1");
+        assert_eq!(line["status"], 422);
+        assert_eq!(line["errorCode"], "INSUFFICIENT_FUNDS");
+        assert!(line.get("traceId").is_none());
+    }
+}
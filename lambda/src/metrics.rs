@@ -0,0 +1,285 @@
+use chrono::Utc;
+use serde_json::{json, Map, Value};
+use std::{
+    cell::RefCell,
+    future::Future,
+    io::{self, Write},
+};
+
+use crate::error::ErrorCode;
+
+const NAMESPACE: &str = "RustMonkey";
+/// Used for the dimensions of metrics recorded before the request was routed.
+const UNROUTED: &str = "Unrouted";
+
+tokio::task_local! {
+    static METRICS: RefCell<Metrics>;
+}
+
+/// The metrics recorded while handling a request, written to stdout in CloudWatch Embedded
+/// Metric Format (EMF) when it ends. CloudWatch Logs extracts them from the log, so there is
+/// no call to CloudWatch to slow the request down.
+///
+/// Every metric has a Route dimension, and metrics with further dimensions, such as the
+/// error code, are written as separate documents.
+/// See https://docs.aws.amazon.com/AmazonCloudWatch/latest/monitoring/CloudWatch_Embedded_Metric_Format_Specification.html
+#[derive(Debug, Default)]
+pub struct Metrics {
+    route: Option<String>,
+    documents: Vec<Document>,
+}
+
+/// Metrics which share the same dimensions. A metric recorded more than once has all of its values.
+#[derive(Debug)]
+struct Document {
+    dimensions: Vec<(&'static str, String)>,
+    metrics: Vec<(&'static str, Unit, Vec<f64>)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Count,
+    Milliseconds,
+    None,
+}
+
+/// Records metrics for everything done by the future, which are printed when it is finished.
+pub async fn scope<F: Future>(future: F) -> F::Output {
+    scope_writing_to(Printed, future).await
+}
+
+/// As [`scope`], writing the metrics to `out` instead.
+pub async fn scope_writing_to<W: Write, F: Future>(mut out: W, future: F) -> F::Output {
+    METRICS
+        .scope(RefCell::new(Metrics::default()), async {
+            let output = future.await;
+            // Written in one go, rather than a line at a time, so that the lines are not mixed with
+            // those of other threads.
+            let mut emf = Vec::new();
+            if METRICS.with(|metrics| metrics.borrow().write_to(&mut emf)).is_ok() {
+                let _ = out.write_all(&emf);
+            }
+            output
+        })
+        .await
+}
+
+/// Writes to stdout with `print!`, so that tests capture what is written.
+struct Printed;
+
+impl Write for Printed {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        print!("{}", String::from_utf8_lossy(buf));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+/// Gives the route of the request being handled, used as a dimension of every metric.
+pub fn set_route(route: String) {
+    let _ = METRICS.try_with(|metrics| metrics.borrow_mut().route = Some(route));
+}
+
+/// Records a metric for the request being handled. Outside of a request it is dropped.
+pub fn put(name: &'static str, unit: Unit, value: f64) {
+    put_with(Vec::new(), name, unit, value);
+}
+
+/// Records a metric with dimensions in addition to the route.
+pub fn put_with(dimensions: Vec<(&'static str, String)>, name: &'static str, unit: Unit, value: f64) {
+    let _ = METRICS.try_with(|metrics| metrics.borrow_mut().put(dimensions, name, unit, value));
+}
+
+/// Counts a request which ended with the given error code.
+pub fn count_error(code: ErrorCode) {
+    let code = serde_json::to_value(code)
+        .ok()
+        .and_then(|value| value.as_str().map(str::to_string))
+        .unwrap_or_default();
+    put_with(vec![("ErrorCode", code)], "Errors", Unit::Count, 1.0);
+}
+
+impl Metrics {
+    fn put(&mut self, dimensions: Vec<(&'static str, String)>, name: &'static str, unit: Unit, value: f64) {
+        let index = match self.documents.iter().position(|document| document.dimensions == dimensions) {
+            Some(index) => index,
+            None => {
+                self.documents.push(Document {
+                    dimensions,
+                    metrics: Vec::new(),
+                });
+                self.documents.len() - 1
+            }
+        };
+        let metrics = &mut self.documents[index].metrics;
+        match metrics.iter_mut().find(|(metric_name, _unit, _values)| *metric_name == name) {
+            Some((_name, _unit, values)) => values.push(value),
+            None => metrics.push((name, unit, vec![value])),
+        }
+    }
+
+    /// Writes each document as a line of JSON.
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let route = self.route.as_deref().unwrap_or(UNROUTED);
+        let timestamp = Utc::now().timestamp_millis();
+        for document in &self.documents {
+            writeln!(out, "{}", document.to_emf(route, timestamp))?;
+        }
+        Ok(())
+    }
+}
+
+impl Document {
+    fn to_emf(&self, route: &str, timestamp: i64) -> Value {
+        let mut root = Map::new();
+        let mut dimension_names = vec!["Route"];
+        root.insert("Route".to_string(), json!(route));
+        for (name, value) in &self.dimensions {
+            dimension_names.push(name);
+            root.insert(name.to_string(), json!(value));
+        }
+
+        let mut definitions = Vec::with_capacity(self.metrics.len());
+        for (name, unit, values) in &self.metrics {
+            definitions.push(json!({ "Name": name, "Unit": unit.as_str() }));
+            let value = match values.as_slice() {
+                [single] => json!(single),
+                many => json!(many),
+            };
+            root.insert(name.to_string(), value);
+        }
+
+        root.insert(
+            "_aws".to_string(),
+            json!({
+                "Timestamp": timestamp,
+                "CloudWatchMetrics": [{
+                    "Namespace": NAMESPACE,
+                    "Dimensions": [dimension_names],
+                    "Metrics": definitions,
+                }],
+            }),
+        );
+        Value::Object(root)
+    }
+}
+
+impl Unit {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Unit::Count => "Count",
+            Unit::Milliseconds => "Milliseconds",
+            Unit::None => "None",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{count_error, put, put_with, scope, scope_writing_to, set_route, Unit};
+    use crate::error::ErrorCode;
+    use serde_json::{json, Value};
+
+    #[tokio::test]
+    async fn should_write_metrics_dimensioned_by_route() {
+        // Given
+        let output = recorded(|| {
+            set_route("AdjustBalance".to_string());
+            put("Requests", Unit::Count, 1.0);
+            put("Latency", Unit::Milliseconds, 12.0);
+        })
+        .await;
+
+        // Then
+        assert_eq!(output.len(), 1);
+        let document = &output[0];
+        assert_eq!(document["Route"], "AdjustBalance");
+        assert_eq!(document["Requests"], 1.0);
+        assert_eq!(document["Latency"], 12.0);
+        let definition = &document["_aws"]["CloudWatchMetrics"][0];
+        assert_eq!(definition["Namespace"], "RustMonkey");
+        assert_eq!(definition["Dimensions"], json!([["Route"]]));
+        assert_eq!(
+            definition["Metrics"],
+            json!([{"Name": "Requests", "Unit": "Count"}, {"Name": "Latency", "Unit": "Milliseconds"}])
+        );
+    }
+
+    #[tokio::test]
+    async fn should_write_each_set_of_dimensions_separately() {
+        let output = recorded(|| {
+            put("Requests", Unit::Count, 1.0);
+            count_error(ErrorCode::InsufficientFunds);
+        })
+        .await;
+
+        assert_eq!(output.len(), 2);
+        assert_eq!(output[0]["Route"], "Unrouted");
+        assert_eq!(output[1]["ErrorCode"], "INSUFFICIENT_FUNDS");
+        assert_eq!(output[1]["Errors"], 1.0);
+        assert_eq!(output[1]["_aws"]["CloudWatchMetrics"][0]["Dimensions"], json!([["Route", "ErrorCode"]]));
+    }
+
+    #[tokio::test]
+    async fn should_keep_every_value_of_repeated_metric() {
+        let output = recorded(|| {
+            put_with(vec![("Operation", "GetItem".to_string())], "DynamoDbLatency", Unit::Milliseconds, 3.0);
+            put_with(vec![("Operation", "GetItem".to_string())], "DynamoDbLatency", Unit::Milliseconds, 5.0);
+        })
+        .await;
+
+        assert_eq!(output.len(), 1);
+        assert_eq!(output[0]["DynamoDbLatency"], json!([3.0, 5.0]));
+    }
+
+    #[test]
+    fn should_drop_metrics_outside_request() {
+        put("Requests", Unit::Count, 1.0);
+    }
+
+    #[tokio::test]
+    async fn should_write_metrics_once_future_has_finished() {
+        // Given
+        let mut out = Vec::new();
+
+        // When
+        let output = scope_writing_to(&mut out, async {
+            put("Requests", Unit::Count, 1.0);
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+            set_route("ReadAccount".to_string());
+            "done"
+        })
+        .await;
+
+        // Then
+        assert_eq!(output, "done");
+        let document: Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(document["Route"], "ReadAccount");
+        assert_eq!(document["Requests"], 1.0);
+    }
+
+    #[tokio::test]
+    async fn should_print_metrics() {
+        let output = scope(async {
+            put("Requests", Unit::Count, 1.0);
+            "done"
+        })
+        .await;
+
+        assert_eq!(output, "done");
+    }
+
+    /// Records metrics as while handling a request, then reads back what was written.
+    async fn recorded<F: FnOnce()>(record: F) -> Vec<Value> {
+        let mut out = Vec::new();
+        scope_writing_to(&mut out, async { record() }).await;
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+}
@@ -0,0 +1,141 @@
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Server,
+};
+use lambda_http::{lambda_runtime::Context, lambda_runtime::Error, Body, Request, RequestExt, Response};
+use std::{collections::HashMap, convert::Infallible, net::SocketAddr, sync::Arc, time::SystemTime};
+use uuid::Uuid;
+
+use super::RequestHandler;
+
+/// How long API Gateway gives the lambda, see Timeout in template.yaml.
+const TIMEOUT_MILLIS: u64 = 15_000;
+
+/// Serves requests over plain HTTP until stopped, standing in for API Gateway and
+/// the lambda runtime by translating each request into what the lambda would be given.
+pub async fn serve(handler: RequestHandler, port: u16) -> Result<(), Error> {
+    let handler = Arc::new(handler);
+    let make_service = make_service_fn(move |_conn| {
+        let handler = handler.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let handler = handler.clone();
+                async move { Ok::<_, Infallible>(handle(&handler, request).await) }
+            }))
+        }
+    });
+
+    let address = SocketAddr::from(([127, 0, 0, 1], port));
+    Server::bind(&address).serve(make_service).await?;
+    Ok(())
+}
+
+async fn handle(handler: &RequestHandler, request: hyper::Request<hyper::Body>) -> hyper::Response<hyper::Body> {
+    let request = match to_lambda_request(request).await {
+        Ok(request) => request,
+        Err(err) => return internal_server_error(err.into()),
+    };
+    match handler.handle_request(request, new_context()).await {
+        Ok(response) => to_hyper_response(response),
+        Err(err) => internal_server_error(err),
+    }
+}
+
+/// Every request is passed to the lambda, which finds the route itself as it does behind API Gateway.
+async fn to_lambda_request(request: hyper::Request<hyper::Body>) -> Result<Request, hyper::Error> {
+    let (parts, body) = request.into_parts();
+    let bytes = hyper::body::to_bytes(body).await?;
+    let body = if bytes.is_empty() {
+        Body::Empty
+    } else {
+        match String::from_utf8(bytes.to_vec()) {
+            Ok(text) => Body::Text(text),
+            Err(err) => Body::Binary(err.into_bytes()),
+        }
+    };
+
+    let query_string_parameters = parse_query(parts.uri.query().unwrap_or_default());
+    Ok(Request::from_parts(parts, body).with_query_string_parameters(query_string_parameters))
+}
+
+fn parse_query(query: &str) -> HashMap<String, Vec<String>> {
+    let mut parameters: HashMap<String, Vec<String>> = HashMap::new();
+    for (name, value) in form_urlencoded::parse(query.as_bytes()) {
+        parameters.entry(name.into_owned()).or_default().push(value.into_owned());
+    }
+    parameters
+}
+
+fn new_context() -> Context {
+    let now_millis = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_millis() as u64)
+        .unwrap_or_default();
+    let mut ctx = Context::default();
+    ctx.request_id = Uuid::new_v4().to_string();
+    ctx.deadline = now_millis + TIMEOUT_MILLIS;
+    ctx
+}
+
+fn to_hyper_response(response: Response<Body>) -> hyper::Response<hyper::Body> {
+    let (parts, body) = response.into_parts();
+    let body = match body {
+        Body::Empty => hyper::Body::empty(),
+        Body::Text(text) => hyper::Body::from(text),
+        Body::Binary(bytes) => hyper::Body::from(bytes),
+    };
+    hyper::Response::from_parts(parts, body)
+}
+
+/// Responds as API Gateway does when the lambda fails.
+fn internal_server_error(err: Error) -> hyper::Response<hyper::Body> {
+    log::error!("{}", err);
+    json_response(
+        http::StatusCode::INTERNAL_SERVER_ERROR,
+        r#"{"message":"Internal server error"}"#,
+    )
+}
+
+fn json_response(status_code: http::StatusCode, body: &'static str) -> hyper::Response<hyper::Body> {
+    let mut response = hyper::Response::new(hyper::Body::from(body));
+    *response.status_mut() = status_code;
+    response.headers_mut().insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_query, to_lambda_request};
+    use lambda_http::{Body, RequestExt};
+
+    #[test]
+    fn should_decode_query_parameters() {
+        let parameters = parse_query("limit=10&nextToken=a%2Eb");
+        assert_eq!(parameters["limit"], ["10"]);
+        assert_eq!(parameters["nextToken"], ["a.b"]);
+    }
+
+    #[tokio::test]
+    async fn should_translate_request() {
+        // Given
+        let request = hyper::Request::builder()
+            .method("POST")
+            .uri("/account/fred/balance?dryRun=true")
+            .header("Content-Type", "application/json")
+            .body(hyper::Body::from(r#"{"amount":1}"#))
+            .expect("could not build request");
+
+        // When
+        let request = to_lambda_request(request)
+            .await
+            .expect("could not translate request");
+
+        // Then
+        assert_eq!(request.uri().path(), "/account/fred/balance");
+        assert_eq!(request.query_string_parameters().get("dryRun"), Some("true"));
+        assert!(matches!(request.body(), Body::Text(text) if text == r#"{"amount":1}"#));
+    }
+}
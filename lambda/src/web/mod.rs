@@ -1,11 +1,19 @@
 use crate::account::AccountService;
+use crate::xray::Daemon;
+
+pub mod local_server;
 
 mod request_handler;
 pub use request_handler::RequestHandler;
 
 mod request_router;
+mod route;
 use request_router::RequestRouter;
 
-pub fn create_request_handler(account_service: AccountService) -> RequestHandler {
-    RequestHandler::new(RequestRouter::new(account_service))
+pub fn create_request_handler(account_service: AccountService, xray_daemon: Option<Daemon>) -> RequestHandler {
+    let handler = RequestHandler::new(RequestRouter::new(account_service));
+    match xray_daemon {
+        Some(daemon) => handler.with_xray_daemon(daemon),
+        None => handler,
+    }
 }
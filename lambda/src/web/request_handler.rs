@@ -1,86 +1,232 @@
 use http::StatusCode;
-use lambda_http::{lambda_runtime::Context, lambda_runtime::Error, Body, Request, Response};
+use lambda_http::{
+    lambda_runtime::Context, lambda_runtime::Error, request::RequestContext, Body, Request, Response,
+};
 use serde::Serialize;
+use std::time::Instant;
 
 use super::request_router::RequestRouter;
-use crate::AppError;
+use super::route::allow_header;
+use crate::deadline;
+use crate::error::{AppError, ErrorCode, FieldError};
+use crate::logging::{self, RequestFields};
+use crate::metrics::{self, Unit};
+use crate::xray::{self, Daemon, TraceHeader};
+
+const PROBLEM_JSON: &str = "application/problem+json";
+/// Seconds a client is asked to wait before retrying when a service is unavailable.
+const RETRY_AFTER_SECS: &str = "1";
 
 /// The [`RequestHandler`] component routes a request then handles
-/// any business error by converting it a JSON response to client..
+/// any business error by converting it a problem+json response to client.
 pub struct RequestHandler {
     router: RequestRouter,
+    xray_daemon: Option<Daemon>,
 }
 
 impl RequestHandler {
     pub fn new(router: RequestRouter) -> Self {
-        Self { router }
+        Self {
+            router,
+            xray_daemon: None,
+        }
+    }
+
+    /// Traces requests which are sampled, sending subsegments to the daemon.
+    pub fn with_xray_daemon(mut self, daemon: Daemon) -> Self {
+        self.xray_daemon = Some(daemon);
+        self
     }
 
     /// Routes request to handling function, handles
-    /// any error by converting it a JSON response to client.
+    /// any error by converting it a problem+json response to client.
+    /// A request not finished shortly before the invocation deadline gets a 504 response.
+    ///
+    /// Every line logged while handling the request carries its ids, and the last its
+    /// status and latency. The metrics recorded are written when it ends, and when the request
+    /// is traced it is a subsegment of the invocation.
     pub async fn handle_request(
         &self,
         request: Request,
         ctx: Context,
     ) -> Result<Response<Body>, Error> {
-        log::info!(
-            "requestId:{} request start {} {}",
-            ctx.request_id,
-            request.method(),
-            request.uri().path()
-        );
-
-        match self.router.route(request).await {
-            Ok(response) => {
-                log::info!("requestId:{} request end", ctx.request_id);
-                Ok(response)
+        let fields = RequestFields::new(&ctx.request_id, api_request_id(&request), &ctx.xray_trace_id);
+        let trace_header = trace_header(&request, &ctx);
+        let handled = async {
+            let started = Instant::now();
+            log::info!("request start {} {}", request.method(), request.uri().path());
+
+            let result = self.respond(request, &ctx).await;
+
+            let status = match &result {
+                Ok(response) => response.status(),
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            let latency = started.elapsed();
+            logging::annotate(|fields| {
+                fields.status = Some(status.as_u16());
+                fields.latency_ms = Some(latency.as_millis() as u64);
+            });
+            metrics::put("Requests", Unit::Count, 1.0);
+            metrics::put("Latency", Unit::Milliseconds, latency.as_secs_f64() * 1000.0);
+            if result.is_err() {
+                metrics::put("InternalErrors", Unit::Count, 1.0);
             }
+            match &result {
+                // Logged here, as the Lambda Runtime does not log through the log crate.
+                Err(error) => log::error!("request end: {}", error),
+                Ok(_) => log::info!("request end"),
+            }
+            result
+        };
+        let handled = xray::subsegment("RequestHandler::handle_request", handled);
+        logging::scope(fields, metrics::scope(xray::scope(trace_header, self.xray_daemon.clone(), handled))).await
+    }
+
+    async fn respond(&self, request: Request, ctx: &Context) -> Result<Response<Body>, Error> {
+        let path = request.uri().path().to_string();
+
+        let result = match deadline::run_until(deadline::budget(ctx.deadline), self.router.route(request)).await {
+            Ok(result) => result,
+            Err(_elapsed) => {
+                // Respond while there is still time, rather than have Lambda stop the function without one.
+                log::warn!("request timed out before the invocation deadline");
+                return serialise_problem_to_json(
+                    ErrorCode::DeadlineExceeded,
+                    "the request did not finish in time".to_string(),
+                    &ctx.request_id,
+                    vec![],
+                );
+            }
+        };
+
+        match result {
+            Ok(response) => Ok(response),
             Err(app_err) => match app_err {
                 AppError::Internal(error) => {
                     // Pass through to Lambda Runtime so that it is logged and a 500 sent to the client.
                     Err(error)
                 }
-                AppError::Business(message, status_code) => {
-                    // Convert business rule violations into JSON error message for client.
-                    log::info!(
-                        "requestId:{} client error: {} {}",
-                        ctx.request_id,
-                        status_code,
-                        message
-                    );
-                    serialise_error_to_json(status_code, message)
+                AppError::Unavailable(error) => {
+                    // Already retried, so the client is asked to try again later rather than told it failed.
+                    log::warn!("unavailable: {}", error);
+                    let mut response = serialise_problem_to_json(
+                        ErrorCode::ServiceUnavailable,
+                        "the service is busy, please try again later".to_string(),
+                        &ctx.request_id,
+                        vec![],
+                    )?;
+                    response
+                        .headers_mut()
+                        .insert(http::header::RETRY_AFTER, http::HeaderValue::from_static(RETRY_AFTER_SECS));
+                    Ok(response)
+                }
+                AppError::Business(code, message) => {
+                    // Convert business rule violations into problem details for client.
+                    log::info!("client error: {}", message);
+                    let mut response = serialise_problem_to_json(code, message, &ctx.request_id, vec![])?;
+                    if code == ErrorCode::MethodNotAllowed {
+                        if let Some(allow) = allow_header(&path) {
+                            response.headers_mut().insert(http::header::ALLOW, allow.parse()?);
+                        }
+                    }
+                    Ok(response)
+                }
+                AppError::Validation(errors) => {
+                    // Report every value at fault, so the client can correct them all at once.
+                    log::info!("client error: {:?}", errors);
+                    let detail = format!("{} values are not valid", errors.len());
+                    serialise_problem_to_json(ErrorCode::ValidationFailed, detail, &ctx.request_id, errors)
                 }
             },
         }
     }
 }
 
-fn serialise_error_to_json(status_code: StatusCode, message: String) -> Result<Response<Body>, Error> {
-    let body = serde_json::to_string(&ErrorDetails { error: message })?;
+/// The id API Gateway gave the request, which is in its access logs.
+fn api_request_id(request: &Request) -> Option<String> {
+    match request.extensions().get::<RequestContext>()? {
+        RequestContext::ApiGateway(context) => Some(context.request_id.clone()),
+        RequestContext::ApiGatewayV2(context) => Some(context.request_id.clone()),
+        RequestContext::Alb(_) => None,
+    }
+}
+
+/// Lambda gives the trace of the invocation, and sets it in the environment. Otherwise,
+/// as with the local server, the client may have sent one.
+fn trace_header(request: &Request, ctx: &Context) -> Option<TraceHeader> {
+    let header = match ctx.xray_trace_id.as_str() {
+        "" => request
+            .headers()
+            .get(xray::TRACE_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+            .or_else(|| std::env::var(xray::TRACE_ID_ENV).ok())?,
+        trace_id => trace_id.to_string(),
+    };
+    TraceHeader::parse(&header)
+}
+
+/// Describes the error as RFC 7807 problem details.
+fn serialise_problem_to_json(
+    code: ErrorCode,
+    detail: String,
+    request_id: &str,
+    errors: Vec<FieldError>,
+) -> Result<Response<Body>, Error> {
+    let problem = Problem {
+        problem_type: code.type_uri(),
+        title: code.title(),
+        status: code.status().as_u16(),
+        detail,
+        instance: request_id.to_string(),
+        code,
+        errors,
+    };
+    let body = serde_json::to_string(&problem)?;
+    logging::annotate(|fields| fields.error_code = Some(code));
+    metrics::count_error(code);
 
     Ok(Response::builder()
-        .status(status_code)
+        .status(code.status())
         .header(
             http::header::CONTENT_TYPE,
-            http::HeaderValue::from_static("application/json"),
+            http::HeaderValue::from_static(PROBLEM_JSON),
         )
         .body(Body::Text(body))?)
 }
 
 /// Used to serialise JSON describing an error.
-#[derive(Debug, Serialize, Default)]
-struct ErrorDetails {
-    #[serde(default)]
-    error: String,
+#[derive(Debug, Serialize)]
+struct Problem {
+    #[serde(rename = "type")]
+    problem_type: String,
+    title: &'static str,
+    status: u16,
+    detail: String,
+    /// The request id, which identifies this occurrence in the logs.
+    instance: String,
+    code: ErrorCode,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<FieldError>,
 }
 
 #[cfg(test)]
 mod test {
     use super::{RequestHandler, RequestRouter};
+    use async_trait::async_trait;
+    use bigdecimal::BigDecimal;
     use faux::when;
     use http::StatusCode;
     use lambda_http::{lambda_runtime::Context, Body, Request, Response};
-    use crate::AppError;
+    use crate::account::{
+        Account, AccountIds, AccountRepository, AccountService, AccountStatus, BalanceSnapshot, Currency, Hold,
+        HolderPatch, IdempotentRequest, PageTokens, StoredResponse, Transaction,
+    };
+    use crate::config::Limits;
+    use crate::error::{AppError, ErrorCode};
+    use std::sync::{atomic::{AtomicBool, Ordering}, Arc};
+    use std::time::{Duration, SystemTime};
 
     const REQUEST_BODY_TEXT: &str = "{\"accountId\":\"sid\"}";
     const RESPONSE_BODY_TEXT: &str = "{\"balance\":25.10}";
@@ -97,7 +243,7 @@ mod test {
         let handler = RequestHandler::new(router);
 
         let request = request_with_text(REQUEST_BODY_TEXT);
-        let ctx = Context::default();
+        let ctx = context();
 
         // When
         let _ = handler.handle_request(request, ctx).await;
@@ -113,7 +259,7 @@ mod test {
         let handler = RequestHandler::new(router);
 
         let request = request_with_text(REQUEST_BODY_TEXT);
-        let ctx = Context::default();
+        let ctx = context();
 
         // When
         let result = handler.handle_request(request, ctx).await;
@@ -131,14 +277,14 @@ mod test {
         // Given
         let mut router = RequestRouter::faux();
         when!(router.route).then(|_req| {
-            let internal_error = std::io::Error::new(std::io::ErrorKind::Other, "internal error");
+            let internal_error = std::io::Error::other("internal error");
             let wrapped_error = AppError::Internal(Box::new(internal_error));
                 Err(wrapped_error)
         });
         let handler = RequestHandler::new(router);
 
         let request = request_with_text(REQUEST_BODY_TEXT);
-        let ctx = Context::default();
+        let ctx = context();
 
         // When
         let result = handler.handle_request(request, ctx).await;
@@ -153,12 +299,13 @@ mod test {
         // Given
         let mut router = RequestRouter::faux();
         when!(router.route).then(|_unacceptable_request| {
-            Err(AppError::unprocessable("I'm sorry Dave, I'm afraid I can't let you do that"))
+            Err(AppError::business(ErrorCode::InsufficientFunds, "I'm sorry Dave, I'm afraid I can't let you do that"))
         });
         let handler = RequestHandler::new(router);
 
         let request = request_with_text(REQUEST_BODY_TEXT);
-        let ctx = Context::default();
+        let mut ctx = context();
+        ctx.request_id = "request-1".to_string();
 
         // When
         let result = handler.handle_request(request, ctx).await;
@@ -167,11 +314,125 @@ mod test {
         assert!(
             matches!(result, Ok(resp) if
                 matches!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY) &&
-                resp.headers().get("Content-Type").unwrap() == "application/json" &&
-                matches!(resp.body(), Body::Text(txt) if *txt == "{\"error\":\"I'm sorry Dave, I'm afraid I can't let you do that\"}")
+                resp.headers().get("Content-Type").unwrap() == "application/problem+json" &&
+                matches!(resp.body(), Body::Text(txt) if *txt == concat!(
+                    r#"{"type":"urn:rustmonkey:problem:insufficient-funds","title":"Insufficient funds","#,
+                    r#""status":422,"detail":"I'm sorry Dave, I'm afraid I can't let you do that","#,
+                    r#""instance":"request-1","code":"INSUFFICIENT_FUNDS"}"#))
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_list_allowed_methods_when_method_not_allowed() {
+        // Given
+        let mut router = RequestRouter::faux();
+        when!(router.route).then(|_request| {
+            Err(AppError::business(ErrorCode::MethodNotAllowed, "DELETE is not allowed"))
+        });
+        let handler = RequestHandler::new(router);
+
+        let request = http::Request::builder()
+            .method("DELETE")
+            .uri("/account")
+            .body(Body::Empty)
+            .unwrap();
+
+        // When
+        let result = handler.handle_request(request, context()).await;
+
+        // Then
+        assert!(
+            matches!(result, Ok(resp) if
+                matches!(resp.status(), StatusCode::METHOD_NOT_ALLOWED) &&
+                resp.headers().get("Allow").unwrap() == "POST, GET, HEAD, OPTIONS"
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_ask_client_to_retry_when_unavailable() {
+        // Given
+        let mut router = RequestRouter::faux();
+        when!(router.route).then(|_request| Err(AppError::unavailable("throttled")));
+        let handler = RequestHandler::new(router);
+
+        let request = request_with_text(REQUEST_BODY_TEXT);
+
+        // When
+        let result = handler.handle_request(request, context()).await;
+
+        // Then
+        assert!(
+            matches!(result, Ok(resp) if
+                matches!(resp.status(), StatusCode::SERVICE_UNAVAILABLE) &&
+                resp.headers().get("Retry-After").unwrap() == "1" &&
+                matches!(resp.body(), Body::Text(txt) if txt.contains(r#""code":"SERVICE_UNAVAILABLE""#))
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_not_start_request_past_deadline() {
+        // Given
+        let mut router = RequestRouter::faux();
+        when!(router.route).then(|_request| Ok(response_with_text(RESPONSE_BODY_TEXT)));
+        let handler = RequestHandler::new(router);
+
+        let request = request_with_text(REQUEST_BODY_TEXT);
+        let mut ctx = Context::default();
+        ctx.request_id = "request-1".to_string();
+        ctx.deadline = millis_from_now(Duration::from_millis(100));
+
+        // When
+        let result = handler.handle_request(request, ctx).await;
+
+        // Then
+        assert!(
+            matches!(result, Ok(resp) if
+                matches!(resp.status(), StatusCode::GATEWAY_TIMEOUT) &&
+                resp.headers().get("Content-Type").unwrap() == "application/problem+json" &&
+                matches!(resp.body(), Body::Text(txt) if
+                    txt.contains(r#""code":"DEADLINE_EXCEEDED""#) && txt.contains(r#""instance":"request-1""#))
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_give_up_on_request_not_finished_by_deadline() {
+        // Given
+        let repository = SlowRepository::default();
+        let started = repository.started.clone();
+        let service = AccountService::new(
+            Box::new(repository), Limits::default(), PageTokens::new(b"secret"), AccountIds::new("acc_"));
+        let handler = RequestHandler::new(RequestRouter::new(service));
+
+        let mut request = Request::new(Body::Empty);
+        *request.uri_mut() = "/account/fred".parse().unwrap();
+        let mut ctx = Context::default();
+        ctx.request_id = "request-1".to_string();
+        ctx.deadline = millis_from_now(Duration::from_millis(700));
+
+        // When
+        let result = handler.handle_request(request, ctx).await;
+
+        // Then
+        assert!(started.load(Ordering::SeqCst));
+        assert!(
+            matches!(result, Ok(resp) if
+                matches!(resp.status(), StatusCode::GATEWAY_TIMEOUT) &&
+                matches!(resp.body(), Body::Text(txt) if
+                    txt.contains(r#""code":"DEADLINE_EXCEEDED""#) && txt.contains(r#""instance":"request-1""#))
         ));
     }
 
+    /// Lambda always gives a deadline, and a request past its deadline is not routed.
+    fn context() -> Context {
+        let mut ctx = Context::default();
+        ctx.deadline = millis_from_now(Duration::from_secs(60));
+        ctx
+    }
+
+    fn millis_from_now(duration: Duration) -> u64 {
+        (SystemTime::now() + duration).duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64
+    }
+
     fn request_with_text(text: &str) -> Request {
         Request::new(Body::Text(text.to_string()))
     }
@@ -179,4 +440,56 @@ mod test {
     fn response_with_text(text: &str) -> Response<Body> {
         Response::builder().body(Body::Text(text.to_string())).unwrap()
     }
+
+    /// Reading an account, which first looks for expired holds, takes longer than any request is given, and nothing else is expected of it.
+    #[derive(Default)]
+    struct SlowRepository {
+        started: Arc<AtomicBool>,
+    }
+
+    #[async_trait]
+    impl AccountRepository for SlowRepository {
+        async fn list_expired_holds(&self, _account_id: &str, _now: i64) -> Result<Vec<Hold>, AppError> {
+            self.started.store(true, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(vec![])
+        }
+
+        async fn adjust_account(&self, _account_id: String, _amount: BigDecimal, _currency: Currency,
+            _description: Option<String>, _idempotent_request: Option<&IdempotentRequest>,
+            _expected_version: Option<u64>) -> Result<BigDecimal, AppError> { unexpected() }
+        async fn transfer(&self, _from_account_id: String, _to_account_id: String, _amount: BigDecimal,
+            _currency: Currency, _description: Option<String>) -> Result<(), AppError> { unexpected() }
+        async fn create_account(&self, _account: Account) -> Result<(), AppError> { unexpected() }
+        async fn list_accounts(&self, _limit: i32, _start_after: Option<String>)
+            -> Result<(Vec<Account>, Option<String>), AppError> { unexpected() }
+        async fn update_overdraft_limit(&self, _account_id: String, _overdraft_limit: BigDecimal,
+            _expected_version: Option<u64>) -> Result<(), AppError> { unexpected() }
+        async fn update_status(&self, _account_id: String, _from: AccountStatus, _to: AccountStatus,
+            _expected_version: Option<u64>) -> Result<(), AppError> { unexpected() }
+        async fn update_holder(&self, _account_id: String, _patch: &HolderPatch, _expected_version: Option<u64>)
+            -> Result<Account, AppError> { unexpected() }
+        async fn create_hold(&self, _account_id: String, _hold: &Hold, _expected_version: Option<u64>)
+            -> Result<(), AppError> { unexpected() }
+        async fn capture_hold(&self, _account_id: String, _hold: &Hold, _now: i64, _expected_version: Option<u64>)
+            -> Result<BigDecimal, AppError> { unexpected() }
+        async fn release_hold(&self, _account_id: String, _hold: &Hold, _expected_version: Option<u64>)
+            -> Result<(), AppError> { unexpected() }
+        async fn read_hold(&self, _account_id: &str, _hold_id: &str) -> Result<Option<Hold>, AppError> { unexpected() }
+        async fn read_account(&self, _account_id: String) -> Result<Account, AppError> { unexpected() }
+        async fn list_transactions(&self, _account_id: String, _limit: i32, _start_after: Option<u64>)
+            -> Result<(Vec<Transaction>, Option<u64>), AppError> { unexpected() }
+        async fn list_transactions_after(&self, _account_id: &str, _sequence: u64, _limit: i32)
+            -> Result<Vec<Transaction>, AppError> { unexpected() }
+        async fn read_balance_snapshot(&self, _account_id: &str, _at_or_before: &str)
+            -> Result<Option<BalanceSnapshot>, AppError> { unexpected() }
+        async fn read_idempotency_record(&self, _key: &str, _now: i64)
+            -> Result<Option<(String, StoredResponse)>, AppError> { unexpected() }
+        async fn remember_response(&self, _request: &IdempotentRequest, _response: StoredResponse)
+            -> Result<(), AppError> { unexpected() }
+    }
+
+    fn unexpected<T>() -> Result<T, AppError> {
+        unreachable!("only reading an account is expected")
+    }
 }
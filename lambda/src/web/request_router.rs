@@ -1,9 +1,21 @@
+use chrono::{DateTime, Utc};
 use http::{Method, StatusCode};
 use lambda_http::{Body, Request, RequestExt, Response};
 use serde::{Deserialize, Serialize};
+use serde_path_to_error::Segment;
 
-use crate::account::AccountService;
-use crate::AppError;
+use super::route::{allow_header, match_route, Operation, PathParameters, RouteMatch};
+use crate::account::{
+    Account, AccountService, Adjustment, HolderPatch, NewHold, OverdraftLimit, StoredResponse, Transfer,
+};
+use crate::error::{AppError, ErrorCode, FieldError};
+use crate::logging;
+use crate::metrics;
+use crate::xray;
+
+const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 255;
+const MERGE_PATCH_JSON: &str = "application/merge-patch+json";
 
 /// The [`RequestRouter`] component routes a request to its handling code,
 /// deals with the deserialisation of the incoming parameters and payload, and
@@ -22,50 +34,359 @@ impl RequestRouter {
         Self { account_service }
     }
 
-    /// Routes request to handling code.
-    /// Deserialises JSON payload and serialises response.    
+    /// Routes request to handling code, by the method and path in the [`route`](super::route) table.
+    /// Deserialises JSON payload and serialises response.
+    /// OPTIONS is answered with the methods allowed, and HEAD as GET without the body.
     pub async fn route(&self, request: Request) -> Result<Response<Body>, AppError> {
-        let path = request.uri().path().trim_end_matches('/');
+        xray::subsegment("RequestRouter::route", async {
+            let method = request.method().clone();
+            let route_method = if method == Method::HEAD { Method::GET } else { method.clone() };
+
+            match match_route(&route_method, request.uri().path()) {
+                RouteMatch::Found(operation, parameters) => {
+                    annotate_route(operation, &parameters);
+                    let response = self.dispatch(operation, &parameters, request).await?;
+                    if method == Method::HEAD {
+                        Ok(without_body(response))
+                    } else {
+                        Ok(response)
+                    }
+                }
+                RouteMatch::MethodNotAllowed if method == Method::OPTIONS => options_response(request.uri().path()),
+                RouteMatch::MethodNotAllowed => Err(AppError::business_s(
+                    ErrorCode::MethodNotAllowed,
+                    format!("{} is not allowed", method),
+                )),
+                RouteMatch::NotFound => Err(AppError::business(ErrorCode::RouteNotFound, "not found")),
+            }
+        })
+        .await
+    }
 
-        // Distinguishes between events defined in template.yaml
-        if path.ends_with("/balance") {
-            let account_id = get_account_id(&request)?;
-            to_json_ok(
+    async fn dispatch(
+        &self,
+        operation: Operation,
+        parameters: &PathParameters,
+        request: Request,
+    ) -> Result<Response<Body>, AppError> {
+        match operation {
+            Operation::AdjustBalance => {
+                let account_id = get_account_id(parameters)?;
+                let idempotency_key = get_idempotency_key(&request)?;
+                let expected_version = get_if_match(&request)?;
+                let adjustment: Adjustment = from_payload(request)?;
+                match idempotency_key {
+                    Some(key) => to_stored_response(
+                        self.account_service
+                            .adjust_balance_once(account_id, adjustment, key, expected_version)
+                            .await?,
+                    ),
+                    None => to_json_ok(
+                        self.account_service
+                            .adjust_balance(account_id, adjustment, expected_version)
+                            .await?,
+                    ),
+                }
+            }
+            Operation::ReadBalance => {
+                let account_id = get_account_id(parameters)?;
+                let as_of = get_as_of(&request)?;
+                to_json_ok(self.account_service.read_balance_as_of(account_id, as_of).await?)
+            }
+            Operation::Freeze => {
                 self.account_service
-                    .adjust_balance(account_id, from_payload(request)?)
-                    .await?,
-            )
-        } else if request.method() == Method::POST {
-            self.account_service
-                .create_account(from_payload(request)?)
-                .await?;
-            empty_created_response()
-        } else {
-            let account_id = get_account_id(&request)?;
-            to_json_ok(self.account_service.read_account(account_id).await?)
+                    .freeze(get_account_id(parameters)?, get_if_match(&request)?)
+                    .await?;
+                empty_no_content_response()
+            }
+            Operation::Unfreeze => {
+                self.account_service
+                    .unfreeze(get_account_id(parameters)?, get_if_match(&request)?)
+                    .await?;
+                empty_no_content_response()
+            }
+            Operation::Close => {
+                self.account_service
+                    .close(get_account_id(parameters)?, get_if_match(&request)?)
+                    .await?;
+                empty_no_content_response()
+            }
+            Operation::SetOverdraftLimit => {
+                let account_id = get_account_id(parameters)?;
+                let expected_version = get_if_match(&request)?;
+                let limit: OverdraftLimit = from_payload(request)?;
+                self.account_service
+                    .set_overdraft_limit(account_id, limit, expected_version)
+                    .await?;
+                empty_no_content_response()
+            }
+            Operation::CaptureHold => {
+                let account_id = get_account_id(parameters)?;
+                let hold_id = get_hold_id(parameters)?;
+                let expected_version = get_if_match(&request)?;
+                to_json_ok(
+                    self.account_service
+                        .capture_hold(account_id, hold_id, expected_version)
+                        .await?,
+                )
+            }
+            Operation::ReleaseHold => {
+                let account_id = get_account_id(parameters)?;
+                let hold_id = get_hold_id(parameters)?;
+                let expected_version = get_if_match(&request)?;
+                self.account_service
+                    .release_hold(account_id, hold_id, expected_version)
+                    .await?;
+                empty_no_content_response()
+            }
+            Operation::CreateHold => {
+                let account_id = get_account_id(parameters)?;
+                let expected_version = get_if_match(&request)?;
+                let new_hold: NewHold = from_payload(request)?;
+                to_json(
+                    StatusCode::CREATED,
+                    self.account_service
+                        .create_hold(account_id, new_hold, expected_version)
+                        .await?,
+                )
+            }
+            Operation::ListTransactions => {
+                let account_id = get_account_id(parameters)?;
+                let limit = get_limit(&request)?;
+                let next_token = get_query_parameter(&request, "nextToken");
+                to_json_ok(
+                    self.account_service
+                        .list_transactions(account_id, limit, next_token)
+                        .await?,
+                )
+            }
+            Operation::Transfer => {
+                let transfer: Transfer = from_payload(request)?;
+                self.account_service.transfer(transfer).await?;
+                empty_no_content_response()
+            }
+            Operation::ListAccounts => {
+                let limit = get_limit(&request)?;
+                let next_token = get_query_parameter(&request, "nextToken");
+                to_json_ok(self.account_service.list_accounts(limit, next_token).await?)
+            }
+            Operation::CreateAccount => {
+                let account = self.account_service.create_account(from_payload(request)?).await?;
+                with_etag(created_response(&account)?, &account)
+            }
+            Operation::ReadAccount => {
+                let account_id = get_account_id(parameters)?;
+                let account = self.account_service.read_account(account_id).await?;
+                if is_none_match(&request, &entity_tag(account.version())) {
+                    with_etag(empty_not_modified_response()?, &account)
+                } else {
+                    with_etag(to_json_ok(&account)?, &account)
+                }
+            }
+            Operation::UpdateHolder => {
+                let account_id = get_account_id(parameters)?;
+                let expected_version = get_if_match(&request)?;
+                let patch: HolderPatch = from_merge_patch(request)?;
+                let account = self
+                    .account_service
+                    .update_holder(account_id, patch, expected_version)
+                    .await?;
+                with_etag(to_json_ok(&account)?, &account)
+            }
         }
     }
 }
 
-fn get_account_id(request: &Request) -> Result<String, AppError> {
-    Ok(request
-        .path_parameters()
+fn get_account_id(parameters: &PathParameters) -> Result<String, AppError> {
+    Ok(parameters
         .get("accountId")
-        .ok_or_else(|| AppError::bad_request_str("missing account id"))?
+        .ok_or_else(|| AppError::invalid_parameter("missing account id"))?
+        .to_string())
+}
+
+fn get_hold_id(parameters: &PathParameters) -> Result<String, AppError> {
+    Ok(parameters
+        .get("holdId")
+        .ok_or_else(|| AppError::invalid_parameter("missing hold id"))?
         .to_string())
 }
 
+/// Clients send this header so that retrying a request does not repeat its effect.
+fn get_idempotency_key(request: &Request) -> Result<Option<String>, AppError> {
+    match request.headers().get(IDEMPOTENCY_KEY) {
+        Some(value) => {
+            let key = value
+                .to_str()
+                .map_err(|_err| AppError::invalid_parameter("invalid idempotency key"))?;
+            if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LENGTH {
+                return Err(AppError::business_s(ErrorCode::InvalidParameter, format!(
+                    "idempotency key must be between 1 and {} characters",
+                    MAX_IDEMPOTENCY_KEY_LENGTH
+                )));
+            }
+            Ok(Some(key.to_string()))
+        }
+        None => Ok(None),
+    }
+}
+
+/// The ETag of an account is its version, quoted as an entity tag must be.
+fn entity_tag(version: u64) -> String {
+    format!("\"{}\"", version)
+}
+
+/// Clients send the ETag they last read in If-Match, so that a change is only made to the
+/// account as they saw it. Any version matches `*`, which is the same as not sending it.
+fn get_if_match(request: &Request) -> Result<Option<u64>, AppError> {
+    let value = match request.headers().get(http::header::IF_MATCH) {
+        Some(value) => value
+            .to_str()
+            .map_err(|_err| AppError::invalid_parameter("invalid If-Match header"))?
+            .trim(),
+        None => return Ok(None),
+    };
+    if value == "*" {
+        return Ok(None);
+    }
+    value
+        .strip_prefix('"')
+        .and_then(|tag| tag.strip_suffix('"'))
+        .and_then(|version| version.parse().ok())
+        .map(Some)
+        .ok_or_else(|| AppError::invalid_parameter("If-Match must be a single ETag of the account"))
+}
+
+fn with_etag(mut response: Response<Body>, account: &Account) -> Result<Response<Body>, AppError> {
+    let etag = http::HeaderValue::from_str(&entity_tag(account.version()))
+        .map_err(|_err| AppError::internal("invalid ETag"))?;
+    response.headers_mut().insert(http::header::ETAG, etag);
+    Ok(response)
+}
+
+/// True when the client already has the current version, from any of the ETags in If-None-Match.
+/// Weak tags are compared as if they were strong, as RFC 9110 asks.
+fn is_none_match(request: &Request, etag: &str) -> bool {
+    request
+        .headers()
+        .get_all(http::header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+fn get_query_parameter(request: &Request, name: &str) -> Option<String> {
+    request
+        .query_string_parameters()
+        .get(name)
+        .map(|value| value.to_string())
+}
+
+fn get_limit(request: &Request) -> Result<Option<i32>, AppError> {
+    get_query_parameter(request, "limit")
+        .map(|limit| limit.parse())
+        .transpose()
+        .map_err(|_err| AppError::invalid_parameter("limit must be a number"))
+}
+
+/// The time a past balance is asked for, which must be given with its offset from UTC.
+fn get_as_of(request: &Request) -> Result<DateTime<Utc>, AppError> {
+    let as_of = get_query_parameter(request, "asOf").ok_or_else(|| AppError::invalid_parameter("asOf is required"))?;
+    DateTime::parse_from_rfc3339(&as_of)
+        .map(|as_of| as_of.with_timezone(&Utc))
+        .map_err(|_err| AppError::invalid_parameter("asOf must be an RFC 3339 date and time, e.g. 2024-01-31T23:59:59Z"))
+}
+
 /// Deserialises payload into the expected type.
-fn from_payload<'a, D>(request: Request) -> Result<D, AppError>
+fn from_payload<D>(request: Request) -> Result<D, AppError>
 where
     for<'de> D: Deserialize<'de>,
 {
+    if content_type(&request).starts_with("application/json") {
+        return from_json(request.body().as_ref());
+    }
     let result = request.payload();
     let op_payload = result.map_err(|payload_err| {
-        AppError::bad_request(format!("invalid payload: {}", payload_err.to_string()))
+        AppError::business_s(ErrorCode::InvalidPayload, format!("invalid payload: {}", payload_err))
+    })?;
+    let payload = op_payload.ok_or_else(|| AppError::business(ErrorCode::InvalidPayload, "missing payload"))?;
+    Ok(payload)
+}
+
+/// Deserialises a JSON Merge Patch payload, which lambda_http only reads when sent as plain JSON.
+fn from_merge_patch<D>(request: Request) -> Result<D, AppError>
+where
+    for<'de> D: Deserialize<'de>,
+{
+    let content_type = content_type(&request);
+    if !content_type.starts_with(MERGE_PATCH_JSON) && !content_type.starts_with("application/json") {
+        return Err(AppError::business_s(
+            ErrorCode::InvalidPayload,
+            format!("payload must be {}", MERGE_PATCH_JSON),
+        ));
+    }
+    from_json(request.body().as_ref())
+}
+
+fn content_type(request: &Request) -> &str {
+    request
+        .headers()
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+}
+
+/// JSON which is well formed but not the shape expected is reported as a validation error, found
+/// by a JSON pointer, the same as a value which breaks a rule.
+fn from_json<D>(json: &[u8]) -> Result<D, AppError>
+where
+    for<'de> D: Deserialize<'de>,
+{
+    let mut deserializer = serde_json::Deserializer::from_slice(json);
+    let payload = serde_path_to_error::deserialize(&mut deserializer).map_err(|path_err| {
+        let pointer = json_pointer(path_err.path());
+        let payload_err = path_err.into_inner();
+        if payload_err.is_data() {
+            AppError::Validation(vec![field_error(pointer, &payload_err)])
+        } else {
+            AppError::business_s(ErrorCode::InvalidPayload, format!("invalid payload: {}", payload_err))
+        }
     })?;
-    let payload = op_payload.ok_or_else(|| AppError::bad_request_str("missing payload"))?;
-    return Ok(payload);
+    deserializer.end().map_err(|payload_err| {
+        AppError::business_s(ErrorCode::InvalidPayload, format!("invalid payload: {}", payload_err))
+    })?;
+    Ok(payload)
+}
+
+/// serde reports a missing field against the object it is missing from, so the pointer is
+/// taken on to the field itself.
+fn field_error(pointer: String, payload_err: &serde_json::Error) -> FieldError {
+    let message = payload_err.to_string();
+    let position = format!(" at line {} column {}", payload_err.line(), payload_err.column());
+    let detail = message.strip_suffix(&position).unwrap_or(&message);
+    match detail.strip_prefix("missing field `").and_then(|field| field.strip_suffix('`')) {
+        Some(field) => FieldError {
+            pointer: format!("{}/{}", pointer, escape_pointer_token(field)),
+            detail: format!("{} is required", field),
+        },
+        None => FieldError { pointer, detail: detail.to_string() },
+    }
+}
+
+/// Writes a path within the payload as a JSON pointer (RFC 6901), the whole payload being the empty pointer.
+fn json_pointer(path: &serde_path_to_error::Path) -> String {
+    path.iter()
+        .map(|segment| match segment {
+            Segment::Seq { index } => format!("/{}", index),
+            Segment::Map { key } | Segment::Enum { variant: key } => format!("/{}", escape_pointer_token(key)),
+            Segment::Unknown => String::new(),
+        })
+        .collect()
+}
+
+fn escape_pointer_token(token: &str) -> String {
+    token.replace('~', "~0").replace('/', "~1")
 }
 
 // Serialise response into a JSON payload response with an 200 OK status.
@@ -92,8 +413,371 @@ where
         .body(Body::Text(body))?)
 }
 
-fn empty_created_response() -> Result<Response<Body>, AppError> {
+/// Recreates a response remembered against an idempotency key.
+fn to_stored_response(stored: StoredResponse) -> Result<Response<Body>, AppError> {
+    let status_code = StatusCode::from_u16(stored.status)
+        .map_err(|_err| AppError::internal("invalid stored status"))?;
+
     Ok(Response::builder()
-        .status(StatusCode::CREATED)
+        .status(status_code)
+        .header(
+            http::header::CONTENT_TYPE,
+            http::HeaderValue::from_static("application/json"),
+        )
+        .body(Body::Text(stored.body))?)
+}
+
+/// The new account with where it can be read from, its id needing no escaping in a path.
+fn created_response(account: &Account) -> Result<Response<Body>, AppError> {
+    let mut response = to_json(StatusCode::CREATED, account)?;
+    let location = http::HeaderValue::from_str(&format!("/account/{}", account.account_id()))
+        .map_err(|_err| AppError::internal("invalid Location"))?;
+    response.headers_mut().insert(http::header::LOCATION, location);
+    Ok(response)
+}
+
+fn empty_not_modified_response() -> Result<Response<Body>, AppError> {
+    Ok(Response::builder()
+        .status(StatusCode::NOT_MODIFIED)
         .body(Body::Empty)?)
 }
+
+fn empty_no_content_response() -> Result<Response<Body>, AppError> {
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::Empty)?)
+}
+
+fn options_response(path: &str) -> Result<Response<Body>, AppError> {
+    let mut response = Response::builder().status(StatusCode::NO_CONTENT);
+    if let Some(allow) = allow_header(path) {
+        response = response.header(http::header::ALLOW, allow);
+    }
+    Ok(response.body(Body::Empty)?)
+}
+
+/// The route and account are added to the logs, metrics and trace of the request.
+fn annotate_route(operation: Operation, parameters: &PathParameters) {
+    let route = format!("{:?}", operation);
+    let account_id = parameters.get("accountId");
+    logging::annotate(|fields| {
+        fields.route = Some(route.clone());
+        fields.account_id = account_id.map(str::to_string);
+    });
+    metrics::set_route(route.clone());
+    xray::annotate("route", route);
+    if let Some(account_id) = account_id {
+        xray::annotate("account_id", account_id);
+    }
+}
+
+fn without_body(response: Response<Body>) -> Response<Body> {
+    let (parts, _body) = response.into_parts();
+    Response::from_parts(parts, Body::Empty)
+}
+
+#[cfg(test)]
+mod test {
+    use super::RequestRouter;
+    use crate::account::{AccountIds, AccountService, InMemoryAccountRepository, PageTokens};
+    use crate::config::Limits;
+    use crate::error::{AppError, ErrorCode, FieldError};
+    use chrono::{FixedOffset, Utc};
+    use http::{Method, StatusCode};
+    use lambda_http::{Body, Request, RequestExt, Response};
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn should_create_then_read_account() {
+        // Given
+        let router = router();
+
+        // When
+        let created = router.route(post("/account", r#"{"accountId":"fred","currency":"GBP","balance":10}"#))
+            .await.expect("could not create account");
+        let read = router.route(get("/account/fred"))
+            .await.expect("could not read account");
+
+        // Then
+        assert_eq!(created.status(), StatusCode::CREATED);
+        assert_eq!(created.headers()["Location"], "/account/fred");
+        assert_eq!(created.headers()["ETag"], r#""1""#);
+        assert_eq!(text(&created), r#"{"accountId":"fred","currency":"GBP","balance":"10","overdraftLimit":"0","availableFunds":"10","status":"ACTIVE","heldAmount":"0"}"#);
+        assert_eq!(read.status(), StatusCode::OK);
+        assert_eq!(text(&read), r#"{"accountId":"fred","currency":"GBP","balance":"10","overdraftLimit":"0","availableFunds":"10","status":"ACTIVE","heldAmount":"0"}"#);
+    }
+
+    #[tokio::test]
+    async fn should_create_account_at_generated_id() {
+        // Given
+        let router = router();
+
+        // When
+        let created = router.route(post("/account", r#"{"currency":"GBP","balance":10}"#))
+            .await.expect("could not create account");
+
+        // Then
+        assert_eq!(created.status(), StatusCode::CREATED);
+        let location = created.headers()["Location"].to_str().expect("no Location").to_string();
+        assert!(location.starts_with("/account/acc_"));
+        let read = router.route(get(&location)).await.expect("could not read account");
+        assert_eq!(text(&read), text(&created));
+    }
+
+    #[tokio::test]
+    async fn should_not_find_unknown_account() {
+        // Given
+        let router = router();
+
+        // When
+        let result = router.route(get("/account/nobody")).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::AccountNotFound, _))));
+    }
+
+    #[tokio::test]
+    async fn should_adjust_balance() {
+        // Given
+        let router = router();
+        router.route(post("/account", r#"{"accountId":"fred","currency":"GBP","balance":10}"#))
+            .await.expect("could not create account");
+
+        // When
+        let adjusted = router.route(post("/account/fred/balance", r#"{"amount":-2.5,"currency":"GBP"}"#))
+            .await.expect("could not adjust balance");
+
+        // Then
+        assert_eq!(adjusted.status(), StatusCode::OK);
+        assert_eq!(text(&adjusted), r#"{"balance":"7.5"}"#);
+    }
+
+    #[tokio::test]
+    async fn should_reject_invalid_payload() {
+        // Given
+        let router = router();
+
+        // When
+        let result = router.route(post("/account", r#"{"accountId":"fred","#)).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::InvalidPayload, _))));
+    }
+
+    #[tokio::test]
+    async fn should_point_to_missing_field_in_payload() {
+        // Given
+        let router = router();
+
+        // When
+        let result = router.route(post("/account", r#"{"accountId":"fred"}"#)).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Validation(errors)) if errors == [FieldError {
+            pointer: "/currency".to_string(),
+            detail: "currency is required".to_string(),
+        }]));
+    }
+
+    #[tokio::test]
+    async fn should_point_to_value_of_wrong_shape_in_payload() {
+        // Given
+        let router = router();
+
+        // When
+        let result = router.route(post("/account", r#"{"accountId":"fred","currency":"XXX","balance":10}"#)).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Validation(errors)) if errors == [FieldError {
+            pointer: "/currency".to_string(),
+            detail: "unknown currency code XXX".to_string(),
+        }]));
+    }
+
+    #[tokio::test]
+    async fn should_not_find_unknown_path() {
+        // Given
+        let router = router();
+
+        // When
+        let result = router.route(get("/account/fred/unknown")).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::RouteNotFound, _))));
+    }
+
+    #[tokio::test]
+    async fn should_not_allow_wrong_method() {
+        // Given
+        let router = router();
+
+        // When
+        let result = router.route(request(Method::DELETE, "/account/fred/balance", Body::Empty)).await;
+
+        // Then
+        assert!(matches!(result, Err(AppError::Business(ErrorCode::MethodNotAllowed, _))));
+    }
+
+    #[tokio::test]
+    async fn should_answer_options_with_allowed_methods() {
+        // Given
+        let router = router();
+
+        // When
+        let response = router.route(request(Method::OPTIONS, "/account", Body::Empty))
+            .await.expect("could not route request");
+
+        // Then
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()[http::header::ALLOW], "POST, GET, HEAD, OPTIONS");
+    }
+
+    #[tokio::test]
+    async fn should_answer_head_as_get_without_body() {
+        // Given
+        let router = router();
+        router.route(post("/account", r#"{"accountId":"fred","currency":"GBP","balance":10}"#))
+            .await.expect("could not create account");
+
+        // When
+        let response = router.route(request(Method::HEAD, "/account/fred", Body::Empty))
+            .await.expect("could not route request");
+
+        // Then
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[http::header::CONTENT_TYPE], "application/json");
+        assert!(matches!(response.body(), Body::Empty));
+    }
+
+    #[tokio::test]
+    async fn should_not_modify_account_when_client_has_current_version() {
+        // Given
+        let router = router();
+        router.route(post("/account", r#"{"accountId":"fred","currency":"GBP","balance":10}"#))
+            .await.expect("could not create account");
+        let read = router.route(get("/account/fred")).await.expect("could not read account");
+        let etag = read.headers()[http::header::ETAG].to_str().expect("invalid ETag").to_string();
+
+        // When
+        let unchanged = router.route(with_header(get("/account/fred"), "If-None-Match", &etag))
+            .await.expect("could not read account");
+        router.route(post("/account/fred/balance", r#"{"amount":1,"currency":"GBP"}"#))
+            .await.expect("could not adjust balance");
+        let changed = router.route(with_header(get("/account/fred"), "If-None-Match", &etag))
+            .await.expect("could not read account");
+
+        // Then
+        assert_eq!(etag, r#""1""#);
+        assert_eq!(unchanged.status(), StatusCode::NOT_MODIFIED);
+        assert!(matches!(unchanged.body(), Body::Empty));
+        assert_eq!(changed.status(), StatusCode::OK);
+        assert_eq!(changed.headers()[http::header::ETAG], r#""2""#);
+    }
+
+    #[tokio::test]
+    async fn should_only_change_account_at_version_in_if_match() {
+        // Given
+        let router = router();
+        router.route(post("/account", r#"{"accountId":"fred","currency":"GBP","balance":10}"#))
+            .await.expect("could not create account");
+
+        // When
+        let current = router.route(with_header(post("/account/fred/freeze", ""), "If-Match", r#""1""#))
+            .await.expect("could not freeze account");
+        let stale = router.route(with_header(post("/account/fred/unfreeze", ""), "If-Match", r#""1""#)).await;
+        let invalid = router.route(with_header(post("/account/fred/unfreeze", ""), "If-Match", "1")).await;
+
+        // Then
+        assert_eq!(current.status(), StatusCode::NO_CONTENT);
+        assert!(matches!(stale, Err(AppError::Business(ErrorCode::PreconditionFailed, _))));
+        assert!(matches!(invalid, Err(AppError::Business(ErrorCode::InvalidParameter, _))));
+    }
+
+    #[tokio::test]
+    async fn should_read_balance_as_of_time_given() {
+        // Given
+        let router = router();
+        router.route(post("/account", r#"{"accountId":"fred","currency":"GBP","balance":10}"#))
+            .await.expect("could not create account");
+
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        let as_of = Utc::now().with_timezone(&FixedOffset::east(3600)).to_rfc3339();
+
+        // When
+        let read = router.route(with_query(get("/account/fred/balance"), "asOf", &as_of))
+            .await.expect("could not read balance");
+        let early = router.route(with_query(get("/account/fred/balance"), "asOf", "2000-01-31T23:59:59+01:00")).await;
+        let future = router.route(with_query(get("/account/fred/balance"), "asOf", "2999-01-31T23:59:59Z")).await;
+        let missing = router.route(get("/account/fred/balance")).await;
+        let invalid = router.route(with_query(get("/account/fred/balance"), "asOf", "yesterday")).await;
+
+        // Then
+        assert_eq!(read.status(), StatusCode::OK);
+        assert!(text(&read).starts_with(r#"{"accountId":"fred","currency":"GBP","balance":"10","asOf":""#));
+        assert!(text(&read).ends_with(r#"Z"}"#));
+        assert!(matches!(early, Err(AppError::Business(ErrorCode::InvalidParameter, message))
+            if message == "asOf is before the account was opened"));
+        assert!(matches!(future, Err(AppError::Business(ErrorCode::InvalidParameter, message))
+            if message == "asOf can not be in the future"));
+        assert!(matches!(missing, Err(AppError::Business(ErrorCode::InvalidParameter, message)) if message == "asOf is required"));
+        assert!(matches!(invalid, Err(AppError::Business(ErrorCode::InvalidParameter, _))));
+    }
+
+    #[tokio::test]
+    async fn should_patch_holder_details() {
+        // Given
+        let router = router();
+        router.route(post("/account", r#"{"accountId":"fred","currency":"GBP","balance":10,"ownerName":"Fred"}"#))
+            .await.expect("could not create account");
+        let patch = request(Method::PATCH, "/account/fred", Body::Text(r#"{"ownerName":null,"tags":["gold"]}"#.to_string()));
+        let patch = with_header(patch, "Content-Type", "application/merge-patch+json");
+
+        // When
+        let patched = router.route(patch).await.expect("could not patch account");
+
+        // Then
+        assert_eq!(patched.status(), StatusCode::OK);
+        assert_eq!(patched.headers()[http::header::ETAG], r#""2""#);
+        assert_eq!(text(&patched), r#"{"accountId":"fred","currency":"GBP","balance":"10","overdraftLimit":"0","availableFunds":"10","status":"ACTIVE","heldAmount":"0","tags":["gold"]}"#);
+    }
+
+    fn router() -> RequestRouter {
+        let repository = Box::new(InMemoryAccountRepository::new());
+        RequestRouter::new(AccountService::new(repository, Limits::default(), PageTokens::new(b"secret"), AccountIds::new("acc_")))
+    }
+
+    fn get(path: &str) -> Request {
+        request(Method::GET, path, Body::Empty)
+    }
+
+    fn post(path: &str, json: &str) -> Request {
+        request(Method::POST, path, Body::Text(json.to_string()))
+    }
+
+    fn request(method: Method, path: &str, body: Body) -> Request {
+        http::Request::builder()
+            .method(method)
+            .uri(path)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .expect("could not build request")
+    }
+
+    fn with_query(request: Request, name: &str, value: &str) -> Request {
+        let mut parameters = HashMap::new();
+        parameters.insert(name.to_string(), vec![value.to_string()]);
+        request.with_query_string_parameters(parameters)
+    }
+
+    fn with_header(mut request: Request, name: &'static str, value: &str) -> Request {
+        request.headers_mut().insert(name, value.parse().expect("invalid header value"));
+        request
+    }
+
+    fn text(response: &Response<Body>) -> &str {
+        match response.body() {
+            Body::Text(text) => text,
+            _ => "",
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use http::Method;
+use percent_encoding::percent_decode_str;
+use std::collections::HashMap;
+
+/// What a request asks to be done, found by matching it against the [`ROUTES`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    CreateAccount,
+    ListAccounts,
+    ReadAccount,
+    UpdateHolder,
+    AdjustBalance,
+    ReadBalance,
+    Freeze,
+    Unfreeze,
+    Close,
+    SetOverdraftLimit,
+    ListTransactions,
+    CreateHold,
+    CaptureHold,
+    ReleaseHold,
+    Transfer,
+}
+
+struct Route {
+    method: Method,
+    template: &'static str,
+    operation: Operation,
+}
+
+/// Every operation of the API. A segment in braces matches any non-empty segment
+/// of a path, which becomes a path parameter of that name.
+const ROUTES: &[Route] = &[
+    route(Method::POST, "/account", Operation::CreateAccount),
+    route(Method::GET, "/account", Operation::ListAccounts),
+    route(Method::GET, "/account/{accountId}", Operation::ReadAccount),
+    route(Method::PATCH, "/account/{accountId}", Operation::UpdateHolder),
+    route(Method::POST, "/account/{accountId}/balance", Operation::AdjustBalance),
+    route(Method::GET, "/account/{accountId}/balance", Operation::ReadBalance),
+    route(Method::POST, "/account/{accountId}/freeze", Operation::Freeze),
+    route(Method::POST, "/account/{accountId}/unfreeze", Operation::Unfreeze),
+    route(Method::POST, "/account/{accountId}/close", Operation::Close),
+    route(Method::PUT, "/account/{accountId}/overdraft-limit", Operation::SetOverdraftLimit),
+    route(Method::GET, "/account/{accountId}/transactions", Operation::ListTransactions),
+    route(Method::POST, "/account/{accountId}/holds", Operation::CreateHold),
+    route(Method::POST, "/account/{accountId}/holds/{holdId}/capture", Operation::CaptureHold),
+    route(Method::POST, "/account/{accountId}/holds/{holdId}/release", Operation::ReleaseHold),
+    route(Method::POST, "/transfer", Operation::Transfer),
+];
+
+const fn route(method: Method, template: &'static str, operation: Operation) -> Route {
+    Route {
+        method,
+        template,
+        operation,
+    }
+}
+
+#[derive(Debug)]
+pub enum RouteMatch {
+    Found(Operation, PathParameters),
+    /// The path is known, but not with this method.
+    MethodNotAllowed,
+    NotFound,
+}
+
+/// The values of the segments in braces, decoded.
+#[derive(Debug, Default)]
+pub struct PathParameters(HashMap<&'static str, String>);
+
+impl PathParameters {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(String::as_str)
+    }
+}
+
+/// Finds the route for a request.
+pub fn match_route(method: &Method, path: &str) -> RouteMatch {
+    let segments = split_path(path);
+    let mut path_known = false;
+    for route in ROUTES {
+        if let Some(parameters) = match_template(route.template, &segments) {
+            if route.method == method {
+                return RouteMatch::Found(route.operation, parameters);
+            }
+            path_known = true;
+        }
+    }
+    if path_known {
+        RouteMatch::MethodNotAllowed
+    } else {
+        RouteMatch::NotFound
+    }
+}
+
+/// The value of the Allow header for a known path, with HEAD and OPTIONS
+/// which are answered for every route.
+pub fn allow_header(path: &str) -> Option<String> {
+    let segments = split_path(path);
+    let mut methods: Vec<&str> = ROUTES
+        .iter()
+        .filter(|route| match_template(route.template, &segments).is_some())
+        .map(|route| route.method.as_str())
+        .collect();
+    if methods.is_empty() {
+        return None;
+    }
+    if methods.contains(&Method::GET.as_str()) {
+        methods.push(Method::HEAD.as_str());
+    }
+    methods.push(Method::OPTIONS.as_str());
+    Some(methods.join(", "))
+}
+
+/// A trailing slash on the path is ignored.
+fn split_path(path: &str) -> Vec<&str> {
+    path.trim_end_matches('/').split('/').collect()
+}
+
+fn match_template(template: &'static str, segments: &[&str]) -> Option<PathParameters> {
+    let template_segments: Vec<&'static str> = template.split('/').collect();
+    if template_segments.len() != segments.len() {
+        return None;
+    }
+    let mut parameters = PathParameters::default();
+    for (template_segment, segment) in template_segments.into_iter().zip(segments) {
+        match template_segment.strip_prefix('{').and_then(|name| name.strip_suffix('}')) {
+            Some(name) if !segment.is_empty() => {
+                let value = percent_decode_str(segment).decode_utf8().ok()?;
+                parameters.0.insert(name, value.into_owned());
+            }
+            None if template_segment == *segment => {}
+            _ => return None,
+        }
+    }
+    Some(parameters)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{allow_header, match_route, Operation, RouteMatch};
+    use http::Method;
+
+    #[test]
+    fn should_fill_in_path_parameters() {
+        let result = match_route(&Method::POST, "/account/fred/holds/H1/capture");
+        assert!(matches!(result, RouteMatch::Found(Operation::CaptureHold, parameters)
+            if parameters.get("accountId") == Some("fred") && parameters.get("holdId") == Some("H1")));
+    }
+
+    #[test]
+    fn should_decode_path_parameters() {
+        let result = match_route(&Method::GET, "/account/fred%20flintstone");
+        assert!(matches!(result, RouteMatch::Found(Operation::ReadAccount, parameters)
+            if parameters.get("accountId") == Some("fred flintstone")));
+    }
+
+    #[test]
+    fn should_match_on_method_as_well_as_path() {
+        assert!(matches!(match_route(&Method::GET, "/account"), RouteMatch::Found(Operation::ListAccounts, _)));
+        assert!(matches!(match_route(&Method::POST, "/account/"), RouteMatch::Found(Operation::CreateAccount, _)));
+    }
+
+    #[test]
+    fn should_not_allow_method_missing_from_path() {
+        assert!(matches!(match_route(&Method::DELETE, "/account/fred"), RouteMatch::MethodNotAllowed));
+    }
+
+    #[test]
+    fn should_list_methods_allowed_for_path() {
+        assert_eq!(allow_header("/account").as_deref(), Some("POST, GET, HEAD, OPTIONS"));
+        assert_eq!(allow_header("/account/fred/balance").as_deref(), Some("POST, GET, HEAD, OPTIONS"));
+        assert_eq!(allow_header("/account/fred/freeze").as_deref(), Some("POST, OPTIONS"));
+        assert_eq!(allow_header("/unknown"), None);
+    }
+
+    #[test]
+    fn should_not_match_unknown_path() {
+        assert!(matches!(match_route(&Method::GET, "/account/fred/unknown"), RouteMatch::NotFound));
+        assert!(matches!(match_route(&Method::GET, "/accounts/fred"), RouteMatch::NotFound));
+        assert!(matches!(match_route(&Method::POST, "/account//balance"), RouteMatch::NotFound));
+    }
+}
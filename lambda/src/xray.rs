@@ -0,0 +1,369 @@
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::{
+    cell::RefCell,
+    future::Future,
+    io,
+    net::{SocketAddr, UdpSocket},
+    pin::Pin,
+    sync::Arc,
+    time::SystemTime,
+};
+
+use crate::error::{AppError, ErrorCode};
+
+/// Sent before each document, as the daemon protocol requires.
+const DAEMON_HEADER: &str = "{\"format\": \"json\", \"version\": 1}";
+/// The environment variable Lambda sets to the trace header of the invocation.
+pub const TRACE_ID_ENV: &str = "_X_AMZN_TRACE_ID";
+/// The header a trace is passed on in over HTTP.
+pub const TRACE_ID_HEADER: &str = "X-Amzn-Trace-Id";
+
+tokio::task_local! {
+    static TRACE: Trace;
+    static PARENT: String;
+}
+
+/// Sends subsegments to the X-Ray daemon over UDP, which passes them on to X-Ray.
+/// Lambda runs a daemon for every function with active tracing, at AWS_XRAY_DAEMON_ADDRESS.
+/// See https://docs.aws.amazon.com/xray/latest/devguide/xray-api-sendingdata.html
+#[derive(Debug, Clone)]
+pub struct Daemon {
+    socket: Arc<UdpSocket>,
+    address: SocketAddr,
+}
+
+/// The trace of the request being handled, which subsegments are added to.
+struct Trace {
+    trace_id: String,
+    daemon: Daemon,
+    /// Added to every subsegment sent after they are known, so that traces can be
+    /// filtered by them whichever subsegment matched.
+    annotations: RefCell<Map<String, Value>>,
+}
+
+/// The parts of a trace header, e.g. `Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct TraceHeader {
+    root: String,
+    parent: Option<String>,
+    sampled: bool,
+}
+
+/// How an operation turned out, which X-Ray shows as the colour of its subsegment.
+pub enum Outcome {
+    Ok,
+    /// Failed because of the request, with the code given to the client.
+    Error(Option<ErrorCode>),
+    /// Failed because of something wrong here or in a service used.
+    Fault,
+}
+
+/// What a traced operation gives back, which can be told apart as success or failure.
+pub trait Traced {
+    fn outcome(&self) -> Outcome;
+}
+
+#[derive(Serialize)]
+struct Subsegment<'a> {
+    name: &'a str,
+    id: String,
+    trace_id: &'a str,
+    parent_id: &'a str,
+    start_time: f64,
+    end_time: f64,
+    #[serde(rename = "type")]
+    segment_type: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    namespace: Option<&'static str>,
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    annotations: Map<String, Value>,
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    aws: Map<String, Value>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    error: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    fault: bool,
+}
+
+impl Daemon {
+    pub fn new(address: SocketAddr) -> io::Result<Self> {
+        let any_port: SocketAddr = if address.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(any_port)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket: Arc::new(socket),
+            address,
+        })
+    }
+
+    /// Tracing should never fail a request, so a document which can not be sent is only logged.
+    fn send(&self, document: &str) {
+        let datagram = format!("{}\n{}", DAEMON_HEADER, document);
+        if let Err(err) = self.socket.send_to(datagram.as_bytes(), self.address) {
+            log::debug!("subsegment not sent to X-Ray daemon at {}: {}", self.address, err);
+        }
+    }
+}
+
+impl TraceHeader {
+    /// Reads the header Lambda gives, or failing that the one the client sent.
+    /// Without a root there is no trace to add to.
+    pub fn parse(header: &str) -> Option<Self> {
+        let mut root = None;
+        let mut parent = None;
+        let mut sampled = false;
+        for part in header.split(';') {
+            match part.trim().split_once('=') {
+                Some(("Root", value)) => root = Some(value.to_string()),
+                Some(("Parent", value)) => parent = Some(value.to_string()),
+                Some(("Sampled", value)) => sampled = value == "1",
+                _ => {}
+            }
+        }
+        Some(Self {
+            root: root?,
+            parent,
+            sampled,
+        })
+    }
+}
+
+/// Handles a request as part of a trace, when the trace is being sampled.
+pub async fn scope<F: Future>(header: Option<TraceHeader>, daemon: Option<Daemon>, future: F) -> F::Output {
+    match (header, daemon) {
+        (Some(header), Some(daemon)) if header.sampled => {
+            let trace = Trace {
+                trace_id: header.root,
+                daemon,
+                annotations: RefCell::new(Map::new()),
+            };
+            // A subsegment needs a parent, which Lambda always gives.
+            let parent = header.parent.unwrap_or_default();
+            TRACE.scope(trace, PARENT.scope(parent, future)).await
+        }
+        _ => future.await,
+    }
+}
+
+/// Adds an annotation, which can be used to filter traces, to the trace of the request being handled.
+pub fn annotate(key: &str, value: impl Into<Value>) {
+    let _ = TRACE.try_with(|trace| trace.annotations.borrow_mut().insert(key.to_string(), value.into()));
+}
+
+/// Records the future as a subsegment of the trace, and of any subsegment it is within.
+///
+/// The future is boxed, as otherwise each subsegment within another would make
+/// the future holding them all larger, until it overflows the stack.
+pub fn subsegment<'a, F>(name: &'a str, future: F) -> impl Future<Output = F::Output> + 'a
+where
+    F: Future + 'a,
+    F::Output: Traced,
+{
+    traced(name, None, Box::pin(future))
+}
+
+/// Records a call to an AWS service, which X-Ray shows as a node of its own.
+pub fn aws_subsegment<'a, F>(service: &'a str, operation: &str, future: F) -> impl Future<Output = F::Output> + 'a
+where
+    F: Future + 'a,
+    F::Output: Traced,
+{
+    let mut aws = Map::new();
+    aws.insert("operation".to_string(), Value::from(operation));
+    traced(service, Some(aws), Box::pin(future))
+}
+
+async fn traced<F>(name: &str, aws: Option<Map<String, Value>>, future: Pin<Box<F>>) -> F::Output
+where
+    F: Future,
+    F::Output: Traced,
+{
+    let parent_id = match PARENT.try_with(String::clone) {
+        Ok(parent_id) if TRACE.try_with(|_| ()).is_ok() => parent_id,
+        _ => return future.await,
+    };
+    let id = new_id();
+    let start_time = epoch_secs();
+    let output = PARENT.scope(id.clone(), future).await;
+    let end_time = epoch_secs();
+
+    let (error, fault) = match output.outcome() {
+        Outcome::Ok => (false, false),
+        Outcome::Error(code) => {
+            if let Some(code) = code {
+                annotate("error_code", serde_json::to_value(code).unwrap_or_default());
+            }
+            (true, false)
+        }
+        Outcome::Fault => (false, true),
+    };
+    let _ = TRACE.try_with(|trace| {
+        let subsegment = Subsegment {
+            name,
+            id,
+            trace_id: &trace.trace_id,
+            parent_id: &parent_id,
+            start_time,
+            end_time,
+            segment_type: "subsegment",
+            namespace: aws.as_ref().map(|_| "aws"),
+            annotations: trace.annotations.borrow().clone(),
+            aws: aws.unwrap_or_default(),
+            error,
+            fault,
+        };
+        if let Ok(document) = serde_json::to_string(&subsegment) {
+            trace.daemon.send(&document);
+        }
+    });
+    output
+}
+
+/// 64 random bits, as 16 hexadecimal digits.
+fn new_id() -> String {
+    let mut bytes = [0u8; 8];
+    let _ = SystemRandom::new().fill(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn epoch_secs() -> f64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs_f64())
+        .unwrap_or_default()
+}
+
+impl<T> Traced for Result<T, AppError> {
+    fn outcome(&self) -> Outcome {
+        match self {
+            Ok(_) => Outcome::Ok,
+            Err(AppError::Business(code, _)) => Outcome::Error(Some(*code)),
+            Err(AppError::Validation(_)) => Outcome::Error(Some(ErrorCode::ValidationFailed)),
+            Err(AppError::Internal(_)) | Err(AppError::Unavailable(_)) => Outcome::Fault,
+        }
+    }
+}
+
+impl<T, E> Traced for Result<T, aws_sdk_dynamodb::SdkError<E>> {
+    fn outcome(&self) -> Outcome {
+        match self {
+            Ok(_) => Outcome::Ok,
+            Err(_) => Outcome::Fault,
+        }
+    }
+}
+
+/// A response with a 4XX status is an error, one with 5XX a fault.
+impl Traced for Result<lambda_http::Response<lambda_http::Body>, lambda_http::lambda_runtime::Error> {
+    fn outcome(&self) -> Outcome {
+        match self {
+            Ok(response) if response.status().is_client_error() => Outcome::Error(None),
+            Ok(response) if response.status().is_server_error() => Outcome::Fault,
+            Ok(_) => Outcome::Ok,
+            Err(_) => Outcome::Fault,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{annotate, aws_subsegment, scope, subsegment, Daemon, TraceHeader};
+    use crate::error::{AppError, ErrorCode};
+    use serde_json::Value;
+    use std::{net::UdpSocket, time::Duration};
+
+    const HEADER: &str = "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1";
+
+    #[test]
+    fn should_parse_trace_header() {
+        let header = TraceHeader::parse(HEADER).unwrap();
+        assert_eq!(header.root, "1-5759e988-bd862e3fe1be46a994272793");
+        assert_eq!(header.parent.as_deref(), Some("53995c3f42cd8ad8"));
+        assert!(header.sampled);
+        assert_eq!(TraceHeader::parse("Parent=53995c3f42cd8ad8;Sampled=1"), None);
+    }
+
+    #[tokio::test]
+    async fn should_send_nested_subsegments_to_daemon() {
+        // Given
+        let (listener, daemon) = local_daemon();
+
+        // When
+        scope(TraceHeader::parse(HEADER), Some(daemon), async {
+            subsegment("handler", async {
+                annotate("account_id", "fred");
+                aws_subsegment("DynamoDB", "GetItem", async { Ok::<(), AppError>(()) }).await
+            })
+            .await
+        })
+        .await
+        .unwrap();
+
+        // Then
+        let inner = receive(&listener);
+        let outer = receive(&listener);
+        assert_eq!(inner["name"], "DynamoDB");
+        assert_eq!(inner["namespace"], "aws");
+        assert_eq!(inner["aws"]["operation"], "GetItem");
+        assert_eq!(inner["trace_id"], "1-5759e988-bd862e3fe1be46a994272793");
+        assert_eq!(inner["parent_id"], outer["id"]);
+        assert_eq!(outer["name"], "handler");
+        assert_eq!(outer["parent_id"], "53995c3f42cd8ad8");
+        assert_eq!(outer["type"], "subsegment");
+        assert_eq!(outer["annotations"]["account_id"], "fred");
+    }
+
+    #[tokio::test]
+    async fn should_mark_business_error_with_its_code() {
+        let (listener, daemon) = local_daemon();
+
+        let _ = scope(TraceHeader::parse(HEADER), Some(daemon), async {
+            subsegment("adjust_balance", async {
+                Err::<(), AppError>(AppError::business(ErrorCode::InsufficientFunds, "insufficient funds"))
+            })
+            .await
+        })
+        .await;
+
+        let document = receive(&listener);
+        assert_eq!(document["error"], true);
+        assert!(document.get("fault").is_none());
+        assert_eq!(document["annotations"]["error_code"], "INSUFFICIENT_FUNDS");
+    }
+
+    #[tokio::test]
+    async fn should_not_send_unsampled_trace() {
+        let (listener, daemon) = local_daemon();
+        let header = TraceHeader::parse("Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=0");
+
+        scope(header, Some(daemon), subsegment("handler", async { Ok::<(), AppError>(()) }))
+            .await
+            .unwrap();
+
+        let mut buffer = [0u8; 4096];
+        assert!(listener.recv(&mut buffer).is_err());
+    }
+
+    fn local_daemon() -> (UdpSocket, Daemon) {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        listener.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        let daemon = Daemon::new(listener.local_addr().unwrap()).unwrap();
+        (listener, daemon)
+    }
+
+    /// Checks the daemon protocol header, then returns the document after it.
+    fn receive(listener: &UdpSocket) -> Value {
+        let mut buffer = [0u8; 4096];
+        let length = listener.recv(&mut buffer).unwrap();
+        let datagram = std::str::from_utf8(&buffer[..length]).unwrap();
+        let (header, document) = datagram.split_once('\n').unwrap();
+        assert_eq!(header, r#"{"format": "json", "version": 1}"#);
+        serde_json::from_str(document).unwrap()
+    }
+}
@@ -15,7 +15,8 @@ rm -f target/localstack.ready
     echo "Localstack ready!"
 
     echo "Deploying stack onto Localstack ..."
-    samlocal deploy --stack-name rustmonkey-api --resolve-s3 --region "${AWS_REGION}" --no-confirm-changeset || exit 1
+    samlocal deploy --stack-name rustmonkey-api --resolve-s3 --region "${AWS_REGION}" --no-confirm-changeset \
+        --parameter-overrides PageTokenSecret=localstack-only-page-token-secret || exit 1
     echo "Stack deployed! Ready for test."
 )&
 
@@ -0,0 +1,45 @@
+#!/bin/bash
+
+source common.sh-source
+start_test "Balance as of"
+
+curl -s ${RUSTMONKEY_URL}/account \
+    -X POST \
+    -H 'Content-Type: application/json' \
+    --data-binary '{"accountId":"arnold","currency":"GBP","balance":10}' \
+    --output /dev/null \
+    || setup_failed
+
+sleep 1
+AS_OF=$(date -u +%Y-%m-%dT%H:%M:%SZ)
+sleep 1
+
+curl -s ${RUSTMONKEY_URL}/account/arnold/balance \
+    -X POST \
+    -H 'Content-Type: application/json' \
+    --data-binary '{"amount":5,"currency":"GBP"}' \
+    --output /dev/null \
+    || setup_failed
+
+IFS="|" read HTTP_BODY HTTP_CODE <<< $(
+    curl -s "${RUSTMONKEY_URL}/account/arnold/balance?asOf=${AS_OF}" \
+        --write-out '|%{http_code}' )
+
+assert_code 200 $HTTP_CODE
+assert_body "{\"accountId\":\"arnold\",\"currency\":\"GBP\",\"balance\":\"10\",\"asOf\":\"${AS_OF}\"}" $HTTP_BODY
+
+HTTP_CODE=$(
+    curl -s "${RUSTMONKEY_URL}/account/arnold/balance?asOf=yesterday" \
+        --output /dev/null \
+        --write-out '%{http_code}' )
+
+assert_code 400 $HTTP_CODE
+
+HTTP_CODE=$(
+    curl -s "${RUSTMONKEY_URL}/account/arnold/balance?asOf=2000-01-01T00:00:00Z" \
+        --output /dev/null \
+        --write-out '%{http_code}' )
+
+assert_code 400 $HTTP_CODE
+
+end_test
@@ -0,0 +1,50 @@
+#!/bin/bash
+
+source common.sh-source
+start_test "Conditional requests"
+
+curl -s ${RUSTMONKEY_URL}/account \
+    -X POST \
+    -H 'Content-Type: application/json' \
+    --data-binary '{"accountId":"dino","currency":"GBP","balance":10}' \
+    --output /dev/null \
+    || setup_failed
+
+ETAG=$(
+    curl -s ${RUSTMONKEY_URL}/account/dino \
+        --output /dev/null \
+        --write-out '%header{etag}' )
+
+assert_body '"1"' "$ETAG"
+
+HTTP_CODE=$(
+    curl -s ${RUSTMONKEY_URL}/account/dino \
+        -H "If-None-Match: $ETAG" \
+        --output /dev/null \
+        --write-out '%{http_code}' )
+
+assert_code 304 $HTTP_CODE
+
+IFS="|" read HTTP_BODY HTTP_CODE <<< $(
+    curl -s ${RUSTMONKEY_URL}/account/dino/balance \
+        -X POST \
+        -H 'Content-Type: application/json' \
+        -H "If-Match: $ETAG" \
+        --data-binary '{"amount":-1,"currency":"GBP"}' \
+        --write-out '|%{http_code}' )
+
+assert_code 200 $HTTP_CODE
+assert_body '{"balance":"9"}' $HTTP_BODY
+
+HTTP_CODE=$(
+    curl -s ${RUSTMONKEY_URL}/account/dino/balance \
+        -X POST \
+        -H 'Content-Type: application/json' \
+        -H "If-Match: $ETAG" \
+        --data-binary '{"amount":-1,"currency":"GBP"}' \
+        --output /dev/null \
+        --write-out '%{http_code}' )
+
+assert_code 412 $HTTP_CODE
+
+end_test
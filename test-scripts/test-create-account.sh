@@ -7,9 +7,36 @@ HTTP_CODE=$(
     curl -s ${RUSTMONKEY_URL}/account \
         -X POST \
         -H 'Content-Type: application/json' \
-        --data-binary '{"accountId":"sid","balance":0}' \
+        --data-binary '{"accountId":"sid","currency":"GBP","balance":0}' \
+        --output /dev/null \
 	    --write-out '%{http_code}' )
 
 assert_code 201 $HTTP_CODE
 
+LOCATION=$(
+    curl -s ${RUSTMONKEY_URL}/account \
+        -X POST \
+        -H 'Content-Type: application/json' \
+        --data-binary '{"currency":"GBP","balance":5}' \
+        --output /dev/null \
+        --dump-header - \
+        | tr -d '\r' | grep -i '^location:' | cut -d' ' -f2 )
+
+[[ "$LOCATION" == /account/acc_* ]] || err "Expected a generated account id in '$LOCATION'"
+
+HTTP_BODY=$(curl -s ${RUSTMONKEY_URL}${LOCATION})
+
+[[ "$HTTP_BODY" == *'"balance":"5"'* ]] || err "Expected the generated account at '$LOCATION' but was '$HTTP_BODY'"
+
+IFS="|" read HTTP_BODY HTTP_CODE <<< $(
+    curl -s ${RUSTMONKEY_URL}/account \
+        -X POST \
+        -H 'Content-Type: application/json' \
+        --data-binary '{"accountId":"sid vicious","currency":"GBP","balance":-1}' \
+        --write-out '|%{http_code}' )
+
+assert_code 400 $HTTP_CODE
+[[ "$HTTP_BODY" == *'"pointer":"/accountId"'*'"pointer":"/balance"'* ]] \
+    || err "Expected both invalid values in '$HTTP_BODY'"
+
 end_test
@@ -6,14 +6,15 @@ start_test "Credit account"
 curl -s ${RUSTMONKEY_URL}/account \
     -X POST \
     -H 'Content-Type: application/json' \
-    --data-binary '{"accountId":"bert","balance":10}' \
+    --data-binary '{"accountId":"bert","currency":"GBP","balance":10}' \
+    --output /dev/null \
     || setup_failed
 
 IFS="|" read HTTP_BODY HTTP_CODE <<< $(
     curl -s ${RUSTMONKEY_URL}/account/bert/balance \
         -X POST \
         -H 'Content-Type: application/json' \
-        --data-binary '{"amount":0.10}' \
+        --data-binary '{"amount":0.10,"currency":"GBP"}' \
 	    --write-out '|%{http_code}' )
 
 assert_code 200 $HTTP_CODE
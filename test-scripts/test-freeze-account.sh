@@ -0,0 +1,54 @@
+#!/bin/bash
+
+source common.sh-source
+start_test "Freeze account"
+
+curl -s ${RUSTMONKEY_URL}/account \
+    -X POST \
+    -H 'Content-Type: application/json' \
+    --data-binary '{"accountId":"bamm-bamm","currency":"GBP","balance":10}' \
+    --output /dev/null \
+    || setup_failed
+
+HTTP_CODE=$(
+    curl -s ${RUSTMONKEY_URL}/account/bamm-bamm/freeze \
+        -X POST \
+        --write-out '%{http_code}' )
+
+assert_code 204 $HTTP_CODE
+
+HTTP_CODE=$(
+    curl -s ${RUSTMONKEY_URL}/account/bamm-bamm/balance \
+        -X POST \
+        -H 'Content-Type: application/json' \
+        --data-binary '{"amount":-1,"currency":"GBP"}' \
+        --output /dev/null \
+        --write-out '%{http_code}' )
+
+assert_code 423 $HTTP_CODE
+
+HTTP_CODE=$(
+    curl -s ${RUSTMONKEY_URL}/account/bamm-bamm/unfreeze \
+        -X POST \
+        --write-out '%{http_code}' )
+
+assert_code 204 $HTTP_CODE
+
+IFS="|" read HTTP_BODY HTTP_CODE <<< $(
+    curl -s ${RUSTMONKEY_URL}/account/bamm-bamm/balance \
+        -X POST \
+        -H 'Content-Type: application/json' \
+        --data-binary '{"amount":-10,"currency":"GBP"}' \
+        --write-out '|%{http_code}' )
+
+assert_code 200 $HTTP_CODE
+assert_body '{"balance":"0"}' $HTTP_BODY
+
+HTTP_CODE=$(
+    curl -s ${RUSTMONKEY_URL}/account/bamm-bamm/close \
+        -X POST \
+        --write-out '%{http_code}' )
+
+assert_code 204 $HTTP_CODE
+
+end_test
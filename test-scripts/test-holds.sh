@@ -0,0 +1,55 @@
+#!/bin/bash
+
+source common.sh-source
+start_test "Holds"
+
+curl -s ${RUSTMONKEY_URL}/account \
+    -X POST \
+    -H 'Content-Type: application/json' \
+    --data-binary '{"accountId":"gazoo","currency":"GBP","balance":100}' \
+    --output /dev/null \
+    || setup_failed
+
+IFS="|" read HTTP_BODY HTTP_CODE <<< $(
+    curl -s ${RUSTMONKEY_URL}/account/gazoo/holds \
+        -X POST \
+        -H 'Content-Type: application/json' \
+        --data-binary '{"amount":30,"currency":"GBP"}' \
+        --write-out '|%{http_code}' )
+
+assert_code 201 $HTTP_CODE
+HOLD_ID=$(echo $HTTP_BODY | sed -e 's/.*"holdId":"\([^"]*\)".*/\1/')
+
+IFS="|" read HTTP_BODY HTTP_CODE <<< $(
+    curl -s ${RUSTMONKEY_URL}/account/gazoo/holds \
+        -X POST \
+        -H 'Content-Type: application/json' \
+        --data-binary '{"amount":80,"currency":"GBP"}' \
+        --write-out '|%{http_code}' )
+
+assert_code 422 $HTTP_CODE
+
+IFS="|" read HTTP_BODY HTTP_CODE <<< $(
+    curl -s ${RUSTMONKEY_URL}/account/gazoo \
+        --write-out '|%{http_code}' )
+
+assert_code 200 $HTTP_CODE
+assert_body '{"accountId":"gazoo","currency":"GBP","balance":"100","overdraftLimit":"0","availableFunds":"70","status":"ACTIVE","heldAmount":"30"}' $HTTP_BODY
+
+IFS="|" read HTTP_BODY HTTP_CODE <<< $(
+    curl -s ${RUSTMONKEY_URL}/account/gazoo/holds/${HOLD_ID}/capture \
+        -X POST \
+        --write-out '|%{http_code}' )
+
+assert_code 200 $HTTP_CODE
+assert_body '{"balance":"70"}' $HTTP_BODY
+
+HTTP_CODE=$(
+    curl -s ${RUSTMONKEY_URL}/account/gazoo/holds/${HOLD_ID}/release \
+        -X POST \
+        --output /dev/null \
+        --write-out '%{http_code}' )
+
+assert_code 404 $HTTP_CODE
+
+end_test
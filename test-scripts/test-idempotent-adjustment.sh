@@ -0,0 +1,38 @@
+#!/bin/bash
+
+source common.sh-source
+start_test "Idempotent adjust"
+
+curl -s ${RUSTMONKEY_URL}/account \
+    -X POST \
+    -H 'Content-Type: application/json' \
+    --data-binary '{"accountId":"betty","currency":"GBP","balance":10}' \
+    --output /dev/null \
+    || setup_failed
+
+for attempt in 1 2
+do
+    IFS="|" read HTTP_BODY HTTP_CODE <<< $(
+        curl -s ${RUSTMONKEY_URL}/account/betty/balance \
+            -X POST \
+            -H 'Content-Type: application/json' \
+            -H 'Idempotency-Key: betty-debit-1' \
+            --data-binary '{"amount":-1,"currency":"GBP"}' \
+            --write-out '|%{http_code}' )
+
+    assert_code 200 $HTTP_CODE
+    assert_body '{"balance":"9"}' $HTTP_BODY
+done
+
+HTTP_CODE=$(
+    curl -s ${RUSTMONKEY_URL}/account/betty/balance \
+        -X POST \
+        -H 'Content-Type: application/json' \
+        -H 'Idempotency-Key: betty-debit-1' \
+        --data-binary '{"amount":-2,"currency":"GBP"}' \
+        --output /dev/null \
+        --write-out '%{http_code}' )
+
+assert_code 422 $HTTP_CODE
+
+end_test
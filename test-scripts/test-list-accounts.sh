@@ -0,0 +1,38 @@
+#!/bin/bash
+
+source common.sh-source
+start_test "List accounts"
+
+for account in dino hoppy
+do
+    curl -s ${RUSTMONKEY_URL}/account \
+        -X POST \
+        -H 'Content-Type: application/json' \
+        --data-binary "{\"accountId\":\"$account\",\"currency\":\"GBP\",\"balance\":1}" \
+        --output /dev/null \
+        || setup_failed
+done
+
+IFS="|" read HTTP_BODY HTTP_CODE <<< $(
+    curl -s "${RUSTMONKEY_URL}/account?limit=1" \
+        --write-out '|%{http_code}' )
+
+assert_code 200 $HTTP_CODE
+[[ "$HTTP_BODY" == *'"nextToken":'* ]] \
+    || err "Expected a next token in '$HTTP_BODY'"
+
+HTTP_CODE=$(
+    curl -s "${RUSTMONKEY_URL}/account?limit=1&nextToken=tampered" \
+        --output /dev/null \
+        --write-out '%{http_code}' )
+
+assert_code 400 $HTTP_CODE
+
+HTTP_CODE=$(
+    curl -s "${RUSTMONKEY_URL}/account?limit=1000" \
+        --output /dev/null \
+        --write-out '%{http_code}' )
+
+assert_code 400 $HTTP_CODE
+
+end_test
@@ -0,0 +1,28 @@
+#!/bin/bash
+
+source common.sh-source
+start_test "List transactions"
+
+curl -s ${RUSTMONKEY_URL}/account \
+    -X POST \
+    -H 'Content-Type: application/json' \
+    --data-binary '{"accountId":"barney","currency":"GBP","balance":10}' \
+    --output /dev/null \
+    || setup_failed
+
+curl -s ${RUSTMONKEY_URL}/account/barney/balance \
+    -X POST \
+    -H 'Content-Type: application/json' \
+    --data-binary '{"amount":-2.5,"currency":"GBP","description":"bowling"}' \
+    || setup_failed
+
+IFS="|" read HTTP_BODY HTTP_CODE <<< $(
+    curl -s ${RUSTMONKEY_URL}/account/barney/transactions \
+        --write-out '|%{http_code}' )
+
+assert_code 200 $HTTP_CODE
+# Transaction id and timestamp vary, so only check the parts that do not.
+[[ "$HTTP_BODY" == *'"amount":"-2.5","balance":"7.5"'*'"description":"bowling"'* ]] \
+    || err "Unexpected body '$HTTP_BODY'"
+
+end_test
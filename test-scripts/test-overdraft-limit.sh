@@ -0,0 +1,39 @@
+#!/bin/bash
+
+source common.sh-source
+start_test "Overdraft limit"
+
+curl -s ${RUSTMONKEY_URL}/account \
+    -X POST \
+    -H 'Content-Type: application/json' \
+    --data-binary '{"accountId":"pebbles","currency":"GBP","balance":10}' \
+    --output /dev/null \
+    || setup_failed
+
+HTTP_CODE=$(
+    curl -s ${RUSTMONKEY_URL}/account/pebbles/overdraft-limit \
+        -X PUT \
+        -H 'Content-Type: application/json' \
+        --data-binary '{"overdraftLimit":25}' \
+        --write-out '%{http_code}' )
+
+assert_code 204 $HTTP_CODE
+
+IFS="|" read HTTP_BODY HTTP_CODE <<< $(
+    curl -s ${RUSTMONKEY_URL}/account/pebbles/balance \
+        -X POST \
+        -H 'Content-Type: application/json' \
+        --data-binary '{"amount":-30,"currency":"GBP"}' \
+        --write-out '|%{http_code}' )
+
+assert_code 200 $HTTP_CODE
+assert_body '{"balance":"-20"}' $HTTP_BODY
+
+IFS="|" read HTTP_BODY HTTP_CODE <<< $(
+    curl -s ${RUSTMONKEY_URL}/account/pebbles \
+        --write-out '|%{http_code}' )
+
+assert_code 200 $HTTP_CODE
+assert_body '{"accountId":"pebbles","currency":"GBP","balance":"-20","overdraftLimit":"25","availableFunds":"5","status":"ACTIVE","heldAmount":"0"}' $HTTP_BODY
+
+end_test
@@ -6,7 +6,8 @@ start_test "Query account"
 curl -s ${RUSTMONKEY_URL}/account \
     -X POST \
     -H 'Content-Type: application/json' \
-    --data-binary '{"accountId":"john","balance":50.22}' \
+    --data-binary '{"accountId":"john","currency":"GBP","balance":50.22}' \
+    --output /dev/null \
     || setup_failed
 
 IFS="|" read HTTP_BODY HTTP_CODE <<< $(
@@ -14,6 +15,6 @@ IFS="|" read HTTP_BODY HTTP_CODE <<< $(
         --write-out '|%{http_code}' )
 
 assert_code 200 $HTTP_CODE
-assert_body '{"accountId":"john","balance":"50.22"}' $HTTP_BODY
+assert_body '{"accountId":"john","currency":"GBP","balance":"50.22","overdraftLimit":"0","availableFunds":"50.22","status":"ACTIVE","heldAmount":"0"}' $HTTP_BODY
 
 end_test
@@ -0,0 +1,31 @@
+#!/bin/bash
+
+source common.sh-source
+start_test "Routes"
+
+HTTP_CODE=$(
+    curl -s ${RUSTMONKEY_URL}/account/fred/unknown \
+        --output /dev/null \
+        --write-out '%{http_code}' )
+
+assert_code 404 $HTTP_CODE
+
+IFS="|" read HTTP_BODY HTTP_CODE <<< $(
+    curl -s ${RUSTMONKEY_URL}/account/fred \
+        -X DELETE \
+        --write-out '|%{http_code}' )
+
+assert_code 405 $HTTP_CODE
+[[ "$HTTP_BODY" == *'"code":"METHOD_NOT_ALLOWED"'* ]] \
+    || err "Expected problem details in '$HTTP_BODY'"
+
+ALLOW=$(
+    curl -s ${RUSTMONKEY_URL}/account \
+        -X OPTIONS \
+        --output /dev/null \
+        --dump-header - \
+        | tr -d '\r' | grep -i '^allow:' )
+
+assert_body 'POST, GET, HEAD, OPTIONS' "${ALLOW#*: }"
+
+end_test
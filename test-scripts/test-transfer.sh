@@ -0,0 +1,36 @@
+#!/bin/bash
+
+source common.sh-source
+start_test "Transfer"
+
+curl -s ${RUSTMONKEY_URL}/account \
+    -X POST \
+    -H 'Content-Type: application/json' \
+    --data-binary '{"accountId":"fred","currency":"GBP","balance":20}' \
+    --output /dev/null \
+    || setup_failed
+
+curl -s ${RUSTMONKEY_URL}/account \
+    -X POST \
+    -H 'Content-Type: application/json' \
+    --data-binary '{"accountId":"wilma","currency":"GBP","balance":5}' \
+    --output /dev/null \
+    || setup_failed
+
+HTTP_CODE=$(
+    curl -s ${RUSTMONKEY_URL}/transfer \
+        -X POST \
+        -H 'Content-Type: application/json' \
+        --data-binary '{"fromAccountId":"fred","toAccountId":"wilma","amount":7.5,"currency":"GBP"}' \
+	    --write-out '%{http_code}' )
+
+assert_code 204 $HTTP_CODE
+
+IFS="|" read HTTP_BODY HTTP_CODE <<< $(
+    curl -s ${RUSTMONKEY_URL}/account/wilma \
+        --write-out '|%{http_code}' )
+
+assert_code 200 $HTTP_CODE
+assert_body '{"accountId":"wilma","currency":"GBP","balance":"12.5","overdraftLimit":"0","availableFunds":"12.5","status":"ACTIVE","heldAmount":"0"}' $HTTP_BODY
+
+end_test
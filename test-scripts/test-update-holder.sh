@@ -0,0 +1,33 @@
+#!/bin/bash
+
+source common.sh-source
+start_test "Update holder"
+
+curl -s ${RUSTMONKEY_URL}/account \
+    -X POST \
+    -H 'Content-Type: application/json' \
+    --data-binary '{"accountId":"slate","currency":"GBP","balance":10,"ownerName":"Mr Slate","externalReference":"Q-1"}' \
+    --output /dev/null \
+    || setup_failed
+
+IFS="|" read HTTP_BODY HTTP_CODE <<< $(
+    curl -s ${RUSTMONKEY_URL}/account/slate \
+        -X PATCH \
+        -H 'Content-Type: application/merge-patch+json' \
+        --data-binary '{"contactEmail":"slate@bedrock.example","externalReference":null,"tags":["quarry"]}' \
+        --write-out '|%{http_code}' )
+
+assert_code 200 $HTTP_CODE
+assert_body '{"accountId":"slate","currency":"GBP","balance":"10","overdraftLimit":"0","availableFunds":"10","status":"ACTIVE","heldAmount":"0","ownerName":"Mr Slate","contactEmail":"slate@bedrock.example","tags":["quarry"]}' "$HTTP_BODY"
+
+HTTP_CODE=$(
+    curl -s ${RUSTMONKEY_URL}/account/slate \
+        -X PATCH \
+        -H 'Content-Type: application/merge-patch+json' \
+        --data-binary '{"balance":1000}' \
+        --output /dev/null \
+        --write-out '%{http_code}' )
+
+assert_code 400 $HTTP_CODE
+
+end_test